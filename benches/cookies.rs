@@ -0,0 +1,53 @@
+use conduit::{header, Body, Handler, HttpResult, Method, RequestExt, Response};
+use conduit_cookie::{parse_cookie_header, Middleware, RequestCookies};
+use conduit_middleware::MiddlewareBuilder;
+use conduit_test::MockRequest;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+fn cookie_header() -> String {
+    (0..20)
+        .map(|i| format!("cookie{i}=value{i}"))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+fn request() -> MockRequest {
+    let mut req = MockRequest::new(Method::GET, "/");
+    req.header(header::COOKIE, &cookie_header());
+    req
+}
+
+fn app(handler: fn(&mut dyn RequestExt) -> HttpResult) -> MiddlewareBuilder {
+    let mut app = MiddlewareBuilder::new(handler);
+    app.add(Middleware::new());
+    app
+}
+
+fn ignore(_: &mut dyn RequestExt) -> HttpResult {
+    Response::builder().body(Body::empty())
+}
+
+fn read_one(req: &mut dyn RequestExt) -> HttpResult {
+    black_box(req.cookies().get("cookie10"));
+    Response::builder().body(Body::empty())
+}
+
+fn parsing(c: &mut Criterion) {
+    let req = request();
+    c.bench_function("parse 20 cookies", |b| {
+        b.iter(|| parse_cookie_header(black_box(req.headers())))
+    });
+
+    // Requests that don't read their cookies skip parsing them
+    let ignoring = app(ignore);
+    c.bench_function("request ignoring 20 cookies", |b| {
+        b.iter_batched_ref(request, |req| ignoring.call(req), BatchSize::SmallInput)
+    });
+    let reading = app(read_one);
+    c.bench_function("request reading 1 of 20 cookies", |b| {
+        b.iter_batched_ref(request, |req| reading.call(req), BatchSize::SmallInput)
+    });
+}
+
+criterion_group!(benches, parsing);
+criterion_main!(benches);
@@ -0,0 +1,17 @@
+#![no_main]
+
+use conduit_cookie::cookie::{Cookie, CookieJar, Key};
+use conduit_cookie::{decode_session, SessionMiddleware};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|value: &str| {
+    let _ = SessionMiddleware::decode(Cookie::new("session", value));
+
+    // Sign the input so that it reaches the decoders of all formats
+    let key = Key::from(&[7; 64]);
+    let mut jar = CookieJar::new();
+    jar.signed_mut(&key)
+        .add(Cookie::new("session", value.to_string()));
+    let signed = jar.get("session").unwrap().value().to_string();
+    let _ = decode_session(&signed, &key, "session");
+});
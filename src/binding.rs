@@ -0,0 +1,33 @@
+use std::net::IpAddr;
+
+use base64::{encode_config, URL_SAFE_NO_PAD};
+use conduit::{header, RequestExt};
+use sha2::{Digest, Sha256};
+
+/// The client IP address truncated to its /24 (IPv4) or /48 (IPv6) network,
+/// so that sessions survive address changes within the same network.
+pub fn client_ip_prefix(req: &dyn RequestExt) -> String {
+    match req.remote_addr().ip() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{a}.{b}.{c}.0/24")
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            format!("{a:x}:{b:x}:{c:x}::/48")
+        }
+    }
+}
+
+/// The `User-Agent` header of the request, or an empty string.
+pub fn user_agent(req: &dyn RequestExt) -> String {
+    let user_agent = req.headers().get(header::USER_AGENT);
+    let user_agent = user_agent.and_then(|value| value.to_str().ok());
+    user_agent.unwrap_or_default().to_string()
+}
+
+/// Hashes the bound attributes, so that they aren't readable from the
+/// (only signed) session cookie.
+pub(crate) fn hash(attributes: &str) -> String {
+    encode_config(Sha256::digest(attributes.as_bytes()), URL_SAFE_NO_PAD)
+}
@@ -0,0 +1,25 @@
+use std::sync::Arc;
+
+use cookie::time::OffsetDateTime;
+
+/// The source of the current time for session expiry, idle timeouts and
+/// store TTLs, so that tests can move time forward without sleeping.
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> OffsetDateTime;
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> OffsetDateTime {
+        (**self).now()
+    }
+}
+
+/// The system clock, used unless another `Clock` is configured.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+}
@@ -0,0 +1,35 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{Error, SessionFormat};
+
+/// Serializes the session map into a cookie value and back, for formats
+/// not covered by `SessionFormat`.
+///
+/// The value is signed or encrypted afterwards, so it only needs to be a
+/// valid cookie value.
+pub trait SessionCodec: Send + Sync + 'static {
+    fn encode(&self, data: &HashMap<String, String>) -> String;
+    fn decode(&self, value: &str) -> Result<HashMap<String, String>, Error>;
+}
+
+impl<C: SessionCodec + ?Sized> SessionCodec for Arc<C> {
+    fn encode(&self, data: &HashMap<String, String>) -> String {
+        (**self).encode(data)
+    }
+
+    fn decode(&self, value: &str) -> Result<HashMap<String, String>, Error> {
+        (**self).decode(value)
+    }
+}
+
+/// Encodes in the given format, and decodes any format.
+impl SessionCodec for SessionFormat {
+    fn encode(&self, data: &HashMap<String, String>) -> String {
+        SessionFormat::encode(*self, data, Default::default())
+    }
+
+    fn decode(&self, value: &str) -> Result<HashMap<String, String>, Error> {
+        SessionFormat::decode(value).map(|(_, data)| data)
+    }
+}
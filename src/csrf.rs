@@ -0,0 +1,195 @@
+use base64::{encode_config, URL_SAFE_NO_PAD};
+use conduit::header::HeaderName;
+use conduit::{Body, Handler, HandlerResult, Method, RequestExt, Response, StatusCode};
+use conduit_middleware::AroundMiddleware;
+use cookie::{Cookie, Key, SameSite};
+
+use crate::{constant_time_eq, RequestCookies};
+
+/// Double-submit CSRF protection.
+///
+/// A random token is kept in a signed cookie and exposed to handlers via
+/// `req.csrf_token()`. Requests with methods other than GET, HEAD, OPTIONS
+/// and TRACE must echo the token in the `X-CSRF-Token` header, or they are
+/// rejected with 403 Forbidden.
+///
+/// This is an around middleware, so that it runs after the cookie
+/// middleware has parsed the request cookies:
+///
+/// ```ignore
+/// app.add(conduit_cookie::Middleware::new());
+/// app.around(CsrfMiddleware::new(key));
+/// ```
+pub struct CsrfMiddleware {
+    key: Key,
+    cookie_name: String,
+    header_name: HeaderName,
+    secure: bool,
+    handler: Option<Box<dyn Handler>>,
+}
+
+struct CsrfToken(String);
+
+impl CsrfMiddleware {
+    pub fn new(key: Key) -> CsrfMiddleware {
+        CsrfMiddleware {
+            key,
+            cookie_name: "csrf_token".to_string(),
+            header_name: HeaderName::from_static("x-csrf-token"),
+            secure: false,
+            handler: None,
+        }
+    }
+
+    pub fn cookie_name(mut self, cookie_name: &str) -> Self {
+        self.cookie_name = cookie_name.to_string();
+        self
+    }
+
+    pub fn header_name(mut self, header_name: HeaderName) -> Self {
+        self.header_name = header_name;
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    fn verify(&self, req: &dyn RequestExt, token: Option<&str>) -> bool {
+        let safe = matches!(
+            *req.method(),
+            Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+        );
+        if safe {
+            return true;
+        }
+        let submitted = req
+            .headers()
+            .get(&self.header_name)
+            .map(|value| value.as_bytes());
+        match (token, submitted) {
+            (Some(token), Some(submitted)) => constant_time_eq(token, submitted),
+            _ => false,
+        }
+    }
+}
+
+impl AroundMiddleware for CsrfMiddleware {
+    fn with_handler(&mut self, handler: Box<dyn Handler>) {
+        self.handler = Some(handler);
+    }
+}
+
+impl Handler for CsrfMiddleware {
+    fn call(&self, req: &mut dyn RequestExt) -> HandlerResult {
+        let existing = req
+            .cookies()
+            .signed(&self.key)
+            .get(&self.cookie_name)
+            .map(|cookie| cookie.value().to_string());
+        let verified = self.verify(req, existing.as_deref());
+
+        let token = match existing {
+            Some(token) => token,
+            None => {
+                let token = encode_config(rand::random::<[u8; 32]>(), URL_SAFE_NO_PAD);
+                let cookie = Cookie::build(self.cookie_name.clone(), token.clone())
+                    .http_only(true)
+                    .secure(self.secure)
+                    .same_site(SameSite::Strict)
+                    .path("/")
+                    .finish();
+                req.cookies_mut().signed_mut(&self.key).add(cookie);
+                token
+            }
+        };
+        req.mut_extensions().insert(CsrfToken(token));
+
+        if !verified {
+            return Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::empty())
+                .map_err(conduit::box_error);
+        }
+        self.handler.as_ref().unwrap().call(req)
+    }
+}
+
+pub trait RequestCsrf {
+    /// The token that must be submitted with unsafe requests.
+    fn csrf_token(&self) -> &str;
+}
+
+impl<T: RequestExt + ?Sized> RequestCsrf for T {
+    fn csrf_token(&self) -> &str {
+        &self
+            .extensions()
+            .get::<CsrfToken>()
+            .expect("missing csrf token")
+            .0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use conduit::{header, Body, Handler, HttpResult, Method, RequestExt, Response, StatusCode};
+    use conduit_middleware::MiddlewareBuilder;
+    use conduit_test::MockRequest;
+    use cookie::Key;
+
+    use crate::{CsrfMiddleware, Middleware, RequestCsrf};
+
+    fn handler(req: &mut dyn RequestExt) -> HttpResult {
+        let token = req.csrf_token().to_string();
+        Response::builder().body(Body::from_vec(token.into_bytes()))
+    }
+
+    fn app() -> MiddlewareBuilder {
+        let master_key: Vec<u8> = (0..32).collect();
+        let mut app = MiddlewareBuilder::new(handler);
+        app.add(Middleware::new());
+        app.around(CsrfMiddleware::new(Key::derive_from(&master_key)));
+        app
+    }
+
+    fn body(response: Response<Body>) -> String {
+        match response.into_body() {
+            Body::Owned(bytes) => String::from_utf8(bytes).unwrap(),
+            _ => panic!("unexpected body"),
+        }
+    }
+
+    #[test]
+    fn double_submit() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        let response = app().call(&mut req).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookie = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, cookie.to_str().unwrap());
+        let token = body(response);
+
+        req.with_method(Method::POST);
+        let response = app().call(&mut req).unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        req.header("x-csrf-token", "wrong");
+        let response = app().call(&mut req).unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        req.header("x-csrf-token", &token);
+        let response = app().call(&mut req).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+        assert_eq!(body(response), token);
+    }
+
+    #[test]
+    fn unsafe_without_cookie() {
+        let mut req = MockRequest::new(Method::POST, "/");
+        req.header("x-csrf-token", "anything");
+        let response = app().call(&mut req).unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(response.headers().get(header::SET_COOKIE).is_some());
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use conduit::RequestExt;
+use conduit_middleware::{AfterResult, BeforeResult};
+
+use crate::RequestCookies;
+
+type LogFn = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Logs the cookie jar and session data before and after the rest of the
+/// middleware chain and the handler run, e.g. to find out which middleware
+/// dropped a cookie.
+///
+/// The output includes cookie values and session data, so this is only
+/// meant for development. Add it after `Middleware` and `SessionMiddleware`
+/// to see what the handler sees, or before them to check their ordering.
+pub struct DebugMiddleware {
+    log: LogFn,
+}
+
+impl Default for DebugMiddleware {
+    fn default() -> Self {
+        DebugMiddleware {
+            log: Arc::new(|line| eprintln!("{line}")),
+        }
+    }
+}
+
+impl DebugMiddleware {
+    /// Logs to stderr.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Passes each log line to `log` instead of printing it.
+    pub fn log<F: Fn(&str) + Send + Sync + 'static>(mut self, log: F) -> Self {
+        self.log = Arc::new(log);
+        self
+    }
+
+    fn dump(&self, stage: &str, req: &dyn RequestExt) {
+        let mut line = format!("{} {} {}: ", stage, req.method(), req.path());
+        match req.try_cookies() {
+            Some(jar) => {
+                let cookies = jar.iter().map(|c| (c.name(), c.value()));
+                let _ = write!(line, "cookies {:?}", cookies.collect::<BTreeMap<_, _>>());
+                let delta = jar.delta().map(|c| c.to_string()).collect::<Vec<_>>();
+                if !delta.is_empty() {
+                    let _ = write!(line, ", delta {delta:?}");
+                }
+            }
+            None => line.push_str("no cookie jar, is `Middleware` added before this?"),
+        }
+        #[cfg(feature = "session")]
+        for (name, data) in crate::session::sessions(req) {
+            let data = data.iter().collect::<BTreeMap<_, _>>();
+            let _ = write!(line, ", session `{name}` {data:?}");
+        }
+        (self.log)(&line);
+    }
+}
+
+impl conduit_middleware::Middleware for DebugMiddleware {
+    fn before(&self, req: &mut dyn RequestExt) -> BeforeResult {
+        self.dump("before", req);
+        Ok(())
+    }
+
+    fn after(&self, req: &mut dyn RequestExt, res: AfterResult) -> AfterResult {
+        self.dump("after", req);
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use conduit::{header, Body, Handler, HttpResult, Method, RequestExt, Response};
+    use conduit_middleware::MiddlewareBuilder;
+    use conduit_test::MockRequest;
+    use cookie::Cookie;
+
+    use super::DebugMiddleware;
+    use crate::{Middleware, RequestCookies};
+
+    #[test]
+    fn logs_jar() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let log = {
+            let lines = lines.clone();
+            move |line: &str| lines.lock().unwrap().push(line.to_string())
+        };
+
+        let mut req = MockRequest::new(Method::GET, "/articles");
+        req.header(header::COOKIE, "b=2; a=1");
+        let mut app = MiddlewareBuilder::new(handler);
+        app.add(DebugMiddleware::new().log(log.clone()));
+        app.add(Middleware::new());
+        app.add(DebugMiddleware::new().log(log));
+        app.call(&mut req).unwrap();
+
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].ends_with("no cookie jar, is `Middleware` added before this?"));
+        assert_eq!(
+            lines[1],
+            r#"before GET /articles: cookies {"a": "1", "b": "2"}"#
+        );
+        assert_eq!(
+            lines[2],
+            r#"after GET /articles: cookies {"a": "3", "b": "2"}, delta ["a=3"]"#
+        );
+
+        fn handler(req: &mut dyn RequestExt) -> HttpResult {
+            req.cookies_mut().add(Cookie::new("a", "3"));
+            Response::builder().body(Body::empty())
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use cookie::{Cookie, SameSite};
+
+/// Attributes applied to every response cookie that doesn't set them itself.
+#[derive(Clone, Default)]
+pub struct CookieDefaults {
+    secure: Option<bool>,
+    http_only: Option<bool>,
+    same_site: Option<SameSite>,
+    path: Option<String>,
+    domain: Option<String>,
+    pub(crate) partitioned: bool,
+}
+
+impl CookieDefaults {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = Some(secure);
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = Some(http_only);
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    /// Sets the `Partitioned` attribute on all response cookies.
+    pub fn partitioned(mut self, partitioned: bool) -> Self {
+        self.partitioned = partitioned;
+        self
+    }
+
+    pub(crate) fn apply(&self, cookie: &mut Cookie<'_>) {
+        if let (None, Some(secure)) = (cookie.secure(), self.secure) {
+            cookie.set_secure(secure);
+        }
+        if let (None, Some(http_only)) = (cookie.http_only(), self.http_only) {
+            cookie.set_http_only(http_only);
+        }
+        if let (None, Some(same_site)) = (cookie.same_site(), self.same_site) {
+            cookie.set_same_site(same_site);
+        }
+        if let (None, Some(path)) = (cookie.path(), &self.path) {
+            cookie.set_path(path.to_string());
+        }
+        if let (None, Some(domain)) = (cookie.domain(), &self.domain) {
+            cookie.set_domain(domain.to_string());
+        }
+    }
+}
+
+/// Exceptions to the `CookieDefaults` for a single response cookie, e.g. a
+/// locale cookie that must be readable from JavaScript. See
+/// `RequestCookies::override_cookie`.
+#[derive(Clone, Default)]
+pub struct CookieOverride {
+    skip_defaults: bool,
+    secure: Option<bool>,
+    http_only: Option<bool>,
+    same_site: Option<SameSite>,
+    host_only: bool,
+}
+
+impl CookieOverride {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Applies none of the defaults to the cookie.
+    pub fn skip_defaults(mut self) -> Self {
+        self.skip_defaults = true;
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = Some(secure);
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = Some(http_only);
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// Leaves out the Domain attribute, even if the cookie or the defaults
+    /// set one.
+    pub fn host_only(mut self) -> Self {
+        self.host_only = true;
+        self
+    }
+
+    pub(crate) fn apply(&self, defaults: &CookieDefaults, cookie: &mut Cookie<'_>) {
+        if !self.skip_defaults {
+            defaults.apply(cookie);
+        }
+        if let Some(secure) = self.secure {
+            cookie.set_secure(secure);
+        }
+        if let Some(http_only) = self.http_only {
+            cookie.set_http_only(http_only);
+        }
+        if let Some(same_site) = self.same_site {
+            cookie.set_same_site(same_site);
+        }
+        if self.host_only {
+            cookie.unset_domain();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cookie::{Cookie, SameSite};
+
+    use super::{CookieDefaults, CookieOverride};
+
+    #[test]
+    fn fills_missing_attributes() {
+        let defaults = CookieDefaults::new()
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .path("/")
+            .domain("example.com");
+
+        let mut cookie = Cookie::new("foo", "bar");
+        defaults.apply(&mut cookie);
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        assert_eq!(cookie.path(), Some("/"));
+        assert_eq!(cookie.domain(), Some("example.com"));
+    }
+
+    #[test]
+    fn keeps_explicit_attributes() {
+        let defaults = CookieDefaults::new()
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .path("/");
+
+        let mut cookie = Cookie::build("foo", "bar")
+            .secure(false)
+            .http_only(false)
+            .same_site(SameSite::None)
+            .path("/admin")
+            .finish();
+        defaults.apply(&mut cookie);
+        assert_eq!(cookie.secure(), Some(false));
+        assert_eq!(cookie.http_only(), Some(false));
+        assert_eq!(cookie.same_site(), Some(SameSite::None));
+        assert_eq!(cookie.path(), Some("/admin"));
+        assert_eq!(cookie.domain(), None);
+    }
+
+    #[test]
+    fn overrides() {
+        let defaults = CookieDefaults::new()
+            .secure(true)
+            .http_only(true)
+            .domain("example.com");
+
+        let mut cookie = Cookie::new("locale", "en");
+        let cookie_override = CookieOverride::new().http_only(false).host_only();
+        cookie_override.apply(&defaults, &mut cookie);
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.http_only(), Some(false));
+        assert_eq!(cookie.domain(), None);
+
+        let mut cookie = Cookie::new("locale", "en");
+        CookieOverride::new()
+            .skip_defaults()
+            .apply(&defaults, &mut cookie);
+        assert_eq!(cookie.secure(), None);
+        assert_eq!(cookie.http_only(), None);
+    }
+}
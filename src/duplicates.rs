@@ -0,0 +1,22 @@
+use std::collections::HashMap;
+
+use cookie::Cookie;
+
+/// Which cookie wins when a request carries the same name more than once,
+/// e.g. because cookies were set with different Domain or Path attributes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DuplicateCookies {
+    /// Keep the first cookie in the jar. Browsers send cookies with more
+    /// specific paths first.
+    FirstWins,
+    /// Keep the last cookie in the jar.
+    #[default]
+    LastWins,
+    /// Keep the last cookie in the jar, and make all of them available via
+    /// `req.all_cookies(name)`.
+    CollectAll,
+}
+
+/// All request cookies by name, in the order they were sent.
+#[derive(Default)]
+pub(crate) struct AllCookies(pub(crate) HashMap<String, Vec<Cookie<'static>>>);
@@ -0,0 +1,108 @@
+use std::fmt;
+use std::sync::Arc;
+
+use conduit::BoxError;
+
+/// Failures while reading or writing cookies.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A verified session cookie couldn't be decoded.
+    InvalidSession(String),
+    /// A response cookie name isn't a valid RFC 6265 token.
+    InvalidName { name: String },
+    /// A response cookie couldn't be converted into a header value.
+    InvalidHeader { name: String },
+    /// A response cookie has `SameSite=None` without `Secure`, which browsers
+    /// silently drop.
+    InsecureSameSiteNone { name: String },
+    /// A response cookie is `Partitioned` without `Secure`, which browsers
+    /// reject.
+    InsecurePartitioned { name: String },
+    /// The session cookie exceeds the configured maximum size.
+    SessionTooLarge { len: usize },
+    /// A key couldn't be loaded or is too weak.
+    InvalidKey(String),
+    /// The session store failed under `StoreFailurePolicy::Unavailable`.
+    /// `SessionMiddleware` answers such requests with 503 Service
+    /// Unavailable.
+    StoreUnavailable(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidSession(reason) => write!(f, "invalid session cookie: {reason}"),
+            Error::InvalidName { name } => write!(f, "invalid cookie name `{name}`"),
+            Error::InvalidHeader { name } => {
+                write!(f, "cookie `{name}` is not a valid header value")
+            }
+            Error::InsecureSameSiteNone { name } => {
+                write!(f, "cookie `{name}` has SameSite=None but is not Secure")
+            }
+            Error::InsecurePartitioned { name } => {
+                write!(f, "cookie `{name}` is Partitioned but not Secure")
+            }
+            Error::SessionTooLarge { len } => {
+                write!(f, "session cookie of {len} bytes exceeds maximum size")
+            }
+            Error::InvalidKey(reason) => write!(f, "invalid key: {reason}"),
+            Error::StoreUnavailable(reason) => write!(f, "session store unavailable: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// What to do about recoverable errors, like undecodable session cookies or
+/// response cookies that aren't valid header values.
+#[derive(Clone, Default)]
+pub enum ErrorPolicy {
+    /// Skip the offending cookie.
+    #[default]
+    Ignore,
+    /// Pass the error to a callback, then skip the offending cookie.
+    Log(Arc<dyn Fn(&Error) + Send + Sync>),
+    /// Fail the request with the error.
+    Fail,
+}
+
+impl ErrorPolicy {
+    /// Logs errors with `log` before skipping the offending cookie.
+    pub fn log<F: Fn(&Error) + Send + Sync + 'static>(log: F) -> Self {
+        ErrorPolicy::Log(Arc::new(log))
+    }
+
+    pub(crate) fn handle(&self, error: Error) -> Result<(), BoxError> {
+        match self {
+            ErrorPolicy::Ignore => Ok(()),
+            ErrorPolicy::Log(log) => {
+                log(&error);
+                Ok(())
+            }
+            ErrorPolicy::Fail => Err(Box::new(error)),
+        }
+    }
+}
+
+/// What `SessionMiddleware` does when its session store fails, e.g. with a
+/// timeout or a refused connection. The outcome is available to handlers as
+/// `SessionState::StoreFailed`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum StoreFailurePolicy {
+    /// Fail the request with the store's error.
+    #[default]
+    Fail,
+    /// Treat the user as anonymous. The session starts out empty and isn't
+    /// written, so that the stored session is used again once the store
+    /// recovers.
+    Anonymous,
+    /// Answer with 503 Service Unavailable. Middleware can't skip the
+    /// handler, so it still runs, with an empty session that isn't written,
+    /// and should check `session_state` before doing any work.
+    Unavailable,
+    /// Embed the session data in the cookie, like without a store, until
+    /// the store recovers. A session that couldn't be loaded starts out
+    /// empty.
+    Cookie,
+}
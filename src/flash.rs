@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+use conduit::RequestExt;
+use conduit_middleware::BeforeResult;
+
+use crate::RequestSession;
+
+const FLASH_PREFIX: &str = "_flash.";
+
+/// Moves flash messages stored in the session by the previous request into
+/// the current request, removing them from the session.
+///
+/// Must be added after `SessionMiddleware`.
+#[derive(Default)]
+pub struct FlashMiddleware {}
+
+impl FlashMiddleware {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+struct Flash {
+    messages: HashMap<String, String>,
+}
+
+impl conduit_middleware::Middleware for FlashMiddleware {
+    fn before(&self, req: &mut dyn RequestExt) -> BeforeResult {
+        let keys = req
+            .session()
+            .keys()
+            .filter(|key| key.starts_with(FLASH_PREFIX))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut messages = HashMap::new();
+        if !keys.is_empty() {
+            let session = req.session_mut();
+            for key in keys {
+                if let Some(value) = session.remove(&key) {
+                    messages.insert(key[FLASH_PREFIX.len()..].to_string(), value);
+                }
+            }
+        }
+        req.mut_extensions().insert(Flash { messages });
+        Ok(())
+    }
+}
+
+pub trait RequestFlash {
+    /// Stores a message that will be available on the next request.
+    fn flash(&mut self, key: &str, value: &str);
+    /// The messages flashed by the previous request.
+    fn flashes(&self) -> &HashMap<String, String>;
+}
+
+impl<T: RequestExt + ?Sized> RequestFlash for T {
+    fn flash(&mut self, key: &str, value: &str) {
+        self.session_mut()
+            .insert(format!("{FLASH_PREFIX}{key}"), value.to_string());
+    }
+
+    fn flashes(&self) -> &HashMap<String, String> {
+        &self
+            .extensions()
+            .get::<Flash>()
+            .expect("missing flash messages")
+            .messages
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use conduit::{header, Body, Handler, HttpResult, Method, RequestExt, Response};
+    use conduit_middleware::MiddlewareBuilder;
+    use conduit_test::MockRequest;
+    use cookie::Key;
+
+    use crate::{FlashMiddleware, Middleware, RequestFlash, SessionMiddleware};
+
+    fn app(handler: fn(&mut dyn RequestExt) -> HttpResult) -> MiddlewareBuilder {
+        let master_key: Vec<u8> = (0..32).collect();
+        let mut app = MiddlewareBuilder::new(handler);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new(
+            "flash",
+            Key::derive_from(&master_key),
+            false,
+        ));
+        app.add(FlashMiddleware::new());
+        app
+    }
+
+    #[test]
+    fn next_request_only() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+
+        let response = app(set_flash).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        let response = app(read_flash).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        let response = app(no_flash).call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        fn set_flash(req: &mut dyn RequestExt) -> HttpResult {
+            req.flash("notice", "saved!");
+            assert!(req.flashes().is_empty());
+            Response::builder().body(Body::empty())
+        }
+        fn read_flash(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.flashes().get("notice").unwrap(), "saved!");
+            Response::builder().body(Body::empty())
+        }
+        fn no_flash(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.flashes().is_empty());
+            Response::builder().body(Body::empty())
+        }
+    }
+}
@@ -0,0 +1,333 @@
+#[cfg(any(feature = "serde", feature = "msgpack"))]
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
+
+use crate::{Error, SessionMiddleware};
+
+/// How the session map is serialized into the cookie value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SessionFormat {
+    /// Keys and values delimited by `0xff` bytes, base64 encoded.
+    #[default]
+    Delimited,
+    /// A JSON object, base64 encoded without padding.
+    #[cfg(feature = "serde")]
+    Json,
+    /// MessagePack, base64 encoded without padding.
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+// Prefixes the payload of versioned formats. It never occurs in UTF-8, so it
+// can't be the first byte of an unversioned `Delimited` payload.
+const VERSION_MARKER: u8 = 0xfe;
+// Set in the version byte when the payload is deflated.
+#[cfg(feature = "compression")]
+const COMPRESSED: u8 = 0x80;
+// `Delimited` payloads are only versioned when they are compressed.
+#[cfg(feature = "compression")]
+const DELIMITED_VERSION: u8 = 0;
+#[cfg(feature = "serde")]
+const JSON_VERSION: u8 = 1;
+#[cfg(feature = "msgpack")]
+const MESSAGE_PACK_VERSION: u8 = 2;
+
+// Upper bound for encoded session values. It is far above what browsers
+// store, but keeps crafted values from forcing large allocations.
+const MAX_ENCODED_SIZE: usize = 64 * 1024;
+
+// Upper bound for inflated payloads, so that a small cookie can't expand
+// into an arbitrarily large allocation.
+#[cfg(feature = "compression")]
+const MAX_INFLATED_SIZE: u64 = 1 << 20;
+
+/// Settings for `SessionFormat::encode`.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct EncodeOptions {
+    /// Deflates payloads larger than this many bytes.
+    pub(crate) compress_threshold: Option<usize>,
+    /// Encodes `Delimited` payloads as URL-safe base64 without padding,
+    /// instead of padding them with `0xff` bytes to avoid `=`.
+    pub(crate) url_safe: bool,
+}
+
+impl SessionFormat {
+    /// Encodes the session, deflating it first if it is larger than the
+    /// compression threshold.
+    pub(crate) fn encode(self, data: &HashMap<String, String>, options: EncodeOptions) -> String {
+        let compress_threshold = options.compress_threshold;
+        let (version, bytes) = match self {
+            SessionFormat::Delimited => (None, SessionMiddleware::encode_bytes(data)),
+            #[cfg(feature = "serde")]
+            SessionFormat::Json => (
+                Some(JSON_VERSION),
+                serde_json::to_vec(&sorted(data)).unwrap(),
+            ),
+            #[cfg(feature = "msgpack")]
+            SessionFormat::MessagePack => (
+                Some(MESSAGE_PACK_VERSION),
+                rmp_serde::to_vec(&sorted(data)).unwrap(),
+            ),
+        };
+
+        #[cfg(feature = "compression")]
+        if matches!(compress_threshold, Some(threshold) if bytes.len() > threshold) {
+            let version = version.unwrap_or(DELIMITED_VERSION) | COMPRESSED;
+            return versioned(version, deflate(&bytes));
+        }
+        #[cfg(not(feature = "compression"))]
+        let _ = compress_threshold;
+
+        match version {
+            Some(version) => versioned(version, bytes),
+            None if options.url_safe => encode_config(bytes, URL_SAFE_NO_PAD),
+            None => SessionMiddleware::encode(data),
+        }
+    }
+
+    /// Decodes a session written in any format, returning the format it was
+    /// written in alongside the data.
+    pub(crate) fn decode(value: &str) -> Result<(SessionFormat, HashMap<String, String>), Error> {
+        if value.len() > MAX_ENCODED_SIZE {
+            let reason = format!("value exceeds {MAX_ENCODED_SIZE} bytes");
+            return Err(Error::InvalidSession(reason));
+        }
+        if let Ok(payload) = decode_config(value, URL_SAFE_NO_PAD) {
+            if let [VERSION_MARKER, version, bytes @ ..] = &payload[..] {
+                return decode_versioned(*version, bytes).ok_or_else(|| {
+                    Error::InvalidSession(format!("invalid payload for version {version}"))
+                });
+            }
+        }
+        let data = SessionMiddleware::try_decode_value(value)?;
+        Ok((SessionFormat::Delimited, data))
+    }
+}
+
+/// The entries sorted by key, so that equal sessions always produce the
+/// same bytes.
+#[cfg(any(feature = "serde", feature = "msgpack"))]
+fn sorted(data: &HashMap<String, String>) -> BTreeMap<&str, &str> {
+    data.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect()
+}
+
+fn versioned(version: u8, bytes: Vec<u8>) -> String {
+    let mut payload = vec![VERSION_MARKER, version];
+    payload.extend(bytes);
+    encode_config(payload, URL_SAFE_NO_PAD)
+}
+
+#[cfg_attr(
+    not(any(feature = "compression", feature = "serde", feature = "msgpack")),
+    allow(unused_variables)
+)]
+fn decode_versioned(version: u8, bytes: &[u8]) -> Option<(SessionFormat, HashMap<String, String>)> {
+    #[cfg(feature = "compression")]
+    if version & COMPRESSED != 0 {
+        let bytes = inflate(bytes)?;
+        return match version & !COMPRESSED {
+            DELIMITED_VERSION => Some((
+                SessionFormat::Delimited,
+                SessionMiddleware::decode_bytes(&bytes).ok()?,
+            )),
+            version => decode_versioned(version, &bytes),
+        };
+    }
+    match version {
+        #[cfg(feature = "serde")]
+        JSON_VERSION => Some((SessionFormat::Json, serde_json::from_slice(bytes).ok()?)),
+        #[cfg(feature = "msgpack")]
+        MESSAGE_PACK_VERSION => Some((
+            SessionFormat::MessagePack,
+            rmp_serde::from_slice(bytes).ok()?,
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "compression")]
+fn deflate(bytes: &[u8]) -> Vec<u8> {
+    use flate2::{write::DeflateEncoder, Compression};
+    use std::io::Write;
+
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[cfg(feature = "compression")]
+fn inflate(bytes: &[u8]) -> Option<Vec<u8>> {
+    use flate2::read::DeflateDecoder;
+    use std::io::Read;
+
+    // Read one byte past the limit to tell oversized payloads apart
+    let mut inflated = Vec::new();
+    DeflateDecoder::new(bytes)
+        .take(MAX_INFLATED_SIZE + 1)
+        .read_to_end(&mut inflated)
+        .ok()?;
+    (inflated.len() as u64 <= MAX_INFLATED_SIZE).then_some(inflated)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{EncodeOptions, SessionFormat};
+
+    fn data() -> HashMap<String, String> {
+        let mut map = HashMap::new();
+        map.insert("a".to_string(), "bc".to_string());
+        map.insert("d".to_string(), "e\u{ff}f".to_string());
+        map
+    }
+
+    #[test]
+    fn delimited_round_trip() {
+        let encoded = SessionFormat::Delimited.encode(&data(), EncodeOptions::default());
+        assert_eq!(
+            SessionFormat::decode(&encoded).unwrap(),
+            (SessionFormat::Delimited, data())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn json_round_trip() {
+        let encoded = SessionFormat::Json.encode(&data(), EncodeOptions::default());
+        assert!(!encoded.contains('='));
+        assert_eq!(
+            SessionFormat::decode(&encoded).unwrap(),
+            (SessionFormat::Json, data())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "msgpack")]
+    fn msgpack_round_trip() {
+        let encoded = SessionFormat::MessagePack.encode(&data(), EncodeOptions::default());
+        assert_eq!(
+            SessionFormat::decode(&encoded).unwrap(),
+            (SessionFormat::MessagePack, data())
+        );
+    }
+
+    #[test]
+    fn empty_delimited() {
+        let encoded = SessionFormat::Delimited.encode(&HashMap::new(), EncodeOptions::default());
+        assert_eq!(
+            SessionFormat::decode(&encoded).unwrap(),
+            (SessionFormat::Delimited, HashMap::new())
+        );
+    }
+
+    #[test]
+    fn deterministic_encoding() {
+        let entries = (0..20).map(|i| (i.to_string(), "x".repeat(i)));
+        let forward = entries.clone().collect::<HashMap<_, _>>();
+        let backward = entries.rev().collect::<HashMap<_, _>>();
+
+        let formats = [
+            SessionFormat::Delimited,
+            #[cfg(feature = "serde")]
+            SessionFormat::Json,
+            #[cfg(feature = "msgpack")]
+            SessionFormat::MessagePack,
+        ];
+        for format in formats {
+            assert_eq!(
+                format.encode(&forward, EncodeOptions::default()),
+                format.encode(&backward, EncodeOptions::default())
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_payload() {
+        assert!(SessionFormat::decode("not base64!").is_err());
+        assert!(SessionFormat::decode("_gkA").is_err());
+    }
+
+    #[test]
+    fn url_safe_delimited() {
+        let options = EncodeOptions {
+            url_safe: true,
+            ..Default::default()
+        };
+        let mut data = data();
+        data.insert("e".to_string(), "\u{3ff}\u{fff}>?".to_string());
+        let encoded = SessionFormat::Delimited.encode(&data, options);
+        assert!(!encoded.contains(['+', '/', '=']));
+        assert_eq!(
+            SessionFormat::decode(&encoded).unwrap(),
+            (SessionFormat::Delimited, data.clone())
+        );
+
+        // Cookies written with the legacy padding are still readable
+        let legacy = SessionFormat::Delimited.encode(&data, EncodeOptions::default());
+        assert_ne!(legacy, encoded);
+        assert_eq!(SessionFormat::decode(&legacy).unwrap().1, data);
+    }
+
+    #[cfg(feature = "compression")]
+    fn compress(threshold: usize) -> EncodeOptions {
+        EncodeOptions {
+            compress_threshold: Some(threshold),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn pathological_input() {
+        let oversized = "a".repeat(super::MAX_ENCODED_SIZE + 1);
+        assert!(SessionFormat::decode(&oversized).is_err());
+
+        // Runs of separators end the session early
+        let separators = base64::encode(vec![0xff; 30_000]);
+        assert_eq!(
+            SessionFormat::decode(&separators).unwrap().1,
+            HashMap::new()
+        );
+
+        let mut invalid_utf8 = b"a\xffb\xff".to_vec();
+        invalid_utf8.extend_from_slice(&[0xc3, 0x28, 0xff, b'c']);
+        assert!(SessionFormat::decode(&base64::encode(invalid_utf8)).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn compressed_round_trip() {
+        let mut data = data();
+        data.insert("large".to_string(), "x".repeat(1000));
+
+        let small = SessionFormat::Delimited.encode(&data, compress(10_000));
+        let compressed = SessionFormat::Delimited.encode(&data, compress(100));
+        assert!(compressed.len() < small.len());
+        assert_eq!(
+            SessionFormat::decode(&compressed).unwrap(),
+            (SessionFormat::Delimited, data.clone())
+        );
+
+        #[cfg(feature = "serde")]
+        {
+            let compressed = SessionFormat::Json.encode(&data, compress(100));
+            assert_eq!(
+                SessionFormat::decode(&compressed).unwrap(),
+                (SessionFormat::Json, data)
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn oversized_inflated_payload() {
+        // Rejected as a whole rather than decoded up to the limit
+        let mut data = HashMap::new();
+        let value = "x".repeat(super::MAX_INFLATED_SIZE as usize);
+        data.insert("large".to_string(), value);
+        let compressed = SessionFormat::Delimited.encode(&data, compress(100));
+        assert!(SessionFormat::decode(&compressed).is_err());
+    }
+}
@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use conduit::RequestExt;
+
+use crate::SessionInfo;
+
+/// Callbacks invoked by `SessionMiddleware` at points in the session
+/// lifecycle, e.g. for audit logging. All methods do nothing by default.
+pub trait SessionHooks: Send + Sync + 'static {
+    /// A new, empty session was started, either because the request had
+    /// no usable session or because it was expired or discarded.
+    fn on_create(&self, _req: &dyn RequestExt, _data: &mut HashMap<String, String>) {}
+    /// A valid session was read from the request.
+    fn on_load(&self, _req: &dyn RequestExt, _data: &HashMap<String, String>) {}
+    /// The session cookie failed verification or couldn't be decoded.
+    fn on_invalid(&self, _req: &dyn RequestExt, _info: &SessionInfo) {}
+    /// The session cookie was written to the response.
+    fn on_write(&self, _req: &dyn RequestExt, _data: &HashMap<String, String>) {}
+}
+
+impl<H: SessionHooks + ?Sized> SessionHooks for Arc<H> {
+    fn on_create(&self, req: &dyn RequestExt, data: &mut HashMap<String, String>) {
+        (**self).on_create(req, data)
+    }
+
+    fn on_load(&self, req: &dyn RequestExt, data: &HashMap<String, String>) {
+        (**self).on_load(req, data)
+    }
+
+    fn on_invalid(&self, req: &dyn RequestExt, info: &SessionInfo) {
+        (**self).on_invalid(req, info)
+    }
+
+    fn on_write(&self, req: &dyn RequestExt, data: &HashMap<String, String>) {
+        (**self).on_write(req, data)
+    }
+}
@@ -0,0 +1,16 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Reads session cookies written by another framework, so that users keep
+/// their sessions while an app is migrated to `SessionMiddleware`.
+pub trait SessionImporter: Send + Sync + 'static {
+    /// Verifies and decodes the value of the foreign session cookie,
+    /// returning `None` if it isn't valid.
+    fn import(&self, value: &str) -> Option<HashMap<String, String>>;
+}
+
+impl<I: SessionImporter + ?Sized> SessionImporter for Arc<I> {
+    fn import(&self, value: &str) -> Option<HashMap<String, String>> {
+        (**self).import(value)
+    }
+}
@@ -0,0 +1,126 @@
+use std::sync::OnceLock;
+
+use conduit::header::HeaderValue;
+use cookie::{Cookie, CookieJar};
+use percent_encoding::percent_decode_str;
+
+use crate::duplicates::{AllCookies, DuplicateCookies};
+
+/// The settings of `Middleware` that affect how request cookies are parsed.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct ParseOptions {
+    pub(crate) max_cookies: Option<usize>,
+    pub(crate) duplicates: DuplicateCookies,
+    pub(crate) quoting: bool,
+    pub(crate) percent_encoding: bool,
+}
+
+/// The request cookies, and the ones the jar was created with.
+#[derive(Default)]
+pub(crate) struct Parsed {
+    pub(crate) jar: CookieJar,
+    pub(crate) original: Vec<Cookie<'static>>,
+    pub(crate) all: AllCookies,
+}
+
+/// The request cookies, parsed from a copy of the `Cookie` headers when they
+/// are first accessed. Requests that never look at their cookies don't pay
+/// for allocating them.
+pub(crate) struct LazyJar {
+    headers: Vec<HeaderValue>,
+    options: ParseOptions,
+    parsed: OnceLock<Parsed>,
+}
+
+impl LazyJar {
+    pub(crate) fn new(headers: Vec<HeaderValue>, options: ParseOptions) -> Self {
+        LazyJar {
+            headers,
+            options,
+            parsed: OnceLock::new(),
+        }
+    }
+
+    /// A jar holding cookies that were already parsed.
+    pub(crate) fn from_parsed(parsed: Parsed) -> Self {
+        LazyJar {
+            headers: Vec::new(),
+            options: ParseOptions::default(),
+            parsed: OnceLock::from(parsed),
+        }
+    }
+
+    pub(crate) fn get(&self) -> &Parsed {
+        self.parsed
+            .get_or_init(|| self.options.parse(&self.headers))
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut Parsed {
+        self.get();
+        self.parsed.get_mut().unwrap()
+    }
+
+    /// Whether the cookies were accessed, and thus may have changed.
+    pub(crate) fn is_parsed(&self) -> bool {
+        self.parsed.get().is_some()
+    }
+}
+
+impl ParseOptions {
+    /// Parses the `Cookie` headers in order. Empty crumbs and crumbs without
+    /// a name are skipped.
+    pub(crate) fn parse(&self, headers: &[HeaderValue]) -> Parsed {
+        let mut all = AllCookies::default();
+        let max_cookies = self.max_cookies.unwrap_or(usize::MAX);
+        let mut jar = CookieJar::new();
+        let mut cookies = 0;
+        'headers: for cookie in headers {
+            if let Ok(cookie) = cookie.to_str() {
+                for cookie in cookie.split(';') {
+                    if cookies == max_cookies {
+                        event!(WARN, max_cookies, "ignoring excess request cookies");
+                        break 'headers;
+                    }
+                    if let Some(cookie) = self.parse_cookie(cookie) {
+                        cookies += 1;
+                        match self.duplicates {
+                            DuplicateCookies::FirstWins if jar.get(cookie.name()).is_some() => {}
+                            DuplicateCookies::CollectAll => {
+                                let name = cookie.name().to_string();
+                                all.0.entry(name).or_default().push(cookie.clone());
+                                jar.add_original(cookie);
+                            }
+                            _ => jar.add_original(cookie),
+                        }
+                    }
+                }
+            }
+        }
+        event!(DEBUG, cookies, "parsed request cookies");
+        let original = jar.iter().cloned().collect();
+        Parsed { jar, original, all }
+    }
+
+    fn parse_cookie(&self, key_value: &str) -> Option<Cookie<'static>> {
+        // Borrow from the header until the name and value are copied into
+        // the cookie, so that each pair costs two allocations
+        let (key, mut value) = parse_pair(key_value)?;
+        if key.is_empty() {
+            return None;
+        }
+        if self.quoting && value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+            value = &value[1..value.len() - 1];
+        }
+        if self.percent_encoding {
+            let key = percent_decode_str(key).decode_utf8_lossy();
+            let value = percent_decode_str(value).decode_utf8_lossy();
+            return Some(Cookie::new(key.into_owned(), value.into_owned()));
+        }
+        Some(Cookie::new(key.to_string(), value.to_string()))
+    }
+}
+
+fn parse_pair(key_value: &str) -> Option<(&str, &str)> {
+    let (key, value) = key_value.split_once('=')?;
+    Some((key.trim(), value.trim()))
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+
+use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
+use cookie::Key;
+use hmac::{Hmac, Mac};
+use serde_json::{Map, Value};
+use sha2::Sha256;
+
+use crate::Error;
+
+// `{"alg":"HS256","typ":"JWT"}`, base64 encoded.
+const HEADER: &str = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9";
+
+// Session metadata stored in registered claims, so that other services can
+// check them with any JWT library.
+const REGISTERED: [(&str, &str); 3] = [("\0id", "jti"), ("\0expires", "exp"), ("\0issued", "iat")];
+
+// The claims holding Unix timestamps, which JWT requires to be numbers.
+const NUMERIC: [&str; 2] = ["exp", "iat"];
+
+// The private claim holding the other session metadata, whose keys start
+// with a NUL byte that doesn't belong in claim names.
+const METADATA_CLAIM: &str = "conduit_cookie";
+
+fn mac(key: &Key, message: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.signing()).unwrap();
+    mac.update(message.as_bytes());
+    mac
+}
+
+/// Encodes the session data as the base64 claims segment of a JWT.
+pub(crate) fn encode_claims(data: &HashMap<String, String>) -> String {
+    let mut claims = Map::new();
+    let mut metadata = Map::new();
+    for (key, value) in data {
+        let claim = match REGISTERED.iter().find(|(name, _)| name == key) {
+            Some((_, claim)) => claim,
+            None => match key.strip_prefix('\0') {
+                Some(key) => {
+                    metadata.insert(key.to_string(), Value::from(value.as_str()));
+                    continue;
+                }
+                None => key.as_str(),
+            },
+        };
+        let value = match value.parse::<i64>() {
+            Ok(number) if NUMERIC.contains(&claim) => Value::from(number),
+            _ => Value::from(value.as_str()),
+        };
+        claims.insert(claim.to_string(), value);
+    }
+    if !metadata.is_empty() {
+        claims.insert(METADATA_CLAIM.to_string(), Value::Object(metadata));
+    }
+    let json = serde_json::to_vec(&claims).unwrap();
+    encode_config(json, URL_SAFE_NO_PAD)
+}
+
+/// Decodes a claims segment written by `encode_claims`, or by another
+/// service with string, number or boolean claims.
+pub(crate) fn decode_claims(claims: &str) -> Result<HashMap<String, String>, Error> {
+    let invalid = |reason: &str| Error::InvalidSession(format!("invalid JWT claims: {reason}"));
+    let json = decode_config(claims, URL_SAFE_NO_PAD).map_err(|_| invalid("not base64"))?;
+    let claims = serde_json::from_slice::<Map<String, Value>>(&json);
+    let claims = claims.map_err(|_| invalid("not a JSON object"))?;
+    let mut data = HashMap::with_capacity(claims.len());
+    for (claim, value) in claims {
+        if let (METADATA_CLAIM, Value::Object(metadata)) = (claim.as_str(), &value) {
+            for (key, value) in metadata {
+                let value = value.as_str().ok_or_else(|| invalid("invalid metadata"))?;
+                data.insert(format!("\0{key}"), value.to_string());
+            }
+            continue;
+        }
+        let value = match value {
+            Value::String(value) => value,
+            Value::Number(_) | Value::Bool(_) => value.to_string(),
+            _ => return Err(invalid("nested values aren't supported")),
+        };
+        let key = match REGISTERED.iter().find(|(_, name)| *name == claim) {
+            Some((key, _)) => key.to_string(),
+            None => claim,
+        };
+        data.insert(key, value);
+    }
+    Ok(data)
+}
+
+/// Signs a claims segment with HS256, returning the complete token.
+pub(crate) fn sign(claims: &str, key: &Key) -> String {
+    let message = format!("{HEADER}.{claims}");
+    let signature = mac(key, &message).finalize().into_bytes();
+    format!("{}.{}", message, encode_config(signature, URL_SAFE_NO_PAD))
+}
+
+/// Returns the claims segment of `token` if it is an HS256 token signed
+/// with `key`.
+pub(crate) fn verify<'a>(token: &'a str, key: &Key) -> Option<&'a str> {
+    let (message, signature) = token.rsplit_once('.')?;
+    let (header, claims) = message.split_once('.')?;
+    let header = decode_config(header, URL_SAFE_NO_PAD).ok()?;
+    let header = serde_json::from_slice::<Map<String, Value>>(&header).ok()?;
+    if header.get("alg").and_then(Value::as_str) != Some("HS256") {
+        return None;
+    }
+    let signature = decode_config(signature, URL_SAFE_NO_PAD).ok()?;
+    mac(key, message).verify_slice(&signature).ok()?;
+    Some(claims)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use base64::{encode_config, URL_SAFE_NO_PAD};
+    use cookie::Key;
+
+    use super::{decode_claims, encode_claims, sign, verify};
+
+    #[test]
+    fn round_trip() {
+        let key = Key::from(&[1; 64]);
+        let mut data = HashMap::new();
+        data.insert("user".to_string(), "alice".to_string());
+        data.insert("\0id".to_string(), "abc".to_string());
+        data.insert("\0expires".to_string(), "1700000000".to_string());
+        data.insert("\0created".to_string(), "1690000000".to_string());
+        data.insert("\0expires:code".to_string(), "1700000000".to_string());
+
+        let token = sign(&encode_claims(&data), &key);
+        let claims = verify(&token, &key).unwrap();
+        let json = base64::decode_config(claims, URL_SAFE_NO_PAD).unwrap();
+        let json = serde_json::from_slice::<serde_json::Value>(&json).unwrap();
+        assert_eq!(json["exp"], 1_700_000_000);
+        assert_eq!(json["jti"], "abc");
+        // No claim names with NUL bytes
+        let names = json.as_object().unwrap().keys().collect::<Vec<_>>();
+        assert!(names.iter().all(|name| !name.contains('\0')));
+        assert_eq!(json["conduit_cookie"]["created"], "1690000000");
+        assert_eq!(json["conduit_cookie"]["expires:code"], "1700000000");
+        assert_eq!(decode_claims(claims).unwrap(), data);
+
+        assert!(verify(&token, &Key::from(&[2; 64])).is_none());
+        data.insert("user".to_string(), "mallory".to_string());
+        let (header, rest) = token.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let tampered = format!("{}.{}.{}", header, encode_claims(&data), signature);
+        assert!(verify(&tampered, &key).is_none());
+
+        // Unsigned tokens are never accepted
+        let none = encode_config(r#"{"alg":"none"}"#, URL_SAFE_NO_PAD);
+        let (_, rest) = token.split_once('.').unwrap();
+        assert!(verify(&format!("{none}.{rest}"), &key).is_none());
+    }
+}
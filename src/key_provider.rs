@@ -0,0 +1,32 @@
+use std::sync::Arc;
+
+use cookie::Key;
+
+/// Supplies the keys for signing and encrypting session cookies, looked up
+/// on every request so that keys can be rotated without a restart, e.g. by
+/// watching a file.
+pub trait KeyProvider: Send + Sync + 'static {
+    /// The key new cookies are signed or encrypted with.
+    fn current(&self) -> Key;
+
+    /// Retired keys that are still accepted when reading cookies.
+    fn previous(&self) -> Vec<Key> {
+        Vec::new()
+    }
+}
+
+impl KeyProvider for Key {
+    fn current(&self) -> Key {
+        self.clone()
+    }
+}
+
+impl<P: KeyProvider + ?Sized> KeyProvider for Arc<P> {
+    fn current(&self) -> Key {
+        (**self).current()
+    }
+
+    fn previous(&self) -> Vec<Key> {
+        (**self).previous()
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use base64::{STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD};
+use cookie::Key;
+
+use crate::Error;
+
+/// Minimum length of the master key material, as required by
+/// `Key::derive_from`.
+const MIN_KEY_LEN: usize = 32;
+/// Rejects keys with too few distinct bytes, e.g. a repeated character or a
+/// short password padded to length.
+const MIN_DISTINCT_BYTES: usize = 16;
+
+/// Where to load the master key for signing and encrypting cookies from.
+#[derive(Clone, Debug)]
+pub enum KeySource {
+    /// A hex encoded key.
+    Hex(String),
+    /// A base64 encoded key, in the standard or URL-safe alphabet.
+    Base64(String),
+    /// An environment variable holding a base64 encoded key.
+    Env(String),
+    /// A file holding a base64 encoded key.
+    File(PathBuf),
+}
+
+impl KeySource {
+    /// Loads the key, checking that it has at least 32 bytes and isn't
+    /// trivially guessable.
+    pub fn load(&self) -> Result<Key, Error> {
+        let bytes = match self {
+            KeySource::Hex(hex) => decode_hex(hex.trim())?,
+            KeySource::Base64(encoded) => decode_base64(encoded)?,
+            KeySource::Env(var) => {
+                let encoded =
+                    std::env::var(var).map_err(|e| Error::InvalidKey(format!("${var}: {e}")))?;
+                decode_base64(&encoded)?
+            }
+            KeySource::File(path) => {
+                let encoded = std::fs::read_to_string(path)
+                    .map_err(|e| Error::InvalidKey(format!("{}: {}", path.display(), e)))?;
+                decode_base64(&encoded)?
+            }
+        };
+        if bytes.len() < MIN_KEY_LEN {
+            let reason = format!("key must have at least {MIN_KEY_LEN} bytes");
+            return Err(Error::InvalidKey(reason));
+        }
+        if bytes.iter().collect::<HashSet<_>>().len() < MIN_DISTINCT_BYTES {
+            return Err(Error::InvalidKey("key has too little entropy".to_string()));
+        }
+        Ok(Key::derive_from(&bytes))
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::InvalidKey("invalid hex".to_string());
+    if !hex.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            let byte = hex.get(i..i + 2).ok_or_else(invalid)?;
+            u8::from_str_radix(byte, 16).map_err(|_| invalid())
+        })
+        .collect()
+}
+
+fn decode_base64(encoded: &str) -> Result<Vec<u8>, Error> {
+    let encoded = encoded.trim();
+    [STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD]
+        .iter()
+        .find_map(|config| base64::decode_config(encoded, *config).ok())
+        .ok_or_else(|| Error::InvalidKey("invalid base64".to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::KeySource;
+
+    #[test]
+    fn load_keys() {
+        let bytes = (0..32).collect::<Vec<u8>>();
+        let hex = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        let encoded = base64::encode(&bytes);
+
+        let from_hex = KeySource::Hex(hex).load().unwrap();
+        let from_base64 = KeySource::Base64(encoded.clone()).load().unwrap();
+        assert_eq!(from_hex.master(), from_base64.master());
+
+        // Only this test sets the variable, so parallel tests can't race on it
+        std::env::set_var("CONDUIT_COOKIE_LOAD_KEYS_KEY", &encoded);
+        let from_env = KeySource::Env("CONDUIT_COOKIE_LOAD_KEYS_KEY".to_string());
+        assert_eq!(from_env.load().unwrap().master(), from_hex.master());
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "{encoded}").unwrap();
+        let from_file = KeySource::File(file.path().to_path_buf()).load().unwrap();
+        assert_eq!(from_file.master(), from_hex.master());
+    }
+
+    #[test]
+    fn reject_weak_keys() {
+        assert!(KeySource::Hex("abc".to_string()).load().is_err());
+        assert!(KeySource::Base64(base64::encode([1; 16])).load().is_err());
+        assert!(KeySource::Base64(base64::encode([1; 64])).load().is_err());
+        assert!(KeySource::Env("CONDUIT_COOKIE_MISSING_KEY".to_string())
+            .load()
+            .is_err());
+    }
+}
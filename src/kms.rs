@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
+use conduit::BoxError;
+use cookie::time::{Duration, OffsetDateTime};
+
+use crate::{Clock, Error, Signer, SystemClock};
+
+// Bounds the cache, which also holds unknown key ids taken from cookies.
+const MAX_CACHED_KEYS: usize = 64;
+
+/// Checks signatures locally, e.g. with the public half of a KMS key.
+pub trait Verifier: Send + Sync + 'static {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool;
+}
+
+/// Signing keys held by a KMS or HSM, for `KmsSigner`.
+///
+/// The methods block, as conduit handlers do. Async KMS clients can be
+/// driven with their runtime's `block_on`. Since verifiers are cached, only
+/// session writes and cache misses wait on the KMS.
+pub trait KmsProvider: Send + Sync + 'static {
+    /// The id of the key new cookies are signed with. It must not contain
+    /// `.`.
+    fn current_key_id(&self) -> String;
+
+    /// Signs `message` with the key `key_id`, usually by a call to the KMS.
+    fn sign(&self, key_id: &str, message: &[u8]) -> Result<Vec<u8>, BoxError>;
+
+    /// Fetches a verifier for signatures of the key `key_id`, or `None` if
+    /// there is no such key.
+    fn verifier(&self, key_id: &str) -> Result<Option<Arc<dyn Verifier>>, BoxError>;
+}
+
+impl<P: KmsProvider + ?Sized> KmsProvider for Arc<P> {
+    fn current_key_id(&self) -> String {
+        (**self).current_key_id()
+    }
+
+    fn sign(&self, key_id: &str, message: &[u8]) -> Result<Vec<u8>, BoxError> {
+        (**self).sign(key_id, message)
+    }
+
+    fn verifier(&self, key_id: &str) -> Result<Option<Arc<dyn Verifier>>, BoxError> {
+        (**self).verifier(key_id)
+    }
+}
+
+/// A `Signer` delegating to a `KmsProvider`, caching its verifiers.
+///
+/// Values are prefixed with the id of the signing key, so that keys can be
+/// rotated in the KMS. Cookies signed with another than the current key are
+/// signed again. A failure to fetch a verifier fails verification, but isn't
+/// cached.
+pub struct KmsSigner<P> {
+    provider: P,
+    cache_ttl: Duration,
+    clock: Box<dyn Clock>,
+    cache: Mutex<HashMap<String, CachedVerifier>>,
+}
+
+struct CachedVerifier {
+    verifier: Option<Arc<dyn Verifier>>,
+    fetched: OffsetDateTime,
+}
+
+impl<P: KmsProvider> KmsSigner<P> {
+    pub fn new(provider: P) -> Self {
+        KmsSigner {
+            provider,
+            cache_ttl: Duration::hours(1),
+            clock: Box::new(SystemClock),
+            cache: Mutex::default(),
+        }
+    }
+
+    /// How long verifiers, and the absence of unknown keys, are cached.
+    pub fn cache_ttl(mut self, cache_ttl: Duration) -> Self {
+        self.cache_ttl = cache_ttl;
+        self
+    }
+
+    pub fn clock<C: Clock>(mut self, clock: C) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    fn verifier(&self, key_id: &str) -> Option<Arc<dyn Verifier>> {
+        let now = self.clock.now();
+        if let Some(cached) = self.cache.lock().unwrap().get(key_id) {
+            if cached.fetched + self.cache_ttl > now {
+                return cached.verifier.clone();
+            }
+        }
+        // The lock isn't held while waiting on the KMS
+        let verifier = self.provider.verifier(key_id).ok()?;
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED_KEYS {
+            cache.retain(|_, cached| cached.fetched + self.cache_ttl > now);
+            if cache.len() >= MAX_CACHED_KEYS {
+                cache.clear();
+            }
+        }
+        let cached = CachedVerifier {
+            verifier: verifier.clone(),
+            fetched: now,
+        };
+        cache.insert(key_id.to_string(), cached);
+        verifier
+    }
+}
+
+fn message(name: &str, value: &str) -> Vec<u8> {
+    format!("{name}={value}").into_bytes()
+}
+
+impl<P: KmsProvider> Signer for KmsSigner<P> {
+    fn sign(&self, name: &str, value: &str) -> Result<String, BoxError> {
+        let key_id = self.provider.current_key_id();
+        if key_id.contains('.') {
+            let reason = format!("key id `{key_id}` contains `.`");
+            return Err(Box::new(Error::InvalidKey(reason)));
+        }
+        let signature = self.provider.sign(&key_id, &message(name, value))?;
+        let signature = encode_config(signature, URL_SAFE_NO_PAD);
+        Ok(format!("{key_id}.{signature}.{value}"))
+    }
+
+    fn verify(&self, name: &str, value: &str) -> Option<(String, bool)> {
+        let mut parts = value.splitn(3, '.');
+        let (key_id, signature, value) = (parts.next()?, parts.next()?, parts.next()?);
+        let signature = decode_config(signature, URL_SAFE_NO_PAD).ok()?;
+        let verifier = self.verifier(key_id)?;
+        if !verifier.verify(&message(name, value), &signature) {
+            return None;
+        }
+        let outdated = key_id != self.provider.current_key_id();
+        Some((value.to_string(), outdated))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use conduit::BoxError;
+    use sha2::{Digest, Sha256};
+
+    use super::{KmsProvider, KmsSigner, Verifier};
+    use crate::Signer;
+
+    // Stands in for an asymmetric key, with a digest of the key id as the
+    // signature
+    struct Kms {
+        current: Mutex<String>,
+        fetches: AtomicUsize,
+    }
+
+    struct KeyVerifier(String);
+
+    fn signature(key_id: &str, message: &[u8]) -> Vec<u8> {
+        let mut digest = Sha256::new();
+        digest.update(key_id);
+        digest.update(message);
+        digest.finalize().to_vec()
+    }
+
+    impl Verifier for KeyVerifier {
+        fn verify(&self, message: &[u8], sig: &[u8]) -> bool {
+            signature(&self.0, message) == sig
+        }
+    }
+
+    impl KmsProvider for Kms {
+        fn current_key_id(&self) -> String {
+            self.current.lock().unwrap().clone()
+        }
+
+        fn sign(&self, key_id: &str, message: &[u8]) -> Result<Vec<u8>, BoxError> {
+            Ok(signature(key_id, message))
+        }
+
+        fn verifier(&self, key_id: &str) -> Result<Option<Arc<dyn Verifier>>, BoxError> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            let known = ["k1", "k2"].contains(&key_id);
+            Ok(known.then(|| Arc::new(KeyVerifier(key_id.to_string())) as Arc<dyn Verifier>))
+        }
+    }
+
+    #[test]
+    fn caches_verifiers() {
+        let kms = Arc::new(Kms {
+            current: Mutex::new("k1".to_string()),
+            fetches: AtomicUsize::new(0),
+        });
+        let signer = KmsSigner::new(kms.clone());
+
+        let signed = signer.sign("session", "a.b").unwrap();
+        assert!(signed.starts_with("k1."));
+        for _ in 0..3 {
+            let verified = signer.verify("session", &signed);
+            assert_eq!(verified, Some(("a.b".to_string(), false)));
+        }
+        assert_eq!(kms.fetches.load(Ordering::SeqCst), 1);
+        assert_eq!(signer.verify("other", &signed), None);
+
+        // Cookies of rotated keys are still accepted, but outdated
+        *kms.current.lock().unwrap() = "k2".to_string();
+        let verified = signer.verify("session", &signed);
+        assert_eq!(verified, Some(("a.b".to_string(), true)));
+        assert!(signer.sign("session", "a.b").unwrap().starts_with("k2."));
+
+        let forged = signed.replacen("k1", "k3", 1);
+        assert_eq!(signer.verify("session", &forged), None);
+        assert_eq!(signer.verify("session", &forged), None);
+        assert_eq!(kms.fetches.load(Ordering::SeqCst), 2);
+    }
+}
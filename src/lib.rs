@@ -1,94 +1,863 @@
 #![cfg_attr(test, deny(warnings))]
 #![warn(rust_2018_idioms)]
 
-use conduit::{header, RequestExt};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use conduit::header::{self, HeaderValue};
+use conduit::{BoxError, HeaderMap, RequestExt};
 use conduit_middleware::{AfterResult, BeforeResult};
-use cookie::{Cookie, CookieJar};
+use cookie::{Cookie, CookieJar, SameSite};
+#[cfg(feature = "session")]
+use cookie::{Key, PrivateJar, SignedJar};
+
+use crate::duplicates::AllCookies;
+use crate::jar::{LazyJar, ParseOptions, Parsed};
+
+pub use cookie;
+
+// Emits a `tracing` event when the `tracing` feature is enabled.
+macro_rules! event {
+    ($level:ident, $($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::event!(tracing::Level::$level, $($arg)*);
+    };
+}
 
-pub use crate::session::{RequestSession, SessionMiddleware};
+#[cfg(feature = "session")]
+pub use crate::binding::{client_ip_prefix, user_agent};
+#[cfg(feature = "session")]
+pub use crate::clock::{Clock, SystemClock};
+#[cfg(feature = "session")]
+pub use crate::codec::SessionCodec;
+#[cfg(feature = "session")]
+pub use crate::csrf::{CsrfMiddleware, RequestCsrf};
+#[cfg(feature = "debug")]
+pub use crate::debug::DebugMiddleware;
+pub use crate::defaults::{CookieDefaults, CookieOverride};
+pub use crate::duplicates::DuplicateCookies;
+pub use crate::error::{Error, ErrorPolicy, StoreFailurePolicy};
+#[cfg(feature = "session")]
+pub use crate::flash::{FlashMiddleware, RequestFlash};
+#[cfg(feature = "session")]
+pub use crate::format::SessionFormat;
+#[cfg(feature = "session")]
+pub use crate::hooks::SessionHooks;
+#[cfg(feature = "session")]
+pub use crate::import::SessionImporter;
+#[cfg(feature = "session")]
+pub use crate::key_provider::KeyProvider;
+#[cfg(feature = "session")]
+pub use crate::key_source::KeySource;
+#[cfg(feature = "session")]
+pub use crate::kms::{KmsProvider, KmsSigner, Verifier};
+pub use crate::metrics::CookieMetrics;
+pub use crate::name::NameValidation;
+#[cfg(feature = "session")]
+pub use crate::nonce::RequestNonce;
+pub use crate::preferences::{Preferences, PreferencesMiddleware, RequestPreferences};
+#[cfg(feature = "rack")]
+pub use crate::rack::RackSession;
+#[cfg(feature = "redis-session")]
+pub use crate::redis_store::RedisStore;
+#[cfg(feature = "session")]
+pub use crate::remember_me::{
+    RememberMeEntry, RememberMeMiddleware, RememberMeStore, RequestRememberMe,
+};
+pub use crate::response_cookie::ResponseCookieBuilder;
+pub use crate::scheme::{RequestScheme, SchemeMiddleware};
+#[cfg(feature = "session")]
+pub use crate::session::{
+    decode_session, encode_session, RequestSession, SessionInfo, SessionMiddleware,
+    SessionMiddlewareBuilder, SessionOptions, SessionState, SessionTenant, SessionView,
+};
+#[cfg(feature = "session")]
+pub use crate::signer::Signer;
+#[cfg(feature = "session")]
+pub use crate::size_policy::SizePolicy;
+#[cfg(feature = "sql-session")]
+pub use crate::sql_store::{SqlConnection, SqlDialect, SqlParam, SqlStore};
+#[cfg(feature = "session")]
+pub use crate::store::{EncryptedStore, LruStore, MemoryStore, SessionStore, StoreStats};
+pub use crate::token::constant_time_eq;
+#[cfg(feature = "serde")]
+pub use crate::typed::{RequestTypedSession, TypedSession};
 
+#[cfg(feature = "session")]
+mod binding;
+#[cfg(feature = "session")]
+mod clock;
+#[cfg(feature = "session")]
+mod codec;
+#[cfg(feature = "session")]
+mod csrf;
+#[cfg(feature = "debug")]
+mod debug;
+mod defaults;
+mod duplicates;
+mod error;
+#[cfg(feature = "session")]
+mod flash;
+#[cfg(feature = "session")]
+mod format;
+#[cfg(feature = "session")]
+mod hooks;
+#[cfg(feature = "session")]
+mod import;
+mod jar;
+#[cfg(feature = "jwt")]
+mod jwt;
+#[cfg(feature = "session")]
+mod key_provider;
+#[cfg(feature = "session")]
+mod key_source;
+#[cfg(feature = "session")]
+mod kms;
+mod metrics;
+mod name;
+#[cfg(feature = "session")]
+mod nonce;
+mod preferences;
+#[cfg(feature = "rack")]
+mod rack;
+#[cfg(feature = "redis-session")]
+mod redis_store;
+#[cfg(feature = "session")]
+mod remember_me;
+mod response_cookie;
+mod scheme;
+#[cfg(feature = "session")]
 mod session;
+#[cfg(feature = "session")]
+mod signer;
+#[cfg(feature = "session")]
+mod size_policy;
+#[cfg(feature = "sql-session")]
+mod sql_store;
+#[cfg(feature = "session")]
+mod store;
+#[cfg(feature = "test-helpers")]
+pub mod test_helpers;
+mod token;
+#[cfg(feature = "serde")]
+mod typed;
 
+/// Parses the `Cookie` header into a jar, and writes its changes back as
+/// `Set-Cookie` headers.
+///
+/// conduit-middleware only has synchronous hooks, so there are no async
+/// variants of this or the other middleware. Neither does any I/O apart from
+/// calls into a `SessionStore` or `RememberMeStore`.
 #[derive(Default)]
-pub struct Middleware {}
+pub struct Middleware {
+    percent_encoding: bool,
+    defaults: CookieDefaults,
+    max_cookies: Option<usize>,
+    max_header_bytes: Option<usize>,
+    error_policy: ErrorPolicy,
+    metrics: Option<Box<dyn CookieMetrics>>,
+    name_validation: NameValidation,
+    duplicates: DuplicateCookies,
+    #[cfg(feature = "session")]
+    signing_key: Option<Key>,
+    canonicalize: bool,
+    quoting: bool,
+    min_same_site: Option<SameSite>,
+    same_site_none_allowed: HashSet<String>,
+    scopes: HashMap<String, CookieScope>,
+    bypass: Option<BypassFn>,
+    status_classes: Option<Vec<u16>>,
+    infrastructure_names: HashSet<String>,
+    infrastructure_defaults: CookieDefaults,
+    skip_unchanged: bool,
+}
+
+pub(crate) type BypassFn = Box<dyn Fn(&dyn RequestExt) -> bool + Send + Sync>;
+
+/// The Path and Domain a cookie is set with, see `Middleware::cookie_scope`.
+#[derive(Clone, Default)]
+struct CookieScope {
+    path: Option<String>,
+    domain: Option<String>,
+}
 
 impl Middleware {
     pub fn new() -> Self {
         Default::default()
     }
-}
 
-fn parse_pair(key_value: &str) -> Option<(String, String)> {
-    key_value.find('=').map(|i| {
-        (
-            key_value[..i].trim().into(),
-            key_value[(i + 1)..].trim().into(),
-        )
-    })
+    pub fn with_defaults(defaults: CookieDefaults) -> Self {
+        Middleware {
+            defaults,
+            ..Default::default()
+        }
+    }
+
+    /// Percent-decode incoming cookie names and values, and percent-encode
+    /// outgoing ones.
+    pub fn percent_encoding(mut self, enabled: bool) -> Self {
+        self.percent_encoding = enabled;
+        self
+    }
+
+    /// Ignores request cookies beyond the first `max_cookies`.
+    pub fn max_cookies(mut self, max_cookies: usize) -> Self {
+        self.max_cookies = Some(max_cookies);
+        self
+    }
+
+    /// Ignores Cookie headers once their combined length exceeds
+    /// `max_header_bytes`.
+    pub fn max_header_bytes(mut self, max_header_bytes: usize) -> Self {
+        self.max_header_bytes = Some(max_header_bytes);
+        self
+    }
+
+    /// How to handle response cookies that aren't valid header values, or
+    /// that have `SameSite=None` without `Secure`. They are dropped by
+    /// default.
+    pub fn error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Checks response cookie names unless percent-encoding is enabled, which
+    /// always produces valid names.
+    pub fn name_validation(mut self, name_validation: NameValidation) -> Self {
+        self.name_validation = name_validation;
+        self
+    }
+
+    /// How to resolve request cookies with the same name.
+    pub fn duplicates(mut self, duplicates: DuplicateCookies) -> Self {
+        self.duplicates = duplicates;
+        self
+    }
+
+    /// Strips the double quotes around quoted request cookie values, and
+    /// quotes response cookie values containing spaces or commas. Has no
+    /// effect on responses with percent-encoding enabled.
+    pub fn quoting(mut self, quoting: bool) -> Self {
+        self.quoting = quoting;
+        self
+    }
+
+    /// Raises the SameSite attribute of response cookies that don't set it or
+    /// set a laxer one to `min_same_site`, e.g. to catch cookies that are
+    /// accidentally sent with cross-site requests.
+    pub fn min_same_site(mut self, min_same_site: SameSite) -> Self {
+        self.min_same_site = Some(min_same_site);
+        self
+    }
+
+    /// Exempts the cookie `name` from `min_same_site` if it uses
+    /// `SameSite=None`.
+    pub fn allow_same_site_none(mut self, name: &str) -> Self {
+        self.same_site_none_allowed.insert(name.to_string());
+        self
+    }
+
+    /// Registers the Path and Domain of the cookie `name`. They are filled in
+    /// when the cookie is set or removed without them, so that removals
+    /// match the cookie that browsers stored and take effect.
+    pub fn cookie_scope(mut self, name: &str, path: Option<&str>, domain: Option<&str>) -> Self {
+        let scope = CookieScope {
+            path: path.map(str::to_string),
+            domain: domain.map(str::to_string),
+        };
+        self.scopes.insert(name.to_string(), scope);
+        self
+    }
+
+    /// Merges `Set-Cookie` headers for the same cookie name, keeping the
+    /// last, and sorts them by name. This includes headers set by the
+    /// handler directly.
+    pub fn canonicalize(mut self, canonicalize: bool) -> Self {
+        self.canonicalize = canonicalize;
+        self
+    }
+
+    /// Skips parsing and emitting cookies for requests matching `bypass`,
+    /// e.g. for static assets or health checks. Such requests see an empty
+    /// jar, and changes to it are discarded.
+    pub fn bypass<F>(mut self, bypass: F) -> Self
+    where
+        F: Fn(&dyn RequestExt) -> bool + Send + Sync + 'static,
+    {
+        self.bypass = Some(Box::new(bypass));
+        self
+    }
+
+    /// Only sends cookie changes with responses whose status is in one of
+    /// `classes`, e.g. `&[2, 3]` for 2xx and 3xx responses, so that failed
+    /// requests don't change the client's cookies.
+    pub fn status_classes(mut self, classes: &[u16]) -> Self {
+        self.status_classes = Some(classes.to_vec());
+        self
+    }
+
+    /// Reserves the cookies `names` for framework code, e.g. A/B test
+    /// buckets. They are kept in a separate jar, available via
+    /// `req.infrastructure_cookies()`, and sent with `defaults` instead of
+    /// the app's defaults. Changes to these cookies in the app jar are
+    /// dropped, so that handlers can't clobber them.
+    pub fn infrastructure_cookies(mut self, names: &[&str], defaults: CookieDefaults) -> Self {
+        self.infrastructure_names = names.iter().map(|name| name.to_string()).collect();
+        self.infrastructure_defaults = defaults;
+        self
+    }
+
+    /// Skips `Set-Cookie` headers for cookies that are set to the value the
+    /// client sent, reducing response size and keeping responses cacheable.
+    /// Clients don't send attributes, so only cookies without attributes
+    /// other than the configured defaults are skipped. Others may be another
+    /// cookie, e.g. with another path, or upgrade its attributes, and cookies
+    /// with Max-Age or Expires extend its lifetime.
+    pub fn skip_unchanged(mut self, skip_unchanged: bool) -> Self {
+        self.skip_unchanged = skip_unchanged;
+        self
+    }
+
+    /// Signs and verifies the cookies of `set_signed` and `get_signed` with
+    /// `key`.
+    #[cfg(feature = "session")]
+    pub fn signing_key(mut self, key: Key) -> Self {
+        self.signing_key = Some(key);
+        self
+    }
+
+    /// Reports request cookies that exceed the configured limits to
+    /// `metrics`.
+    pub fn metrics<M: CookieMetrics>(mut self, metrics: M) -> Self {
+        self.metrics = Some(Box::new(metrics));
+        self
+    }
+
+    /// The `Cookie` headers to parse, in order. HTTP/2 clients may split the
+    /// cookies across several headers.
+    fn cookie_headers(&self, headers: &HeaderMap) -> Vec<HeaderValue> {
+        let max_header_bytes = self.max_header_bytes.unwrap_or(usize::MAX);
+        let mut header_bytes = 0;
+        let mut cookie_headers = Vec::new();
+        for cookie in headers.get_all(header::COOKIE).iter() {
+            header_bytes += cookie.len();
+            if header_bytes > max_header_bytes {
+                event!(WARN, max_header_bytes, "ignoring oversized Cookie headers");
+                if let Some(metrics) = &self.metrics {
+                    metrics.oversized_cookie();
+                }
+                break;
+            }
+            cookie_headers.push(cookie.clone());
+        }
+        cookie_headers
+    }
+
+    fn parse_options(&self) -> ParseOptions {
+        ParseOptions {
+            max_cookies: self.max_cookies,
+            duplicates: self.duplicates,
+            quoting: self.quoting,
+            percent_encoding: self.percent_encoding,
+        }
+    }
+
+    /// Parses all `Cookie` headers right away.
+    fn parse_headers(&self, headers: &HeaderMap) -> (CookieJar, AllCookies) {
+        let parsed = self.parse_options().parse(&self.cookie_headers(headers));
+        (parsed.jar, parsed.all)
+    }
+
+    /// Fills in the attributes `cookie` doesn't set from its scope, override
+    /// or `defaults`.
+    fn apply_defaults(
+        &self,
+        cookie: &mut Cookie<'_>,
+        defaults: &CookieDefaults,
+        overrides: Option<&CookieOverrides>,
+    ) {
+        if let Some(scope) = self.scopes.get(cookie.name()) {
+            if let (None, Some(path)) = (cookie.path(), &scope.path) {
+                cookie.set_path(path.clone());
+            }
+            if let (None, Some(domain)) = (cookie.domain(), &scope.domain) {
+                cookie.set_domain(domain.clone());
+            }
+        }
+        match overrides.and_then(|overrides| overrides.0.get(cookie.name())) {
+            Some(cookie_override) => cookie_override.apply(defaults, cookie),
+            None => defaults.apply(cookie),
+        }
+    }
+
+    /// Whether `delta` sets a cookie to the value it was sent with, without
+    /// extending its lifetime. Since request cookies carry no attributes,
+    /// `delta` must not set any other than the configured ones, or it may
+    /// be another cookie, e.g. with another path, or upgrade the attributes.
+    fn is_unchanged(
+        &self,
+        delta: &Cookie<'_>,
+        original: &[Cookie<'static>],
+        defaults: &CookieDefaults,
+        overrides: Option<&CookieOverrides>,
+    ) -> bool {
+        let expiring = delta.max_age().is_some() || delta.expires().is_some();
+        let sent = original
+            .iter()
+            .any(|cookie| cookie.name() == delta.name() && cookie.value() == delta.value());
+        if expiring || !sent {
+            return false;
+        }
+        let mut delta = delta.clone();
+        self.apply_defaults(&mut delta, defaults, overrides);
+        let mut configured = Cookie::new(delta.name().to_string(), delta.value().to_string());
+        self.apply_defaults(&mut configured, defaults, overrides);
+        delta.to_string() == configured.to_string()
+    }
+
+    /// Appends a `Set-Cookie` header to `headers` for each change in `jar`
+    /// that isn't skipped by `skip`.
+    fn write_delta(
+        &self,
+        jar: &CookieJar,
+        defaults: &CookieDefaults,
+        skip: &dyn Fn(&Cookie<'_>) -> bool,
+        overrides: Option<&CookieOverrides>,
+        partitioned: Option<&PartitionedCookies>,
+        headers: &mut HeaderMap,
+    ) -> Result<(), BoxError> {
+        use std::convert::TryInto;
+
+        for delta in jar.delta() {
+            if skip(delta) {
+                continue;
+            }
+            let mut delta = delta.clone();
+            self.apply_defaults(&mut delta, defaults, overrides);
+            if !self.percent_encoding && !name::is_valid(delta.name()) {
+                match self.name_validation {
+                    NameValidation::Off => {}
+                    NameValidation::Reject => {
+                        let name = delta.name().to_string();
+                        self.error_policy.handle(Error::InvalidName { name })?;
+                        continue;
+                    }
+                    NameValidation::Sanitize => delta.set_name(name::sanitize(delta.name())),
+                }
+            }
+            if let Some(min_same_site) = self.min_same_site {
+                let allowed = delta.same_site() == Some(SameSite::None)
+                    && self.same_site_none_allowed.contains(delta.name());
+                let rank = |same_site| match same_site {
+                    Some(SameSite::Strict) => 2,
+                    Some(SameSite::Lax) => 1,
+                    _ => 0,
+                };
+                if !allowed && rank(delta.same_site()) < rank(Some(min_same_site)) {
+                    event!(DEBUG, name = delta.name(), "raising SameSite attribute");
+                    delta.set_same_site(min_same_site);
+                }
+            }
+            if delta.same_site() == Some(SameSite::None) && delta.secure() != Some(true) {
+                let name = delta.name().to_string();
+                self.error_policy
+                    .handle(Error::InsecureSameSiteNone { name })?;
+                continue;
+            }
+            let partitioned = defaults.partitioned
+                || partitioned.is_some_and(|names| names.0.contains(delta.name()));
+            if partitioned && delta.secure() != Some(true) {
+                let name = delta.name().to_string();
+                self.error_policy
+                    .handle(Error::InsecurePartitioned { name })?;
+                continue;
+            }
+            let needs_quotes = delta.value().contains([' ', ',']);
+            if self.quoting && !self.percent_encoding && needs_quotes {
+                delta.set_value(format!("\"{}\"", delta.value()));
+            }
+            let mut value = if self.percent_encoding {
+                delta.encoded().to_string()
+            } else {
+                delta.to_string()
+            };
+            if partitioned {
+                // Not supported by the `cookie` crate yet
+                value.push_str("; Partitioned");
+            }
+            match value.try_into() {
+                Ok(value) => {
+                    event!(DEBUG, name = delta.name(), "emitting Set-Cookie");
+                    headers.append(header::SET_COOKIE, value);
+                }
+                Err(_) => {
+                    let name = delta.name().to_string();
+                    self.error_policy.handle(Error::InvalidHeader { name })?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 impl conduit_middleware::Middleware for Middleware {
     fn before(&self, req: &mut dyn RequestExt) -> BeforeResult {
-        let jar = {
-            let headers = req.headers();
+        // Keep the jar of the outer pass when the request is re-dispatched
+        // to another app, so that its changes aren't lost
+        if let Some(nested) = req.mut_extensions().get_mut::<NestedDispatches>() {
+            nested.0 += 1;
+            return Ok(());
+        }
+        // Also on bypassed routes, where `get_signed` and `set_signed` see
+        // the empty jar
+        #[cfg(feature = "session")]
+        if let Some(key) = &self.signing_key {
+            req.mut_extensions().insert(SigningKey(key.clone()));
+        }
+        if matches!(&self.bypass, Some(bypass) if bypass(req)) {
+            req.mut_extensions()
+                .insert(LazyJar::from_parsed(Parsed::default()));
+            req.mut_extensions()
+                .insert(InfrastructureJar(CookieJar::new(), Vec::new()));
+            req.mut_extensions().insert(NestedDispatches(0));
+            req.mut_extensions().insert(Bypassed);
+            return Ok(());
+        }
+        let headers = self.cookie_headers(req.headers());
+        let mut infrastructure = CookieJar::new();
+        let jar = if self.infrastructure_names.is_empty() {
+            LazyJar::new(headers, self.parse_options())
+        } else {
+            // Infrastructure cookies are split off right away
+            let parsed = self.parse_options().parse(&headers);
             let mut jar = CookieJar::new();
-            for cookie in headers.get_all(header::COOKIE).iter() {
-                if let Ok(cookie) = cookie.to_str() {
-                    for cookie in cookie.split(';') {
-                        if let Some((key, value)) = parse_pair(cookie) {
-                            jar.add_original(Cookie::new(key, value));
-                        }
-                    }
+            for cookie in parsed.jar.iter() {
+                match self.infrastructure_names.contains(cookie.name()) {
+                    true => infrastructure.add_original(cookie.clone()),
+                    false => jar.add_original(cookie.clone()),
                 }
             }
-            jar
+            let original = jar.iter().cloned().collect();
+            let all = parsed.all;
+            LazyJar::from_parsed(Parsed { jar, original, all })
         };
+        let infrastructure_original = infrastructure.iter().cloned().collect();
         req.mut_extensions().insert(jar);
+        req.mut_extensions()
+            .insert(InfrastructureJar(infrastructure, infrastructure_original));
+        req.mut_extensions().insert(NestedDispatches(0));
         Ok(())
     }
 
     fn after(&self, req: &mut dyn RequestExt, res: AfterResult) -> AfterResult {
-        use std::convert::TryInto;
+        // The outermost pass emits the changes of all passes
+        match req.mut_extensions().get_mut::<NestedDispatches>() {
+            Some(nested) if nested.0 > 0 => {
+                nested.0 -= 1;
+                return res;
+            }
+            _ => {
+                req.mut_extensions().remove::<NestedDispatches>();
+            }
+        }
+        if req.mut_extensions().remove::<Bypassed>().is_some() {
+            return res;
+        }
 
         let mut res = res?;
-
-        for delta in req.cookies().delta() {
-            if let Ok(value) = delta.to_string().try_into() {
-                res.headers_mut().append(header::SET_COOKIE, value);
+        if let Some(classes) = &self.status_classes {
+            if !classes.contains(&(res.status().as_u16() / 100)) {
+                return Ok(res);
             }
         }
 
+        let overrides = req.extensions().get::<CookieOverrides>();
+        let partitioned = req.extensions().get::<PartitionedCookies>();
+        let headers = res.headers_mut();
+        // Cookies that were never accessed can't have changed
+        let jar = req.extensions().get::<LazyJar>().unwrap();
+        if jar.is_parsed() {
+            let Parsed { jar, original, .. } = jar.get();
+            let skip = |delta: &Cookie<'_>| {
+                if self.infrastructure_names.contains(delta.name()) {
+                    event!(
+                        WARN,
+                        name = delta.name(),
+                        "dropping change to infrastructure cookie"
+                    );
+                    return true;
+                }
+                self.skip_unchanged && self.is_unchanged(delta, original, &self.defaults, overrides)
+            };
+            self.write_delta(jar, &self.defaults, &skip, overrides, partitioned, headers)?;
+        }
+        let infrastructure = req.extensions().get::<InfrastructureJar>().unwrap();
+        let defaults = &self.infrastructure_defaults;
+        let skip = |delta: &Cookie<'_>| {
+            self.skip_unchanged && self.is_unchanged(delta, &infrastructure.1, defaults, None)
+        };
+        self.write_delta(&infrastructure.0, defaults, &skip, None, None, headers)?;
+
+        if self.canonicalize {
+            canonicalize(res.headers_mut());
+        }
         Ok(res)
     }
 }
 
+/// Parses the `Cookie` headers of a request exactly like a default
+/// `Middleware`, for code that doesn't go through conduit.
+pub fn parse_cookie_header(headers: &HeaderMap) -> CookieJar {
+    Middleware::new().parse_headers(headers).0
+}
+
+/// Appends a `Set-Cookie` header to `headers` for each change in `jar`,
+/// exactly like a default `Middleware`.
+pub fn apply_delta(jar: &CookieJar, headers: &mut HeaderMap) -> Result<(), BoxError> {
+    let middleware = Middleware::new();
+    middleware.write_delta(jar, &middleware.defaults, &|_| false, None, None, headers)
+}
+
+fn canonicalize(headers: &mut HeaderMap) {
+    let mut by_name = BTreeMap::new();
+    for value in headers.get_all(header::SET_COOKIE) {
+        let bytes = value.as_bytes();
+        let name = bytes.split(|&b| b == b'=').next().unwrap_or(bytes);
+        let name = String::from_utf8_lossy(name).trim().to_string();
+        by_name.insert(name, value.clone());
+    }
+    headers.remove(header::SET_COOKIE);
+    for value in by_name.into_values() {
+        headers.append(header::SET_COOKIE, value);
+    }
+}
+
 pub trait RequestCookies {
     fn cookies(&self) -> &CookieJar;
     fn cookies_mut(&mut self) -> &mut CookieJar;
+
+    /// Like `cookies`, but returns `None` if `Middleware` isn't installed.
+    fn try_cookies(&self) -> Option<&CookieJar>;
+    fn try_cookies_mut(&mut self) -> Option<&mut CookieJar>;
+
+    /// The jar of the cookies reserved with
+    /// `Middleware::infrastructure_cookies`, for framework code.
+    fn infrastructure_cookies(&self) -> &CookieJar;
+    fn infrastructure_cookies_mut(&mut self) -> &mut CookieJar;
+
+    /// A view of the cookies that verifies and signs them with `key`.
+    #[cfg(feature = "session")]
+    fn signed_cookies(&self, key: &Key) -> SignedJar<&CookieJar> {
+        self.cookies().signed(key)
+    }
+
+    #[cfg(feature = "session")]
+    fn signed_cookies_mut(&mut self, key: &Key) -> SignedJar<&mut CookieJar> {
+        self.cookies_mut().signed_mut(key)
+    }
+
+    /// A view of the cookies that decrypts and encrypts them with `key`.
+    #[cfg(feature = "session")]
+    fn private_cookies(&self, key: &Key) -> PrivateJar<&CookieJar> {
+        self.cookies().private(key)
+    }
+
+    #[cfg(feature = "session")]
+    fn private_cookies_mut(&mut self, key: &Key) -> PrivateJar<&mut CookieJar> {
+        self.cookies_mut().private_mut(key)
+    }
+
+    /// Starts building a cookie to send with the response.
+    fn response_cookie(&mut self, name: &str, value: &str) -> ResponseCookieBuilder<'_> {
+        ResponseCookieBuilder::new(self.cookies_mut(), name, value)
+    }
+
+    /// The cookies received with the request, even if they were since
+    /// changed or removed in the jar.
+    fn original_cookies(&self) -> Vec<&Cookie<'static>>;
+
+    /// The cookies that will be set or removed with the response.
+    fn pending_cookies(&self) -> Vec<&Cookie<'static>> {
+        self.cookies().delta().collect()
+    }
+
+    /// All request cookies named `name`, in the order they were sent. Without
+    /// `DuplicateCookies::CollectAll` this only includes the one in the jar.
+    fn all_cookies(&self, name: &str) -> Vec<&Cookie<'static>>;
+
+    /// Sends the response cookie `name` with the `Partitioned` attribute,
+    /// storing it separately per top-level site (CHIPS).
+    fn partition_cookie(&mut self, name: &str);
+
+    /// Applies `cookie_override` instead of the plain `CookieDefaults` to
+    /// the response cookie `name`.
+    fn override_cookie(&mut self, name: &str, cookie_override: CookieOverride);
+
+    /// Removes the cookie `name` from the client. Browsers only remove it if
+    /// `path` and `domain` match the ones it was set with; if they are
+    /// `None` they are taken from `Middleware::cookie_scope` or the
+    /// `CookieDefaults`.
+    fn remove_cookie_matching(&mut self, name: &str, path: Option<&str>, domain: Option<&str>) {
+        let mut cookie = Cookie::named(name.to_string());
+        if let Some(path) = path {
+            cookie.set_path(path.to_string());
+        }
+        if let Some(domain) = domain {
+            cookie.set_domain(domain.to_string());
+        }
+        self.cookies_mut().remove(cookie);
+    }
+
+    /// Adds `cookies` to the jar, e.g. the pending cookies of a request that
+    /// was re-dispatched as a separate request, so that they are sent with
+    /// this response.
+    fn merge_cookies<I: IntoIterator<Item = Cookie<'static>>>(&mut self, cookies: I) {
+        let jar = self.cookies_mut();
+        for cookie in cookies {
+            jar.add(cookie);
+        }
+    }
+
+    /// Whether the cookie `name` is present and holds `expected`, compared
+    /// in constant time.
+    fn cookie_matches(&self, name: &str, expected: &str) -> bool {
+        let cookie = self.cookies().get(name);
+        cookie.is_some_and(|cookie| constant_time_eq(cookie.value(), expected))
+    }
+
+    /// The value of the cookie `name` if it was signed with the key passed
+    /// to `Middleware::signing_key`.
+    #[cfg(feature = "session")]
+    fn get_signed(&self, name: &str) -> Option<String>;
+
+    /// Sends `value` in the cookie `name`, signed with the key passed to
+    /// `Middleware::signing_key`. Unlike a session value this doesn't
+    /// rewrite the session cookie.
+    #[cfg(feature = "session")]
+    fn set_signed(&mut self, name: &str, value: &str);
 }
 
 impl<T: RequestExt + ?Sized> RequestCookies for T {
     fn cookies(&self) -> &CookieJar {
-        self.extensions()
-            .get::<CookieJar>()
-            .expect("Missing cookie jar")
+        self.try_cookies().expect("Missing cookie jar")
     }
 
     fn cookies_mut(&mut self) -> &mut CookieJar {
-        self.mut_extensions()
-            .get_mut::<CookieJar>()
-            .expect("Missing cookie jar")
+        self.try_cookies_mut().expect("Missing cookie jar")
+    }
+
+    fn try_cookies(&self) -> Option<&CookieJar> {
+        let jar = self.extensions().get::<LazyJar>()?;
+        Some(&jar.get().jar)
+    }
+
+    fn try_cookies_mut(&mut self) -> Option<&mut CookieJar> {
+        let jar = self.mut_extensions().get_mut::<LazyJar>()?;
+        Some(&mut jar.get_mut().jar)
+    }
+
+    fn infrastructure_cookies(&self) -> &CookieJar {
+        let jar = self.extensions().get::<InfrastructureJar>();
+        &jar.expect("Missing cookie jar").0
+    }
+
+    fn infrastructure_cookies_mut(&mut self) -> &mut CookieJar {
+        let jar = self.mut_extensions().get_mut::<InfrastructureJar>();
+        &mut jar.expect("Missing cookie jar").0
+    }
+
+    fn original_cookies(&self) -> Vec<&Cookie<'static>> {
+        let jar = self.extensions().get::<LazyJar>();
+        jar.expect("Missing cookie jar")
+            .get()
+            .original
+            .iter()
+            .collect()
+    }
+
+    fn all_cookies(&self, name: &str) -> Vec<&Cookie<'static>> {
+        let jar = self.extensions().get::<LazyJar>();
+        match jar.and_then(|jar| jar.get().all.0.get(name)) {
+            Some(all) => all.iter().collect(),
+            None => self.cookies().get(name).into_iter().collect(),
+        }
+    }
+
+    fn partition_cookie(&mut self, name: &str) {
+        let extensions = self.mut_extensions();
+        match extensions.get_mut::<PartitionedCookies>() {
+            Some(partitioned) => {
+                partitioned.0.insert(name.to_string());
+            }
+            None => {
+                let names = std::iter::once(name.to_string()).collect();
+                extensions.insert(PartitionedCookies(names));
+            }
+        }
+    }
+
+    fn override_cookie(&mut self, name: &str, cookie_override: CookieOverride) {
+        let extensions = self.mut_extensions();
+        if extensions.get::<CookieOverrides>().is_none() {
+            extensions.insert(CookieOverrides(HashMap::new()));
+        }
+        let overrides = extensions.get_mut::<CookieOverrides>().unwrap();
+        overrides.0.insert(name.to_string(), cookie_override);
+    }
+
+    #[cfg(feature = "session")]
+    fn get_signed(&self, name: &str) -> Option<String> {
+        let key = self.extensions().get::<SigningKey>();
+        let key = &key.expect("Missing signing key").0;
+        let cookie = self.cookies().signed(key).get(name)?;
+        Some(cookie.value().to_string())
+    }
+
+    #[cfg(feature = "session")]
+    fn set_signed(&mut self, name: &str, value: &str) {
+        let key = self.extensions().get::<SigningKey>();
+        let key = key.expect("Missing signing key").0.clone();
+        let cookie = Cookie::new(name.to_string(), value.to_string());
+        self.cookies_mut().signed_mut(&key).add(cookie);
     }
 }
 
+/// How many passes of `Middleware` over a re-dispatched request are running
+/// inside the outermost one. Only present while the outermost one runs.
+struct NestedDispatches(usize);
+
+/// Overrides registered with `override_cookie`, by cookie name.
+struct CookieOverrides(HashMap<String, CookieOverride>);
+
+/// The jar of `Middleware::infrastructure_cookies`, and the cookies it was
+/// created with.
+struct InfrastructureJar(CookieJar, Vec<Cookie<'static>>);
+
+/// Marks requests skipped by `Middleware::bypass`.
+struct Bypassed;
+
+/// The key of `Middleware::signing_key`.
+#[cfg(feature = "session")]
+struct SigningKey(Key);
+
+/// Names of the response cookies marked with `partition_cookie`.
+struct PartitionedCookies(HashSet<String>);
+
 #[cfg(test)]
 mod tests {
-    use conduit::{header, Body, Handler, HttpResult, Method, RequestExt, Response};
+    use std::sync::{Arc, Mutex};
+
+    use conduit::header::{self, HeaderValue};
+    use conduit::{Body, Handler, HeaderMap, HttpResult, Method, RequestExt, Response};
     use conduit_middleware::MiddlewareBuilder;
     use conduit_test::MockRequest;
-    use cookie::Cookie;
+    #[cfg(feature = "session")]
+    use cookie::Key;
+    use cookie::{time::Duration, Cookie, SameSite};
 
-    use super::{Middleware, RequestCookies};
+    use super::{
+        CookieDefaults, CookieOverride, DuplicateCookies, ErrorPolicy, LazyJar, Middleware,
+        NameValidation, RequestCookies,
+    };
 
     #[test]
     fn request_headers() {
@@ -147,4 +916,807 @@ mod tests {
             Response::builder().body(Body::empty())
         }
     }
+
+    #[test]
+    fn default_attributes() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(Middleware::with_defaults(
+            CookieDefaults::new().secure(true).http_only(true).path("/"),
+        ));
+        let response = app.call(&mut req).unwrap();
+        let mut v = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .collect::<Vec<_>>();
+        v.sort();
+        assert_eq!(
+            &v[..],
+            [
+                "baz=qux; Secure; Path=/",
+                "foo=bar; HttpOnly; Secure; Path=/",
+                "locale=en; Secure; Path=/"
+            ]
+        );
+
+        fn test(req: &mut dyn RequestExt) -> HttpResult {
+            req.cookies_mut().add(Cookie::new("foo", "bar"));
+            let c = Cookie::build("baz", "qux").http_only(false).finish();
+            req.cookies_mut().add(c);
+            req.cookies_mut().add(Cookie::new("locale", "en"));
+            req.override_cookie("locale", CookieOverride::new().http_only(false));
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn same_site_none_requires_secure() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(Middleware::new());
+        let response = app.call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let log = errors.clone();
+        let policy = ErrorPolicy::log(move |e| log.lock().unwrap().push(e.to_string()));
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(Middleware::new().error_policy(policy));
+        let response = app.call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+        assert_eq!(
+            *errors.lock().unwrap(),
+            ["cookie `foo` has SameSite=None but is not Secure"]
+        );
+
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(Middleware::new().error_policy(ErrorPolicy::Fail));
+        assert!(app.call(&mut req).is_err());
+
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(Middleware::with_defaults(
+            CookieDefaults::new().secure(true),
+        ));
+        let response = app.call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_some());
+
+        fn test(req: &mut dyn RequestExt) -> HttpResult {
+            let c = Cookie::build("foo", "bar")
+                .same_site(SameSite::None)
+                .finish();
+            req.cookies_mut().add(c);
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn min_same_site() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(
+            Middleware::new()
+                .min_same_site(SameSite::Lax)
+                .allow_same_site_none("embed"),
+        );
+        let response = app.call(&mut req).unwrap();
+        let mut v = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .collect::<Vec<_>>();
+        v.sort();
+        assert_eq!(
+            &v[..],
+            [
+                "embed=1; SameSite=None; Secure",
+                "lax=2; SameSite=Lax",
+                "none=3; SameSite=Lax; Secure",
+                "strict=4; SameSite=Strict",
+            ]
+        );
+
+        fn test(req: &mut dyn RequestExt) -> HttpResult {
+            let jar = req.cookies_mut();
+            let none = |name, value| {
+                Cookie::build(name, value)
+                    .same_site(SameSite::None)
+                    .secure(true)
+                    .finish()
+            };
+            jar.add(none("embed", "1"));
+            jar.add(Cookie::new("lax", "2"));
+            jar.add(none("none", "3"));
+            let strict = Cookie::build("strict", "4").same_site(SameSite::Strict);
+            jar.add(strict.finish());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn remove_cookie_matching() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        req.header(header::COOKIE, "scoped=1; explicit=2; default=3");
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(
+            Middleware::with_defaults(CookieDefaults::new().path("/")).cookie_scope(
+                "scoped",
+                Some("/admin"),
+                Some("example.com"),
+            ),
+        );
+        let response = app.call(&mut req).unwrap();
+        let mut v = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|v| {
+                let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+                let path = cookie.path().map(str::to_string);
+                let domain = cookie.domain().map(str::to_string);
+                (cookie.name().to_string(), path, domain)
+            })
+            .collect::<Vec<_>>();
+        v.sort();
+        let scope = |name: &str, path: &str, domain: Option<&str>| {
+            (
+                name.to_string(),
+                Some(path.to_string()),
+                domain.map(str::to_string),
+            )
+        };
+        assert_eq!(
+            v,
+            [
+                scope("default", "/", None),
+                scope("explicit", "/x", Some("x.example.com")),
+                scope("scoped", "/admin", Some("example.com")),
+            ]
+        );
+
+        fn test(req: &mut dyn RequestExt) -> HttpResult {
+            req.remove_cookie_matching("scoped", None, None);
+            req.remove_cookie_matching("explicit", Some("/x"), Some("x.example.com"));
+            req.remove_cookie_matching("default", None, None);
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "session")]
+    fn signed_and_private() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        let mut app = MiddlewareBuilder::new(set);
+        app.add(Middleware::new());
+        let response = app.call(&mut req).unwrap();
+        let v = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert!(v
+            .iter()
+            .any(|v| v.starts_with("secret=") && !v.contains("dark")));
+        req.header(header::COOKIE, &v.join("; "));
+
+        let mut app = MiddlewareBuilder::new(get);
+        app.add(Middleware::new());
+        assert!(app.call(&mut req).is_ok());
+
+        fn key() -> Key {
+            Key::derive_from(&[7; 32])
+        }
+        fn set(req: &mut dyn RequestExt) -> HttpResult {
+            let key = key();
+            req.signed_cookies_mut(&key)
+                .add(Cookie::new("theme", "dark"));
+            req.private_cookies_mut(&key)
+                .add(Cookie::new("secret", "dark"));
+            Response::builder().body(Body::empty())
+        }
+        fn get(req: &mut dyn RequestExt) -> HttpResult {
+            let key = key();
+            let theme = req.signed_cookies(&key).get("theme").unwrap();
+            assert_eq!(theme.value(), "dark");
+            let secret = req.private_cookies(&key).get("secret").unwrap();
+            assert_eq!(secret.value(), "dark");
+            assert!(req.private_cookies(&key).get("theme").is_none());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn response_cookie() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(Middleware::with_defaults(
+            CookieDefaults::new().secure(true).http_only(true),
+        ));
+        let response = app.call(&mut req).unwrap();
+        let v = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .collect::<Vec<_>>();
+        assert_eq!(&v[..], ["locale=en; Secure; Path=/; Max-Age=60"]);
+
+        fn test(req: &mut dyn RequestExt) -> HttpResult {
+            req.response_cookie("locale", "en")
+                .http_only(false)
+                .path("/")
+                .max_age(Duration::minutes(1))
+                .add();
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn limits() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        req.header(header::COOKIE, "a=1; b=2; c=3");
+
+        let mut app = MiddlewareBuilder::new(two_cookies);
+        app.add(Middleware::new().max_cookies(2));
+        assert!(app.call(&mut req).is_ok());
+
+        let mut app = MiddlewareBuilder::new(no_cookies);
+        app.add(Middleware::new().max_header_bytes(12));
+        assert!(app.call(&mut req).is_ok());
+
+        fn two_cookies(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.cookies().iter().count(), 2);
+            assert!(req.cookies().get("c").is_none());
+            Response::builder().body(Body::empty())
+        }
+        fn no_cookies(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.cookies().iter().count(), 0);
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn missing_middleware() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        assert!(req.try_cookies().is_none());
+        assert!(req.try_cookies_mut().is_none());
+    }
+
+    #[test]
+    fn percent_encoding() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        req.header(header::COOKIE, "foo=a%3Db%20c; bar=%FF");
+
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(Middleware::new().percent_encoding(true));
+        let response = app.call(&mut req).unwrap();
+        let v = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .collect::<Vec<_>>();
+        assert_eq!(&v[..], ["baz=x%3By"]);
+
+        fn test(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.cookies().get("foo").unwrap().value(), "a=b c");
+            assert_eq!(req.cookies().get("bar").unwrap().value(), "\u{fffd}");
+            req.cookies_mut().add(Cookie::new("baz", "x;y"));
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn raw_values_by_default() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        req.header(header::COOKIE, "foo=a%3Db");
+
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(Middleware::new());
+        assert!(app.call(&mut req).is_ok());
+
+        fn test(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.cookies().get("foo").unwrap().value(), "a%3Db");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn invalid_header_value() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(Middleware::new());
+        let response = app.call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(Middleware::new().error_policy(ErrorPolicy::Fail));
+        assert!(app.call(&mut req).is_err());
+
+        fn test(req: &mut dyn RequestExt) -> HttpResult {
+            req.cookies_mut().add(Cookie::new("foo", "a\nb"));
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn name_validation() {
+        let cookies = |validation| {
+            let mut req = MockRequest::new(Method::POST, "/articles");
+            let mut app = MiddlewareBuilder::new(test);
+            app.add(Middleware::new().name_validation(validation));
+            let response = app.call(&mut req).unwrap();
+            let cookies = response.headers().get_all(header::SET_COOKIE);
+            let cookies = cookies.iter().map(|v| v.to_str().unwrap().to_string());
+            cookies.collect::<Vec<_>>()
+        };
+
+        assert_eq!(cookies(NameValidation::Off), ["a b=1"]);
+        assert!(cookies(NameValidation::Reject).is_empty());
+        assert_eq!(cookies(NameValidation::Sanitize), ["a_b=1"]);
+
+        fn test(req: &mut dyn RequestExt) -> HttpResult {
+            req.cookies_mut().add(Cookie::new("a b", "1"));
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn partitioned() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(Middleware::new());
+        let response = app.call(&mut req).unwrap();
+        let mut v = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .collect::<Vec<_>>();
+        v.sort();
+        assert_eq!(&v[..], ["bar=2", "foo=1; Secure; Partitioned"]);
+
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(Middleware::with_defaults(
+            CookieDefaults::new().secure(true).partitioned(true),
+        ));
+        let response = app.call(&mut req).unwrap();
+        let v = response.headers().get_all(header::SET_COOKIE);
+        assert_eq!(v.iter().count(), 2);
+        assert!(v
+            .iter()
+            .all(|v| v.to_str().unwrap().ends_with("; Partitioned")));
+
+        // Browsers reject partitioned cookies that aren't Secure
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let log = errors.clone();
+        let policy = ErrorPolicy::log(move |e| log.lock().unwrap().push(e.to_string()));
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(
+            Middleware::with_defaults(CookieDefaults::new().partitioned(true)).error_policy(policy),
+        );
+        let response = app.call(&mut req).unwrap();
+        let v = response.headers().get_all(header::SET_COOKIE);
+        assert_eq!(v.iter().collect::<Vec<_>>(), ["foo=1; Secure; Partitioned"]);
+        assert_eq!(
+            *errors.lock().unwrap(),
+            ["cookie `bar` is Partitioned but not Secure"]
+        );
+
+        fn test(req: &mut dyn RequestExt) -> HttpResult {
+            let foo = Cookie::build("foo", "1").secure(true).finish();
+            req.cookies_mut().add(foo);
+            req.cookies_mut().add(Cookie::new("bar", "2"));
+            req.partition_cookie("foo");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn duplicates() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        req.header(header::COOKIE, "foo=1; bar=2; foo=3");
+
+        let mut value = |duplicates| {
+            let mut app = MiddlewareBuilder::new(test);
+            app.add(Middleware::new().duplicates(duplicates));
+            let response = app.call(&mut req).unwrap();
+            let value = response.headers().get("x-foo").unwrap();
+            value.to_str().unwrap().to_string()
+        };
+
+        assert_eq!(value(DuplicateCookies::LastWins), "3");
+        assert_eq!(value(DuplicateCookies::FirstWins), "1");
+        assert_eq!(value(DuplicateCookies::CollectAll), "1,3");
+
+        fn test(req: &mut dyn RequestExt) -> HttpResult {
+            let all = req.all_cookies("foo");
+            let all = all.iter().map(|c| c.value()).collect::<Vec<_>>();
+            Response::builder()
+                .header("x-foo", all.join(","))
+                .body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn original_and_pending() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        req.header(header::COOKIE, "foo=1; bar=2");
+
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(Middleware::new());
+        assert!(app.call(&mut req).is_ok());
+
+        fn test(req: &mut dyn RequestExt) -> HttpResult {
+            req.cookies_mut().add(Cookie::new("foo", "3"));
+            req.cookies_mut().add(Cookie::new("baz", "4"));
+
+            let mut original = req
+                .original_cookies()
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>();
+            original.sort();
+            assert_eq!(original, ["bar=2", "foo=1"]);
+
+            let mut pending = req
+                .pending_cookies()
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>();
+            pending.sort();
+            assert_eq!(pending, ["baz=4", "foo=3"]);
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "session")]
+    fn signed_values() {
+        let key = Key::from(&[1; 64]);
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new().signing_key(key.clone()));
+            app
+        };
+
+        let mut req = MockRequest::new(Method::GET, "/");
+        let response = app(set).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap().to_string()).unwrap();
+        assert_ne!(cookie.value(), "dark");
+        req.header(header::COOKIE, &cookie.stripped().to_string());
+        app(get).call(&mut req).unwrap();
+
+        let mut req = MockRequest::new(Method::GET, "/");
+        req.header(header::COOKIE, "theme=dark");
+        app(forged).call(&mut req).unwrap();
+
+        // Bypassed routes see no cookies, but don't panic
+        let mut app = MiddlewareBuilder::new(bypassed);
+        app.add(
+            Middleware::new()
+                .signing_key(key.clone())
+                .bypass(|req| req.path().starts_with("/assets/")),
+        );
+        let mut req = MockRequest::new(Method::GET, "/assets/app.css");
+        req.header(header::COOKIE, &cookie.stripped().to_string());
+        let response = app.call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        fn set(req: &mut dyn RequestExt) -> HttpResult {
+            req.set_signed("theme", "dark");
+            Response::builder().body(Body::empty())
+        }
+        fn get(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.get_signed("theme").as_deref(), Some("dark"));
+            Response::builder().body(Body::empty())
+        }
+        fn forged(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.get_signed("theme"), None);
+            Response::builder().body(Body::empty())
+        }
+        fn bypassed(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.get_signed("theme"), None);
+            req.set_signed("theme", "light");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn canonicalize() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(Middleware::new().canonicalize(true));
+        let response = app.call(&mut req).unwrap();
+        let v = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .collect::<Vec<_>>();
+        assert_eq!(&v[..], ["a=1", "b=3", "c=4"]);
+
+        fn test(req: &mut dyn RequestExt) -> HttpResult {
+            req.cookies_mut().add(Cookie::new("c", "4"));
+            req.cookies_mut().add(Cookie::new("a", "1"));
+            Response::builder()
+                .header(header::SET_COOKIE, "b=2")
+                .header(header::SET_COOKIE, "b=3")
+                .body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn split_headers() {
+        let mut headers = HeaderMap::new();
+        headers.append(header::COOKIE, HeaderValue::from_static("a=1; ; b=2"));
+        headers.append(header::COOKIE, HeaderValue::from_static("b=3;;c=4"));
+        headers.append(header::COOKIE, HeaderValue::from_static("=x; d; e="));
+
+        let (jar, _) = Middleware::new().parse_headers(&headers);
+        let mut cookies = jar.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        cookies.sort();
+        assert_eq!(cookies, ["a=1", "b=3", "c=4", "e="]);
+
+        let middleware = Middleware::new().duplicates(DuplicateCookies::FirstWins);
+        let (jar, _) = middleware.parse_headers(&headers);
+        assert_eq!(jar.get("b").unwrap().value(), "2");
+
+        let middleware = Middleware::new().duplicates(DuplicateCookies::CollectAll);
+        let (_, all) = middleware.parse_headers(&headers);
+        let b = all.0["b"].iter().map(|c| c.value()).collect::<Vec<_>>();
+        assert_eq!(b, ["2", "3"]);
+    }
+
+    #[test]
+    fn lazy_parsing() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        req.header(header::COOKIE, "foo=bar; baz=qux");
+        let parsed = |req: &MockRequest| req.extensions().get::<LazyJar>().unwrap().is_parsed();
+
+        let mut app = MiddlewareBuilder::new(ignore);
+        app.add(Middleware::new());
+        let response = app.call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+        assert!(!parsed(&req));
+
+        let mut app = MiddlewareBuilder::new(read);
+        app.add(Middleware::new());
+        app.call(&mut req).unwrap();
+        assert!(parsed(&req));
+
+        fn ignore(_: &mut dyn RequestExt) -> HttpResult {
+            Response::builder().body(Body::empty())
+        }
+        fn read(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.cookies().get("foo").unwrap().value(), "bar");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn quoting() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        req.header(header::COOKIE, "foo=\"bar baz\"; plain=\"x");
+
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(Middleware::new().quoting(true));
+        let response = app.call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        assert_eq!(v, "out=\"a, b\"");
+
+        fn test(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.cookies().get("foo").unwrap().value(), "bar baz");
+            assert_eq!(req.cookies().get("plain").unwrap().value(), "\"x");
+            req.cookies_mut().add(Cookie::new("out", "a, b"));
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn nested_dispatch() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        req.header(header::COOKIE, "kept=1");
+
+        let mut app = MiddlewareBuilder::new(outer);
+        app.add(Middleware::new());
+        let response = app.call(&mut req).unwrap();
+        let mut v = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .collect::<Vec<_>>();
+        v.sort();
+        assert_eq!(&v[..], ["inner=2", "merged=3", "outer=1"]);
+
+        fn outer(req: &mut dyn RequestExt) -> HttpResult {
+            req.cookies_mut().add(Cookie::new("outer", "1"));
+            let mut app = MiddlewareBuilder::new(inner);
+            app.add(Middleware::new());
+            let response = app.call(req).unwrap();
+            assert!(response.headers().get(header::SET_COOKIE).is_none());
+            req.merge_cookies(vec![Cookie::new("merged", "3")]);
+            Ok(response)
+        }
+        fn inner(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.cookies().get("kept").is_some());
+            assert!(req.cookies().get("outer").is_some());
+            req.cookies_mut().add(Cookie::new("inner", "2"));
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn header_map_adapter() {
+        let mut headers = HeaderMap::new();
+        headers.append(header::COOKIE, HeaderValue::from_static("foo=bar; baz=qux"));
+        let mut jar = super::parse_cookie_header(&headers);
+        assert_eq!(jar.get("foo").unwrap().value(), "bar");
+
+        jar.add(Cookie::new("foo", "new"));
+        jar.remove(Cookie::named("baz"));
+        let mut response = HeaderMap::new();
+        super::apply_delta(&jar, &mut response).unwrap();
+        let mut v = response
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|v| v.to_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        v.sort();
+        assert!(v[0].starts_with("baz=; Max-Age=0"));
+        assert_eq!(v[1], "foo=new");
+    }
+
+    #[test]
+    fn cookie_matches() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        req.header(header::COOKIE, "token=secret");
+
+        let mut app = MiddlewareBuilder::new(test);
+        app.add(Middleware::new());
+        assert!(app.call(&mut req).is_ok());
+
+        fn test(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.cookie_matches("token", "secret"));
+            assert!(!req.cookie_matches("token", "secreT"));
+            assert!(!req.cookie_matches("missing", "secret"));
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn status_classes() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        let mut app = MiddlewareBuilder::new(handler);
+        app.add(Middleware::new().status_classes(&[2, 3]));
+
+        for (status, emitted) in [("200", true), ("302", true), ("404", false), ("500", false)] {
+            req.header(header::COOKIE, &format!("status={status}"));
+            let response = app.call(&mut req).unwrap();
+            let set_cookie = response.headers().get(header::SET_COOKIE);
+            assert_eq!(set_cookie.is_some(), emitted, "status {status}");
+        }
+
+        fn handler(req: &mut dyn RequestExt) -> HttpResult {
+            let status = req.cookies().get("status").unwrap().value().to_string();
+            req.cookies_mut().add(Cookie::new("visited", "1"));
+            Response::builder()
+                .status(status.parse::<u16>().unwrap())
+                .body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn infrastructure_cookies() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        req.header(header::COOKIE, "bucket=a; foo=bar");
+        let mut app = MiddlewareBuilder::new(handler);
+        app.add(
+            Middleware::with_defaults(CookieDefaults::new().http_only(true))
+                .infrastructure_cookies(&["bucket"], CookieDefaults::new().path("/").secure(true)),
+        );
+        let response = app.call(&mut req).unwrap();
+        let mut v = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .collect::<Vec<_>>();
+        v.sort();
+        assert_eq!(&v[..], ["bucket=b; Secure; Path=/", "foo=baz; HttpOnly"]);
+
+        fn handler(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.cookies().get("bucket").is_none());
+            assert_eq!(
+                req.infrastructure_cookies().get("bucket").unwrap().value(),
+                "a"
+            );
+            req.infrastructure_cookies_mut()
+                .add(Cookie::new("bucket", "b"));
+            // Handlers can't clobber the bucket
+            req.cookies_mut().add(Cookie::new("bucket", "c"));
+            req.cookies_mut().add(Cookie::new("foo", "baz"));
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn skip_unchanged() {
+        let set_cookies =
+            |middleware: Middleware, handler: fn(&mut dyn RequestExt) -> HttpResult| {
+                let mut req = MockRequest::new(Method::GET, "/");
+                req.header(header::COOKIE, "a=1; b=2; c=3");
+                let mut app = MiddlewareBuilder::new(handler);
+                app.add(middleware.skip_unchanged(true));
+                let response = app.call(&mut req).unwrap();
+                let mut v = response
+                    .headers()
+                    .get_all(header::SET_COOKIE)
+                    .iter()
+                    .map(|v| v.to_str().unwrap().to_string())
+                    .collect::<Vec<_>>();
+                v.sort();
+                v
+            };
+
+        assert_eq!(
+            set_cookies(Middleware::new(), handler),
+            ["b=3", "c=3; Max-Age=3600", "d=4"]
+        );
+        // Other attributes make another cookie, or upgrade the sent one
+        assert_eq!(
+            set_cookies(Middleware::new(), attributes),
+            ["a=1; Path=/admin", "b=2; Domain=example.com", "c=3; Secure"]
+        );
+        // Unless they are the configured ones
+        let defaults = CookieDefaults::new().secure(true);
+        let middleware = Middleware::with_defaults(defaults);
+        assert_eq!(
+            set_cookies(middleware, attributes),
+            [
+                "a=1; Secure; Path=/admin",
+                "b=2; Secure; Domain=example.com"
+            ]
+        );
+
+        fn handler(req: &mut dyn RequestExt) -> HttpResult {
+            let jar = req.cookies_mut();
+            jar.add(Cookie::new("a", "1"));
+            jar.add(Cookie::new("b", "3"));
+            jar.add(Cookie::build("c", "3").max_age(Duration::hours(1)).finish());
+            jar.add(Cookie::new("d", "4"));
+            Response::builder().body(Body::empty())
+        }
+        fn attributes(req: &mut dyn RequestExt) -> HttpResult {
+            let jar = req.cookies_mut();
+            jar.add(Cookie::build("a", "1").path("/admin").finish());
+            jar.add(Cookie::build("b", "2").domain("example.com").finish());
+            jar.add(Cookie::build("c", "3").secure(true).finish());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn bypass() {
+        let mut app = MiddlewareBuilder::new(handler);
+        app.add(Middleware::new().bypass(|req| req.path().starts_with("/assets/")));
+
+        let mut req = MockRequest::new(Method::GET, "/assets/app.css");
+        req.header(header::COOKIE, "foo=bar");
+        let response = app.call(&mut req).unwrap();
+        assert_eq!(response.headers().get("x-cookies").unwrap(), "0");
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        let mut req = MockRequest::new(Method::GET, "/articles");
+        req.header(header::COOKIE, "foo=bar");
+        let response = app.call(&mut req).unwrap();
+        assert_eq!(response.headers().get("x-cookies").unwrap(), "1");
+        assert!(response.headers().get(header::SET_COOKIE).is_some());
+
+        fn handler(req: &mut dyn RequestExt) -> HttpResult {
+            let count = req.cookies().iter().count();
+            req.cookies_mut().add(Cookie::new("visited", "1"));
+            Response::builder()
+                .header("x-cookies", count.to_string())
+                .body(Body::empty())
+        }
+    }
 }
@@ -0,0 +1,32 @@
+use std::sync::Arc;
+
+/// Counters for cookie and session events, e.g. to export to Prometheus or
+/// StatsD. All methods do nothing by default.
+pub trait CookieMetrics: Send + Sync + 'static {
+    /// A valid session cookie was read from the request.
+    fn session_read(&self) {}
+    /// A session cookie was written to the response.
+    fn session_written(&self) {}
+    /// A session cookie failed signature verification or decryption.
+    fn invalid_signature(&self) {}
+    /// A request or response cookie exceeded a configured size limit.
+    fn oversized_cookie(&self) {}
+}
+
+impl<M: CookieMetrics + ?Sized> CookieMetrics for Arc<M> {
+    fn session_read(&self) {
+        (**self).session_read()
+    }
+
+    fn session_written(&self) {
+        (**self).session_written()
+    }
+
+    fn invalid_signature(&self) {
+        (**self).invalid_signature()
+    }
+
+    fn oversized_cookie(&self) {
+        (**self).oversized_cookie()
+    }
+}
@@ -0,0 +1,51 @@
+/// How `Middleware` treats response cookie names that aren't valid RFC 6265
+/// tokens, e.g. names containing `;`, `,` or whitespace, which browsers
+/// would misinterpret.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum NameValidation {
+    /// Send names as they are.
+    #[default]
+    Off,
+    /// Treat such cookies as errors, which are handled according to the
+    /// middleware's `ErrorPolicy`.
+    Reject,
+    /// Replace invalid characters with `_`.
+    Sanitize,
+}
+
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(c)
+}
+
+pub(crate) fn is_valid(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(is_token_char)
+}
+
+pub(crate) fn sanitize(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| if is_token_char(c) { c } else { '_' })
+        .collect::<String>();
+    if name.is_empty() {
+        "_".to_string()
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_valid, sanitize};
+
+    #[test]
+    fn tokens() {
+        assert!(is_valid("session_id"));
+        assert!(is_valid("__Host-a.b"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("a b"));
+        assert!(!is_valid("a;b"));
+        assert!(!is_valid("a,b"));
+        assert!(!is_valid("ä"));
+        assert_eq!(sanitize("a b;c"), "a_b_c");
+    }
+}
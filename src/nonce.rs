@@ -0,0 +1,105 @@
+use base64::{encode_config, URL_SAFE_NO_PAD};
+use conduit::RequestExt;
+use cookie::time::Duration;
+
+use crate::{constant_time_eq, RequestSession};
+
+const NONCE_PREFIX: &str = "_nonce.";
+
+/// One-time tokens kept in the session, e.g. for the OAuth `state`
+/// parameter or a PKCE code verifier.
+///
+/// Requires `SessionMiddleware`.
+pub trait RequestNonce {
+    /// Generates a random URL-safe token for `purpose`, valid for `ttl`.
+    /// Issuing another token for the same purpose replaces the previous one.
+    fn issue_nonce(&mut self, purpose: &str, ttl: Duration) -> String;
+
+    /// Whether `nonce` is the unexpired token issued for `purpose`. The
+    /// token is removed either way, so it can only be used once.
+    fn consume_nonce(&mut self, purpose: &str, nonce: &str) -> bool;
+
+    /// Removes and returns the unexpired token issued for `purpose`, e.g. a
+    /// PKCE code verifier to send to the token endpoint.
+    fn take_nonce(&mut self, purpose: &str) -> Option<String>;
+}
+
+impl<T: RequestExt + ?Sized> RequestNonce for T {
+    fn issue_nonce(&mut self, purpose: &str, ttl: Duration) -> String {
+        let nonce = encode_config(rand::random::<[u8; 32]>(), URL_SAFE_NO_PAD);
+        let key = format!("{NONCE_PREFIX}{purpose}");
+        self.session_set_with_ttl(&key, &nonce, ttl);
+        nonce
+    }
+
+    fn consume_nonce(&mut self, purpose: &str, nonce: &str) -> bool {
+        let expected = self.take_nonce(purpose);
+        expected.is_some_and(|expected| constant_time_eq(expected, nonce))
+    }
+
+    fn take_nonce(&mut self, purpose: &str) -> Option<String> {
+        let key = format!("{NONCE_PREFIX}{purpose}");
+        // Avoids rewriting the session when there is no token
+        self.session().get(&key)?;
+        self.session_remove(&key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use conduit::{header, Body, Handler, HttpResult, Method, RequestExt, Response};
+    use conduit_middleware::MiddlewareBuilder;
+    use conduit_test::MockRequest;
+    use cookie::time::Duration;
+    use cookie::Key;
+
+    use super::RequestNonce;
+    use crate::{Middleware, SessionMiddleware};
+
+    fn app(handler: fn(&mut dyn RequestExt) -> HttpResult) -> MiddlewareBuilder {
+        let master_key: Vec<u8> = (0..32).collect();
+        let mut app = MiddlewareBuilder::new(handler);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new(
+            "nonce",
+            Key::derive_from(&master_key),
+            false,
+        ));
+        app
+    }
+
+    #[test]
+    fn single_use() {
+        let mut req = MockRequest::new(Method::GET, "/login");
+        let response = app(issue).call(&mut req).unwrap();
+        let state = response.headers().get("x-state").unwrap().to_str().unwrap();
+        assert_eq!(state.len(), 43);
+        req.header("x-state", state);
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        let response = app(callback).call(&mut req).unwrap();
+        assert_eq!(response.headers().get("x-valid").unwrap(), "true");
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+
+        // The token can't be used again
+        req.header(header::COOKIE, v.to_str().unwrap());
+        let response = app(callback).call(&mut req).unwrap();
+        assert_eq!(response.headers().get("x-valid").unwrap(), "false");
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        fn issue(req: &mut dyn RequestExt) -> HttpResult {
+            let state = req.issue_nonce("oauth_state", Duration::minutes(10));
+            Response::builder()
+                .header("x-state", state)
+                .body(Body::empty())
+        }
+        fn callback(req: &mut dyn RequestExt) -> HttpResult {
+            let state = req.headers()["x-state"].to_str().unwrap().to_string();
+            let valid = req.consume_nonce("oauth_state", &state);
+            Response::builder()
+                .header("x-valid", valid.to_string())
+                .body(Body::empty())
+        }
+    }
+}
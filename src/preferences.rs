@@ -0,0 +1,213 @@
+use conduit::RequestExt;
+use conduit_middleware::{AfterResult, BeforeResult};
+use cookie::time::Duration;
+use cookie::{Cookie, SameSite};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+
+use crate::RequestCookies;
+
+const DEFAULT_MAX_AGE_DAYS: i64 = 365;
+
+// Characters that can't appear in cookie values, plus the delimiters of the
+// encoded preferences.
+const ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b',')
+    .add(b';')
+    .add(b'\\')
+    .add(b'%')
+    .add(b'&')
+    .add(b'=');
+
+/// User preferences kept in a plain cookie by `PreferencesMiddleware`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Preferences {
+    pub locale: Option<String>,
+    pub theme: Option<String>,
+    pub timezone: Option<String>,
+}
+
+impl Preferences {
+    fn parse(value: &str) -> Preferences {
+        let mut preferences = Preferences::default();
+        for pair in value.split('&') {
+            let (name, value) = match pair.split_once('=') {
+                Some(pair) => pair,
+                None => continue,
+            };
+            let value = percent_decode_str(value).decode_utf8_lossy().into_owned();
+            match name {
+                "locale" => preferences.locale = Some(value),
+                "theme" => preferences.theme = Some(value),
+                "timezone" => preferences.timezone = Some(value),
+                // Preferences written by newer versions of the app
+                _ => {}
+            }
+        }
+        preferences
+    }
+
+    fn encode(&self) -> String {
+        let fields = [
+            ("locale", &self.locale),
+            ("theme", &self.theme),
+            ("timezone", &self.timezone),
+        ];
+        let pairs = fields.iter().filter_map(|(name, value)| {
+            let value = utf8_percent_encode(value.as_deref()?, ENCODE_SET);
+            Some(format!("{name}={value}"))
+        });
+        pairs.collect::<Vec<_>>().join("&")
+    }
+}
+
+/// Reads `Preferences` from a plain cookie, available via
+/// `req.preferences()`, and writes them back with a long expiry when they
+/// were changed.
+///
+/// Must be added after `Middleware`.
+pub struct PreferencesMiddleware {
+    cookie_name: String,
+    max_age: Duration,
+}
+
+struct PreferencesState {
+    original: Preferences,
+    current: Preferences,
+}
+
+impl Default for PreferencesMiddleware {
+    fn default() -> Self {
+        PreferencesMiddleware {
+            cookie_name: "preferences".to_string(),
+            max_age: Duration::days(DEFAULT_MAX_AGE_DAYS),
+        }
+    }
+}
+
+impl PreferencesMiddleware {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn cookie_name(mut self, cookie_name: &str) -> Self {
+        self.cookie_name = cookie_name.to_string();
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+}
+
+impl conduit_middleware::Middleware for PreferencesMiddleware {
+    fn before(&self, req: &mut dyn RequestExt) -> BeforeResult {
+        let cookie = req.cookies().get(&self.cookie_name);
+        let original = cookie
+            .map(|cookie| Preferences::parse(cookie.value()))
+            .unwrap_or_default();
+        let current = original.clone();
+        req.mut_extensions()
+            .insert(PreferencesState { original, current });
+        Ok(())
+    }
+
+    fn after(&self, req: &mut dyn RequestExt, res: AfterResult) -> AfterResult {
+        let state = req.mut_extensions().remove::<PreferencesState>();
+        let state = state.expect("preferences must be present after request");
+        if state.current == state.original {
+            return res;
+        }
+        let cookie = Cookie::build(self.cookie_name.clone(), state.current.encode())
+            .path("/")
+            .same_site(SameSite::Lax);
+        if state.current == Preferences::default() {
+            req.cookies_mut().remove(cookie.finish());
+        } else {
+            req.cookies_mut().add(cookie.max_age(self.max_age).finish());
+        }
+        res
+    }
+}
+
+pub trait RequestPreferences {
+    /// The preferences read from the request's cookie.
+    fn preferences(&self) -> &Preferences;
+    /// The preferences, which are sent with the response if changed.
+    fn preferences_mut(&mut self) -> &mut Preferences;
+}
+
+impl<T: RequestExt + ?Sized> RequestPreferences for T {
+    fn preferences(&self) -> &Preferences {
+        let state = self.extensions().get::<PreferencesState>();
+        &state.expect("missing preferences").current
+    }
+
+    fn preferences_mut(&mut self) -> &mut Preferences {
+        let state = self.mut_extensions().get_mut::<PreferencesState>();
+        &mut state.expect("missing preferences").current
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use conduit::{header, Body, Handler, HttpResult, Method, RequestExt, Response};
+    use conduit_middleware::MiddlewareBuilder;
+    use conduit_test::MockRequest;
+    use cookie::Cookie;
+
+    use super::{Preferences, PreferencesMiddleware, RequestPreferences};
+    use crate::Middleware;
+
+    fn app(handler: fn(&mut dyn RequestExt) -> HttpResult) -> MiddlewareBuilder {
+        let mut app = MiddlewareBuilder::new(handler);
+        app.add(Middleware::new());
+        app.add(PreferencesMiddleware::new().cookie_name("prefs"));
+        app
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        let response = app(set).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+        assert_eq!(
+            cookie.value(),
+            "locale=de&timezone=America/Los_Angeles%3B%26"
+        );
+        assert!(cookie.max_age().is_some());
+
+        // Unchanged preferences aren't written again
+        req.header(header::COOKIE, &cookie.stripped().to_string());
+        let response = app(check).call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        let response = app(reset).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        assert!(v.to_str().unwrap().starts_with("prefs=;"));
+
+        fn set(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(*req.preferences(), Preferences::default());
+            req.preferences_mut().locale = Some("de".to_string());
+            req.preferences_mut().timezone = Some("America/Los_Angeles;&".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn check(req: &mut dyn RequestExt) -> HttpResult {
+            let preferences = req.preferences();
+            assert_eq!(preferences.locale.as_deref(), Some("de"));
+            assert_eq!(preferences.theme, None);
+            assert_eq!(
+                preferences.timezone.as_deref(),
+                Some("America/Los_Angeles;&")
+            );
+            Response::builder().body(Body::empty())
+        }
+        fn reset(req: &mut dyn RequestExt) -> HttpResult {
+            *req.preferences_mut() = Preferences::default();
+            Response::builder().body(Body::empty())
+        }
+    }
+}
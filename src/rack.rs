@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use cookie::time::format_description::well_known::Rfc3339;
+use cookie::time::OffsetDateTime;
+use hmac::{Hmac, Mac};
+use percent_encoding::percent_decode_str;
+use serde_json::{Map, Value};
+use sha2::Sha256;
+
+use crate::{constant_time_eq, SessionImporter};
+
+// Salt and iteration count Rails derives the cookie encryption key with.
+const ENCRYPTED_COOKIE_SALT: &[u8] = b"authenticated encrypted cookie";
+const KEY_ITERATIONS: u32 = 1000;
+
+/// Imports sessions from the cookies of Rack and Rails apps, for
+/// `SessionMiddlewareBuilder::import`.
+///
+/// Only JSON serialized sessions can be read, not Ruby's Marshal format.
+/// Values that aren't strings are imported as their JSON representation.
+/// Cookie values are URL-decoded, as Rack escapes them, whether or not
+/// `Middleware::percent_encoding` is enabled.
+pub struct RackSession {
+    kind: Kind,
+}
+
+enum Kind {
+    Signed { secret: Vec<u8> },
+    Encrypted { key: [u8; 32], purpose: String },
+}
+
+impl RackSession {
+    /// Reads the cookies of `Rack::Session::Cookie` configured with
+    /// `secrets: secret`, `hmac: OpenSSL::Digest::SHA256` and
+    /// `coder: Rack::Session::Cookie::Base64::JSON.new`.
+    pub fn signed(secret: &[u8]) -> Self {
+        let secret = secret.to_vec();
+        RackSession {
+            kind: Kind::Signed { secret },
+        }
+    }
+
+    /// Reads the encrypted session cookie `cookie_name` of a Rails app with
+    /// `secret_key_base`, using the JSON cookie serializer and SHA256 key
+    /// derivation, the defaults since Rails 7.
+    pub fn encrypted(secret_key_base: &[u8], cookie_name: &str) -> Self {
+        let key = pbkdf2_sha256(secret_key_base, ENCRYPTED_COOKIE_SALT, KEY_ITERATIONS);
+        let purpose = format!("cookie.{cookie_name}");
+        RackSession {
+            kind: Kind::Encrypted { key, purpose },
+        }
+    }
+}
+
+fn hmac_sha256(key: &[u8]) -> Hmac<Sha256> {
+    <Hmac<Sha256> as Mac>::new_from_slice(key).unwrap()
+}
+
+/// PBKDF2-HMAC-SHA256 for a single 32 byte block, as used by
+/// `ActiveSupport::KeyGenerator`.
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut mac = hmac_sha256(password);
+    mac.update(salt);
+    mac.update(&1u32.to_be_bytes());
+    let mut block = mac.finalize().into_bytes();
+    let mut key = <[u8; 32]>::from(block);
+    for _ in 1..iterations {
+        let mut mac = hmac_sha256(password);
+        mac.update(&block);
+        block = mac.finalize().into_bytes();
+        key.iter_mut().zip(block).for_each(|(k, b)| *k ^= b);
+    }
+    key
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Decodes base64 as written by Ruby, which may contain line breaks.
+fn decode_base64(value: &str) -> Option<Vec<u8>> {
+    let value = value.replace(['\n', '\r'], "");
+    base64::decode(value).ok()
+}
+
+fn to_session(object: Map<String, Value>) -> HashMap<String, String> {
+    object
+        .into_iter()
+        .map(|(key, value)| match value {
+            Value::String(value) => (key, value),
+            value => (key, value.to_string()),
+        })
+        .collect()
+}
+
+/// Unwraps the metadata envelope of Rails messages, checking their purpose
+/// and expiry.
+fn unwrap_envelope(mut object: Map<String, Value>, purpose: &str) -> Option<Map<String, Value>> {
+    let rails = match object.remove("_rails") {
+        Some(Value::Object(rails)) => rails,
+        Some(_) => return None,
+        None => return Some(object),
+    };
+    if rails.get("pur").and_then(Value::as_str) != Some(purpose) {
+        return None;
+    }
+    if let Some(exp) = rails.get("exp").and_then(Value::as_str) {
+        if OffsetDateTime::parse(exp, &Rfc3339).ok()? < OffsetDateTime::now_utc() {
+            return None;
+        }
+    }
+    match (rails.get("data"), rails.get("message")) {
+        (Some(Value::Object(data)), _) => Some(data.clone()),
+        (_, Some(Value::String(message))) => serde_json::from_slice(&decode_base64(message)?).ok(),
+        _ => None,
+    }
+}
+
+impl RackSession {
+    fn verify(secret: &[u8], value: &str) -> Option<Map<String, Value>> {
+        let (data, digest) = value.rsplit_once("--")?;
+        let mut mac = hmac_sha256(secret);
+        mac.update(data.as_bytes());
+        if !constant_time_eq(hex(&mac.finalize().into_bytes()), digest) {
+            return None;
+        }
+        serde_json::from_slice(&decode_base64(data)?).ok()
+    }
+
+    fn decrypt(key: &[u8; 32], purpose: &str, value: &str) -> Option<Map<String, Value>> {
+        let mut parts = value.split("--").map(decode_base64);
+        let (data, iv, tag) = (parts.next()??, parts.next()??, parts.next()??);
+        if parts.next().is_some() || iv.len() != 12 {
+            return None;
+        }
+        let mut msg = data;
+        msg.extend(tag);
+        let payload = Payload {
+            msg: &msg,
+            aad: b"",
+        };
+        let cipher = Aes256Gcm::new_from_slice(key).ok()?;
+        let plaintext = cipher.decrypt(Nonce::from_slice(&iv), payload).ok()?;
+        unwrap_envelope(serde_json::from_slice(&plaintext).ok()?, purpose)
+    }
+}
+
+impl SessionImporter for RackSession {
+    fn import(&self, value: &str) -> Option<HashMap<String, String>> {
+        // Base64 has no `%`, so decoding an already decoded value is a no-op
+        let value = percent_decode_str(value).decode_utf8().ok()?;
+        let object = match &self.kind {
+            Kind::Signed { secret } => Self::verify(secret, &value)?,
+            Kind::Encrypted { key, purpose } => Self::decrypt(key, purpose, &value)?,
+        };
+        Some(to_session(object))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RackSession;
+    use crate::SessionImporter;
+
+    // Written with the key derivation and message format of Rails 7
+    const RAILS_COOKIE: &str = "O+0gmj01oTBvayRgR/TOincB8Aux1YglvBwSRR+tSqF4A+Vn6dshfMW/jE82Z79UCTqzWhlnGv84ChfYVabyX2GOoGozxlUvU/Odsr/4R5Onm0RqemLD/n+da5iFszDlD95CpEuubf2Ct0Cb/E7iwHFb8WvAiTL+txfzdvNM9qdfOqb2o6/YSg==--AAECAwQFBgcICQoL--0+/unkPX+qvFk2yq8auWBg==";
+    const RACK_COOKIE: &str = "eyJ1c2VyX2lkIjoiNyIsImZsYXNoIjp7ImEiOjF9fQ==\n--c87edae5013ea8886524439e9a8a6603761e30cc4c62986629000c599993bac0";
+
+    #[test]
+    fn encrypted() {
+        let secret_key_base = [b'a'; 128];
+        let rails = RackSession::encrypted(&secret_key_base, "_app_session");
+        let session = rails.import(RAILS_COOKIE).unwrap();
+        assert_eq!(session["session_id"], "4f2a");
+        assert_eq!(session["user_id"], "42");
+        assert_eq!(session["_csrf_token"], "tok");
+
+        // The purpose binds the cookie to its name
+        let other = RackSession::encrypted(&secret_key_base, "_other_session");
+        assert!(other.import(RAILS_COOKIE).is_none());
+        let wrong_key = RackSession::encrypted(&[b'b'; 128], "_app_session");
+        assert!(wrong_key.import(RAILS_COOKIE).is_none());
+        assert!(rails.import("a--b--c").is_none());
+    }
+
+    #[test]
+    fn signed() {
+        let rack = RackSession::signed(b"rack secret");
+        let session = rack.import(RACK_COOKIE).unwrap();
+        assert_eq!(session["user_id"], "7");
+        assert_eq!(session["flash"], r#"{"a":1}"#);
+
+        assert!(RackSession::signed(b"other").import(RACK_COOKIE).is_none());
+        let tampered = RACK_COOKIE.replace("eyJ1", "eyJ2");
+        assert!(rack.import(&tampered).is_none());
+    }
+
+    #[test]
+    fn escaped() {
+        // As sent in the `Set-Cookie` header of a Rack response
+        let escaped = "eyJ1c2VyX2lkIjoiNyIsImZsYXNoIjp7ImEiOjF9fQ%3D%3D%0A--c87edae5013ea8886524439e9a8a6603761e30cc4c62986629000c599993bac0";
+        let session = RackSession::signed(b"rack secret").import(escaped).unwrap();
+        assert_eq!(session["user_id"], "7");
+
+        let escaped = RAILS_COOKIE
+            .replace('+', "%2B")
+            .replace('/', "%2F")
+            .replace('=', "%3D");
+        let rails = RackSession::encrypted(&[b'a'; 128], "_app_session");
+        assert_eq!(rails.import(&escaped).unwrap()["user_id"], "42");
+    }
+}
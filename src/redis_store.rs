@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use conduit::{box_error, BoxError};
+use redis::{Client, Commands, Connection};
+
+use crate::{SessionMiddleware, SessionStore};
+
+/// A `SessionStore` keeping sessions in Redis, using key expiry for the
+/// session lifetime.
+pub struct RedisStore {
+    client: Client,
+    prefix: String,
+}
+
+impl RedisStore {
+    pub fn new(client: Client) -> RedisStore {
+        RedisStore {
+            client,
+            prefix: "session:".to_string(),
+        }
+    }
+
+    /// Sets the prefix for session keys, `session:` by default.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    fn connection(&self) -> Result<Connection, BoxError> {
+        self.client.get_connection().map_err(box_error)
+    }
+
+    fn key(&self, id: &str) -> String {
+        format!("{}{}", self.prefix, id)
+    }
+}
+
+impl SessionStore for RedisStore {
+    fn load(&self, id: &str) -> Result<Option<HashMap<String, String>>, BoxError> {
+        let value: Option<String> = self.connection()?.get(self.key(id)).map_err(box_error)?;
+        value
+            .map(|value| SessionMiddleware::try_decode_value(&value).map_err(box_error))
+            .transpose()
+    }
+
+    fn save(
+        &self,
+        id: &str,
+        data: &HashMap<String, String>,
+        ttl: Duration,
+    ) -> Result<(), BoxError> {
+        let value = SessionMiddleware::encode(data);
+        // Redis rejects an expiry of zero seconds
+        let seconds = ttl.as_secs().max(1);
+        self.connection()?
+            .set_ex::<_, _, ()>(self.key(id), value, seconds)
+            .map_err(box_error)
+    }
+
+    fn remove(&self, id: &str) -> Result<(), BoxError> {
+        self.connection()?
+            .del::<_, ()>(self.key(id))
+            .map_err(box_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use redis::Client;
+
+    use super::RedisStore;
+
+    #[test]
+    fn prefixed_keys() {
+        let client = Client::open("redis://127.0.0.1/").unwrap();
+        let store = RedisStore::new(client).prefix("app:");
+        assert_eq!(store.key("abc"), "app:abc");
+    }
+}
@@ -0,0 +1,508 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use base64::{encode_config, URL_SAFE_NO_PAD};
+use conduit::{BoxError, RequestExt};
+use conduit_middleware::{AfterResult, BeforeResult};
+use cookie::time::{Duration, OffsetDateTime};
+use cookie::{Cookie, Key, SameSite};
+
+use crate::{constant_time_eq, RequestCookies};
+
+const DEFAULT_MAX_AGE_DAYS: i64 = 30;
+const DEFAULT_GRACE_PERIOD_SECS: i64 = 60;
+
+/// A series of remember-me tokens, as kept by a `RememberMeStore`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RememberMeEntry {
+    pub user: String,
+    pub token: String,
+    /// The token replaced by `token`, which is still accepted during the
+    /// grace period after `rotated`.
+    pub previous: Option<String>,
+    pub rotated: OffsetDateTime,
+}
+
+/// Persists remember-me tokens for `RememberMeMiddleware`.
+///
+/// Each login gets its own series, whose token changes whenever the cookie
+/// logs the user in again. A known series presented with a token that is
+/// neither the current one nor, shortly after a change, the previous one
+/// means the cookie was stolen, in which case `revoke` is called for the
+/// user.
+pub trait RememberMeStore: Send + Sync + 'static {
+    fn load(&self, series: &str) -> Result<Option<RememberMeEntry>, BoxError>;
+    fn save(&self, series: &str, entry: &RememberMeEntry) -> Result<(), BoxError>;
+    fn remove(&self, series: &str) -> Result<(), BoxError>;
+    /// Removes all series of `user`.
+    fn revoke(&self, user: &str) -> Result<(), BoxError>;
+}
+
+impl<S: RememberMeStore + ?Sized> RememberMeStore for Arc<S> {
+    fn load(&self, series: &str) -> Result<Option<RememberMeEntry>, BoxError> {
+        (**self).load(series)
+    }
+
+    fn save(&self, series: &str, entry: &RememberMeEntry) -> Result<(), BoxError> {
+        (**self).save(series, entry)
+    }
+
+    fn remove(&self, series: &str) -> Result<(), BoxError> {
+        (**self).remove(series)
+    }
+
+    fn revoke(&self, user: &str) -> Result<(), BoxError> {
+        (**self).revoke(user)
+    }
+}
+
+/// Keeps users logged in across sessions with a long-lived signed cookie
+/// holding a series id and a single-use token.
+///
+/// The token is replaced after a successful response to a request that
+/// read `remembered_user`, i.e. that used the cookie to log the user in.
+/// Requests that were already in flight with the previous token are
+/// accepted for a grace period, see `grace_period`. Failures of the store
+/// while writing are logged and leave the response alone.
+///
+/// Must be added after `Middleware`.
+pub struct RememberMeMiddleware {
+    key: Key,
+    store: Box<dyn RememberMeStore>,
+    cookie_name: String,
+    secure: bool,
+    max_age: Duration,
+    grace_period: Duration,
+}
+
+enum Pending {
+    Remember(String),
+    Forget,
+}
+
+struct RememberMe {
+    user: Option<String>,
+    series: Option<String>,
+    token: Option<String>,
+    used: AtomicBool,
+    pending: Option<Pending>,
+}
+
+impl RememberMeMiddleware {
+    pub fn new<S: RememberMeStore>(key: Key, store: S) -> Self {
+        RememberMeMiddleware {
+            key,
+            store: Box::new(store),
+            cookie_name: "remember_me".to_string(),
+            secure: false,
+            max_age: Duration::days(DEFAULT_MAX_AGE_DAYS),
+            grace_period: Duration::seconds(DEFAULT_GRACE_PERIOD_SECS),
+        }
+    }
+
+    pub fn cookie_name(mut self, cookie_name: &str) -> Self {
+        self.cookie_name = cookie_name.to_string();
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// How long the previous token stays valid after it was replaced, for
+    /// parallel requests and responses that never reached the client.
+    /// Defaults to one minute.
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Checks the cookie against the store, returning the user, series and
+    /// token if it is valid.
+    fn validate(&self, value: &str) -> Result<Option<(String, String, String)>, BoxError> {
+        let (series, token) = match value.split_once(':') {
+            Some(parts) => parts,
+            None => return Ok(None),
+        };
+        let entry = match self.store.load(series)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        if !constant_time_eq(token, &entry.token) && !self.is_previous(&entry, token) {
+            self.store.revoke(&entry.user)?;
+            return Ok(None);
+        }
+        Ok(Some((entry.user, series.to_string(), token.to_string())))
+    }
+
+    /// Whether `token` was replaced within the grace period.
+    fn is_previous(&self, entry: &RememberMeEntry, token: &str) -> bool {
+        let previous = entry.previous.as_deref();
+        entry.rotated + self.grace_period > OffsetDateTime::now_utc()
+            && previous.is_some_and(|previous| constant_time_eq(token, previous))
+    }
+
+    /// Replaces `token` with a fresh one, returning the token to send to
+    /// the client. Requests that raced an earlier rotation get the token
+    /// it issued.
+    fn rotate(&self, series: &str, token: &str) -> Result<Option<String>, BoxError> {
+        let mut entry = match self.store.load(series)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        if constant_time_eq(token, &entry.token) {
+            entry.previous = Some(std::mem::replace(&mut entry.token, new_token()));
+            entry.rotated = OffsetDateTime::now_utc();
+            self.store.save(series, &entry)?;
+        } else if !self.is_previous(&entry, token) {
+            return Ok(None);
+        }
+        Ok(Some(entry.token))
+    }
+
+    /// The cookie value to send with the response: `None` if it stays as
+    /// is, and `Some(None)` if the cookie is removed.
+    fn update(&self, state: RememberMe) -> Result<Option<Option<String>>, BoxError> {
+        let value = match state.pending {
+            Some(Pending::Remember(user)) => {
+                if let Some(series) = &state.series {
+                    self.store.remove(series)?;
+                }
+                let series = new_token();
+                let entry = RememberMeEntry {
+                    user,
+                    token: new_token(),
+                    previous: None,
+                    rotated: OffsetDateTime::now_utc(),
+                };
+                self.store.save(&series, &entry)?;
+                Some(format!("{series}:{}", entry.token))
+            }
+            Some(Pending::Forget) => {
+                if let Some(series) = &state.series {
+                    self.store.remove(series)?;
+                }
+                None
+            }
+            None => match (state.series, state.token) {
+                (Some(series), Some(token)) if state.used.load(Ordering::Relaxed) => {
+                    match self.rotate(&series, &token)? {
+                        Some(token) => Some(format!("{series}:{token}")),
+                        None => return Ok(None),
+                    }
+                }
+                _ => return Ok(None),
+            },
+        };
+        Ok(Some(value))
+    }
+
+    /// Logs a failure of the store, which shouldn't fail the response.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn store_failed(&self, error: BoxError) {
+        event!(WARN, cookie = %self.cookie_name, %error, "remember me store failed");
+    }
+
+    fn cookie(&self, value: String) -> Cookie<'static> {
+        Cookie::build(self.cookie_name.clone(), value)
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+            .path("/")
+            .max_age(self.max_age)
+            .finish()
+    }
+}
+
+fn new_token() -> String {
+    encode_config(rand::random::<[u8; 32]>(), URL_SAFE_NO_PAD)
+}
+
+impl conduit_middleware::Middleware for RememberMeMiddleware {
+    fn before(&self, req: &mut dyn RequestExt) -> BeforeResult {
+        let value = req
+            .cookies()
+            .signed(&self.key)
+            .get(&self.cookie_name)
+            .map(|cookie| cookie.value().to_string());
+        let validated = match &value {
+            Some(value) => self.validate(value)?,
+            None => None,
+        };
+        let mut state = RememberMe {
+            user: None,
+            series: None,
+            token: None,
+            used: AtomicBool::new(false),
+            pending: None,
+        };
+        match validated {
+            Some((user, series, token)) => {
+                state.user = Some(user);
+                state.series = Some(series);
+                state.token = Some(token);
+            }
+            // Drop cookies that are invalid or were revoked
+            None if req.cookies().get(&self.cookie_name).is_some() => {
+                state.pending = Some(Pending::Forget);
+            }
+            None => {}
+        }
+        req.mut_extensions().insert(state);
+        Ok(())
+    }
+
+    fn after(&self, req: &mut dyn RequestExt, res: AfterResult) -> AfterResult {
+        let state = req.mut_extensions().remove::<RememberMe>();
+        let mut state = state.expect("remember me state must be present after request");
+        // Keep the current token if the client may never see a new one
+        if !matches!(&res, Ok(res) if res.status().is_success()) {
+            state.used = AtomicBool::new(false);
+        }
+        let value = match self.update(state) {
+            Ok(Some(value)) => value,
+            Ok(None) => return res,
+            Err(error) => {
+                self.store_failed(error);
+                return res;
+            }
+        };
+        match value {
+            Some(value) => {
+                let cookie = self.cookie(value);
+                req.cookies_mut().signed_mut(&self.key).add(cookie);
+            }
+            None => {
+                let cookie = Cookie::build(self.cookie_name.clone(), "").path("/");
+                req.cookies_mut().remove(cookie.finish());
+            }
+        }
+        res
+    }
+}
+
+pub trait RequestRememberMe {
+    /// The user logged in through a valid remember-me cookie, if any.
+    /// Reading it counts as logging in with the cookie, which replaces its
+    /// token with the response, so only read it when the session has no
+    /// user yet.
+    fn remembered_user(&self) -> Option<&str>;
+    /// Issues a remember-me cookie for `user` with the response.
+    fn remember(&mut self, user: &str);
+    /// Removes the remember-me cookie and its series, e.g. on logout.
+    fn forget(&mut self);
+}
+
+fn state_mut<R: RequestExt + ?Sized>(req: &mut R) -> &mut RememberMe {
+    req.mut_extensions()
+        .get_mut::<RememberMe>()
+        .expect("missing remember me state")
+}
+
+impl<T: RequestExt + ?Sized> RequestRememberMe for T {
+    fn remembered_user(&self) -> Option<&str> {
+        let state = self.extensions().get::<RememberMe>();
+        let state = state.expect("missing remember me state");
+        state.used.store(true, Ordering::Relaxed);
+        state.user.as_deref()
+    }
+
+    fn remember(&mut self, user: &str) {
+        state_mut(self).pending = Some(Pending::Remember(user.to_string()));
+    }
+
+    fn forget(&mut self) {
+        let state = state_mut(self);
+        state.user = None;
+        state.pending = Some(Pending::Forget);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use conduit::{
+        header, Body, BoxError, Handler, HttpResult, Method, RequestExt, Response, StatusCode,
+    };
+    use conduit_middleware::MiddlewareBuilder;
+    use conduit_test::MockRequest;
+    use cookie::time::Duration;
+    use cookie::{Cookie, Key};
+
+    use super::{RememberMeEntry, RememberMeMiddleware, RememberMeStore, RequestRememberMe};
+    use crate::Middleware;
+
+    #[derive(Default)]
+    struct Store {
+        series: Mutex<HashMap<String, RememberMeEntry>>,
+        down: AtomicBool,
+    }
+
+    impl Store {
+        fn check(&self) -> Result<(), BoxError> {
+            if self.down.load(Ordering::SeqCst) {
+                let error = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+                return Err(Box::new(error));
+            }
+            Ok(())
+        }
+    }
+
+    impl RememberMeStore for Store {
+        fn load(&self, series: &str) -> Result<Option<RememberMeEntry>, BoxError> {
+            Ok(self.series.lock().unwrap().get(series).cloned())
+        }
+
+        fn save(&self, series: &str, entry: &RememberMeEntry) -> Result<(), BoxError> {
+            self.check()?;
+            let mut all = self.series.lock().unwrap();
+            all.insert(series.to_string(), entry.clone());
+            Ok(())
+        }
+
+        fn remove(&self, series: &str) -> Result<(), BoxError> {
+            self.check()?;
+            self.series.lock().unwrap().remove(series);
+            Ok(())
+        }
+
+        fn revoke(&self, user: &str) -> Result<(), BoxError> {
+            let mut series = self.series.lock().unwrap();
+            series.retain(|_, entry| entry.user != user);
+            Ok(())
+        }
+    }
+
+    fn app(
+        store: &Arc<Store>,
+        handler: fn(&mut dyn RequestExt) -> HttpResult,
+    ) -> MiddlewareBuilder {
+        app_with_grace_period(store, Duration::minutes(1), handler)
+    }
+
+    fn app_with_grace_period(
+        store: &Arc<Store>,
+        grace_period: Duration,
+        handler: fn(&mut dyn RequestExt) -> HttpResult,
+    ) -> MiddlewareBuilder {
+        let master_key: Vec<u8> = (0..32).collect();
+        let mut app = MiddlewareBuilder::new(handler);
+        app.add(Middleware::new());
+        app.add(
+            RememberMeMiddleware::new(Key::derive_from(&master_key), store.clone())
+                .grace_period(grace_period),
+        );
+        app
+    }
+
+    fn cookie(response: &Response<Body>) -> String {
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap().to_string()).unwrap();
+        cookie.stripped().to_string()
+    }
+
+    fn login(req: &mut dyn RequestExt) -> HttpResult {
+        assert_eq!(req.remembered_user(), None);
+        req.remember("alice");
+        Response::builder().body(Body::empty())
+    }
+    fn check(req: &mut dyn RequestExt) -> HttpResult {
+        assert_eq!(req.remembered_user(), Some("alice"));
+        Response::builder().body(Body::empty())
+    }
+    fn anonymous(req: &mut dyn RequestExt) -> HttpResult {
+        assert_eq!(req.remembered_user(), None);
+        Response::builder().body(Body::empty())
+    }
+
+    #[test]
+    fn rotates_tokens() {
+        let store = Arc::new(Store::default());
+        let app = |handler| app_with_grace_period(&store, Duration::ZERO, handler);
+
+        let mut req = MockRequest::new(Method::POST, "/login");
+        let response = app(login).call(&mut req).unwrap();
+        let first = cookie(&response);
+
+        req.header(header::COOKIE, &first);
+        let response = app(check).call(&mut req).unwrap();
+        let second = cookie(&response);
+        assert_ne!(first, second);
+
+        // Replaying the first token revokes the whole series
+        req.header(header::COOKIE, &first);
+        let response = app(anonymous).call(&mut req).unwrap();
+        assert!(cookie(&response).ends_with("remember_me="));
+        req.header(header::COOKIE, &second);
+        app(anonymous).call(&mut req).unwrap();
+    }
+
+    #[test]
+    fn parallel_requests() {
+        let store = Arc::new(Store::default());
+
+        let mut req = MockRequest::new(Method::POST, "/login");
+        let response = app(&store, login).call(&mut req).unwrap();
+        let first = cookie(&response);
+
+        // Two requests with the same cookie, e.g. from two tabs
+        req.header(header::COOKIE, &first);
+        let response = app(&store, check).call(&mut req).unwrap();
+        let second = cookie(&response);
+        let response = app(&store, check).call(&mut req).unwrap();
+        assert_eq!(cookie(&response), second);
+
+        req.header(header::COOKIE, &second);
+        app(&store, check).call(&mut req).unwrap();
+    }
+
+    #[test]
+    fn rotates_only_on_login() {
+        let store = Arc::new(Store::default());
+
+        let mut req = MockRequest::new(Method::POST, "/login");
+        let response = app(&store, login).call(&mut req).unwrap();
+        req.header(header::COOKIE, &cookie(&response));
+
+        // Neither requests that don't use the cookie, nor failed ones
+        let response = app(&store, unused).call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+        let response = app(&store, failed).call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        fn unused(_: &mut dyn RequestExt) -> HttpResult {
+            Response::builder().body(Body::empty())
+        }
+        fn failed(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.remembered_user(), Some("alice"));
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn store_failure() {
+        let store = Arc::new(Store::default());
+
+        let mut req = MockRequest::new(Method::POST, "/login");
+        let response = app(&store, login).call(&mut req).unwrap();
+        req.header(header::COOKIE, &cookie(&response));
+
+        store.down.store(true, Ordering::SeqCst);
+        let response = app(&store, check).call(&mut req).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+        let response = app(&store, login).call(&mut MockRequest::new(Method::POST, "/login"));
+        assert_eq!(response.unwrap().status(), StatusCode::OK);
+    }
+}
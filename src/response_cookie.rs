@@ -0,0 +1,55 @@
+use cookie::time::Duration;
+use cookie::{Cookie, CookieJar, SameSite};
+
+/// Builds a cookie to be sent with the response, see
+/// `RequestCookies::response_cookie`.
+///
+/// Attributes that aren't set here are filled in from the `CookieDefaults`
+/// of the cookie `Middleware`.
+#[must_use = "the cookie is only sent once `add` is called"]
+pub struct ResponseCookieBuilder<'a> {
+    jar: &'a mut CookieJar,
+    cookie: Cookie<'static>,
+}
+
+impl<'a> ResponseCookieBuilder<'a> {
+    pub(crate) fn new(jar: &'a mut CookieJar, name: &str, value: &str) -> Self {
+        let cookie = Cookie::new(name.to_string(), value.to_string());
+        ResponseCookieBuilder { jar, cookie }
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.cookie.set_path(path.to_string());
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.cookie.set_domain(domain.to_string());
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.cookie.set_max_age(max_age);
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.cookie.set_secure(secure);
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.cookie.set_http_only(http_only);
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.cookie.set_same_site(same_site);
+        self
+    }
+
+    /// Adds the cookie to the jar, so that it is sent with the response.
+    pub fn add(self) {
+        self.jar.add(self.cookie);
+    }
+}
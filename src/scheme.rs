@@ -0,0 +1,205 @@
+use std::net::IpAddr;
+
+use conduit::{header, RequestExt, Scheme};
+use conduit_middleware::BeforeResult;
+
+/// Resolves the scheme the client used to reach the app, honoring the
+/// `Forwarded` and `X-Forwarded-Proto` headers of trusted reverse proxies.
+///
+/// The result is available via `req.effective_scheme()`, and is used by
+/// `SessionMiddlewareBuilder::auto_secure`.
+#[derive(Default)]
+pub struct SchemeMiddleware {
+    trusted_proxies: Vec<(IpAddr, u8)>,
+}
+
+struct EffectiveScheme(Scheme);
+
+impl SchemeMiddleware {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Trusts forwarding headers set by proxies in `cidr`, e.g. `10.0.0.0/8`
+    /// or `::1`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cidr` isn't a valid IP address or network.
+    pub fn trust_proxy(mut self, cidr: &str) -> Self {
+        let network = parse_cidr(cidr).unwrap_or_else(|| panic!("invalid CIDR `{}`", cidr));
+        self.trusted_proxies.push(network);
+        self
+    }
+
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|&(network, prefix_len)| in_network(addr, network, prefix_len))
+    }
+}
+
+fn parse_cidr(cidr: &str) -> Option<(IpAddr, u8)> {
+    let (addr, prefix_len) = match cidr.split_once('/') {
+        Some((addr, prefix_len)) => (addr.parse().ok()?, Some(prefix_len.parse().ok()?)),
+        None => (cidr.parse().ok()?, None),
+    };
+    let max_len = match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    let prefix_len = prefix_len.unwrap_or(max_len);
+    if prefix_len > max_len {
+        return None;
+    }
+    Some((addr, prefix_len))
+}
+
+fn in_network(addr: IpAddr, network: IpAddr, prefix_len: u8) -> bool {
+    match (addr, network) {
+        (IpAddr::V4(addr), IpAddr::V4(network)) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix_len))
+                .unwrap_or(0);
+            u32::from(addr) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(addr), IpAddr::V6(network)) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix_len))
+                .unwrap_or(0);
+            u128::from(addr) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+/// The scheme the client used according to the forwarding headers,
+/// preferring the standard `Forwarded` header.
+pub(crate) fn forwarded_scheme(req: &dyn RequestExt) -> Option<Scheme> {
+    match forwarded_proto(req)? {
+        proto if proto.eq_ignore_ascii_case("https") => Some(Scheme::Https),
+        proto if proto.eq_ignore_ascii_case("http") => Some(Scheme::Http),
+        _ => None,
+    }
+}
+
+/// The scheme resolved by `SchemeMiddleware`, if it is installed.
+#[cfg(feature = "session")]
+pub(crate) fn resolved_scheme(req: &dyn RequestExt) -> Option<Scheme> {
+    req.extensions()
+        .get::<EffectiveScheme>()
+        .map(|scheme| scheme.0)
+}
+
+/// The last element of a comma-separated header, across all its lines.
+fn last_element<'a>(req: &'a dyn RequestExt, name: &str) -> Option<&'a str> {
+    let values = req.headers().get_all(name).iter();
+    let values = values.filter_map(|value| value.to_str().ok());
+    values.flat_map(|value| value.split(',')).last()
+}
+
+fn forwarded_proto(req: &dyn RequestExt) -> Option<String> {
+    // Proxies append to these headers, so only the last element was added
+    // by the trusted proxy connected to us. Earlier ones may come from the
+    // client.
+    let proto = last_element(req, header::FORWARDED.as_str()).and_then(|last| {
+        last.split(';').find_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            let name = name.trim();
+            name.eq_ignore_ascii_case("proto")
+                .then(|| value.trim().trim_matches('"').to_string())
+        })
+    });
+    proto.or_else(|| Some(last_element(req, "x-forwarded-proto")?.trim().to_string()))
+}
+
+impl conduit_middleware::Middleware for SchemeMiddleware {
+    fn before(&self, req: &mut dyn RequestExt) -> BeforeResult {
+        let forwarded = match self.is_trusted(req.remote_addr().ip()) {
+            true => forwarded_scheme(req),
+            false => None,
+        };
+        let scheme = forwarded.unwrap_or_else(|| req.scheme());
+        req.mut_extensions().insert(EffectiveScheme(scheme));
+        Ok(())
+    }
+}
+
+pub trait RequestScheme {
+    /// The scheme resolved by `SchemeMiddleware`, or the scheme of the
+    /// connection if it isn't installed.
+    fn effective_scheme(&self) -> Scheme;
+}
+
+impl<T: RequestExt + ?Sized> RequestScheme for T {
+    fn effective_scheme(&self) -> Scheme {
+        match self.extensions().get::<EffectiveScheme>() {
+            Some(scheme) => scheme.0,
+            None => self.scheme(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use conduit::{Body, Handler, HttpResult, Method, RequestExt, Response, Scheme};
+    use conduit_middleware::MiddlewareBuilder;
+    use conduit_test::MockRequest;
+
+    use super::{RequestScheme, SchemeMiddleware};
+
+    fn scheme(proxy: &str, headers: &[(&'static str, &str)]) -> Scheme {
+        let mut req = MockRequest::new(Method::GET, "/");
+        for (name, value) in headers {
+            req.header(*name, value);
+        }
+        let mut app = MiddlewareBuilder::new(handler);
+        app.add(SchemeMiddleware::new().trust_proxy(proxy));
+        let response = app.call(&mut req).unwrap();
+        match response.headers().get("x-scheme").unwrap().as_bytes() {
+            b"https" => Scheme::Https,
+            _ => Scheme::Http,
+        }
+    }
+
+    fn handler(req: &mut dyn RequestExt) -> HttpResult {
+        let scheme = match req.effective_scheme() {
+            Scheme::Https => "https",
+            Scheme::Http => "http",
+        };
+        Response::builder()
+            .header("x-scheme", scheme)
+            .body(Body::empty())
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        let forwarded = [("x-forwarded-proto", "https")];
+        assert_eq!(scheme("127.0.0.0/8", &[]), Scheme::Http);
+        assert_eq!(scheme("127.0.0.0/8", &forwarded), Scheme::Https);
+        assert_eq!(scheme("127.0.0.1", &forwarded), Scheme::Https);
+        assert_eq!(scheme("10.0.0.0/8", &forwarded), Scheme::Http);
+        assert_eq!(scheme("::1", &forwarded), Scheme::Http);
+
+        let forwarded = [
+            (
+                "forwarded",
+                "for=1.2.3.4;proto=http, for=10.0.0.1;proto=https",
+            ),
+            ("x-forwarded-proto", "http"),
+        ];
+        assert_eq!(scheme("127.0.0.1/32", &forwarded), Scheme::Https);
+    }
+
+    #[test]
+    fn spoofed_proto() {
+        // The client sent `proto=https`, which the proxy appended to
+        let forwarded = [(
+            "forwarded",
+            "for=1.2.3.4;proto=https, for=10.0.0.1;proto=http",
+        )];
+        assert_eq!(scheme("127.0.0.1", &forwarded), Scheme::Http);
+        let forwarded = [("x-forwarded-proto", "https, http")];
+        assert_eq!(scheme("127.0.0.1", &forwarded), Scheme::Http);
+    }
+}
@@ -1,217 +1,4534 @@
-use base64::{decode, encode};
-use std::collections::HashMap;
+use base64::{decode, decode_config, encode, encode_config, URL_SAFE_NO_PAD};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{hash_map, HashMap};
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
 use std::str;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 
-use conduit::RequestExt;
+use conduit::header::{self, HeaderValue};
+use conduit::{box_error, Body, BoxError, HeaderMap, RequestExt, Response, Scheme, StatusCode};
 use conduit_middleware::{AfterResult, BeforeResult};
-use cookie::{time::Duration, Cookie, Key, SameSite};
+use cookie::time::{Duration, OffsetDateTime};
+use cookie::{Cookie, CookieJar, Key, SameSite};
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Serialize};
 
-use super::RequestCookies;
+use crate::format::EncodeOptions;
+#[cfg(feature = "jwt")]
+use crate::jwt;
+use crate::{binding, scheme, BypassFn};
+use crate::{
+    Clock, CookieMetrics, Error, ErrorPolicy, KeyProvider, KeySource, RequestCookies, SessionCodec,
+    SessionFormat, SessionHooks, SessionImporter, SessionStore, Signer, SizePolicy,
+    StoreFailurePolicy, SystemClock,
+};
 
-const MAX_AGE_DAYS: i64 = 90;
+const DEFAULT_MAX_AGE_DAYS: i64 = 90;
+
+const HOST_PREFIX: &str = "__Host-";
+const SECURE_PREFIX: &str = "__Secure-";
+
+// Session cookies longer than this are split across several cookies, leaving
+// room for the name and attributes within the browser's 4096 byte limit.
+const CHUNK_SIZE: usize = 3800;
+
+// Metadata stored alongside the session data. The leading NUL keeps them
+// from colliding with application keys.
+const ID_KEY: &str = "\0id";
+const CREATED_KEY: &str = "\0created";
+const EXPIRES_KEY: &str = "\0expires";
+const ISSUED_KEY: &str = "\0issued";
+const BINDING_KEY: &str = "\0binding";
+const COUNTER_KEY: &str = "\0counter";
+const TENANT_KEY: &str = "\0tenant";
+// Followed by the key of a value set with `session_set_with_ttl`.
+const VALUE_EXPIRES_PREFIX: &str = "\0expires:";
+
+// Marks cookie values embedding the session data, written while the store
+// is failing. Session ids never contain it.
+const EMBEDDED_PREFIX: &str = "~";
+
+// Tells apart the sessions of several middleware for the same cookie.
+static NEXT_MIDDLEWARE_ID: AtomicUsize = AtomicUsize::new(0);
 
 pub struct SessionMiddleware {
+    id: usize,
     cookie_name: String,
-    key: Key,
+    keys: Box<dyn KeyProvider>,
+    old_keys: Vec<Key>,
+    signer: Option<Box<dyn Signer>>,
     secure: bool,
+    auto_secure: Option<bool>,
+    http_only: bool,
+    same_site: SameSite,
+    path: String,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    encrypted: bool,
+    #[cfg(feature = "jwt")]
+    jwt: bool,
+    store: Option<Box<dyn SessionStore>>,
+    store_failure_policy: StoreFailurePolicy,
+    max_size: Option<usize>,
+    size_policy: SizePolicy,
+    format: SessionFormat,
+    migrate: Option<MigrateFn>,
+    prune: Option<PruneFn>,
+    codec: Option<Box<dyn SessionCodec>>,
+    lifetime: Option<Duration>,
+    clock_skew: Duration,
+    refresh_threshold: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    bind: Option<BindFn>,
+    bypass: Option<BypassFn>,
+    sample: Option<BypassFn>,
+    vary: Option<HeaderValue>,
+    #[cfg(feature = "compression")]
+    compress_threshold: Option<usize>,
+    url_safe: bool,
+    error_policy: ErrorPolicy,
+    metrics: Option<Box<dyn CookieMetrics>>,
+    hooks: Option<Box<dyn SessionHooks>>,
+    importers: Vec<(String, Box<dyn SessionImporter>)>,
+    legacy: Option<(Box<dyn SessionImporter>, OffsetDateTime)>,
+    counters: Option<Box<dyn SessionStore>>,
+    partitioned: bool,
+    clock: Arc<dyn Clock>,
+}
+
+type BindFn = Box<dyn Fn(&dyn RequestExt) -> String + Send + Sync>;
+
+type MigrateFn = Box<dyn Fn(SessionFormat, &mut HashMap<String, String>) + Send + Sync>;
+
+type PruneFn = Box<dyn Fn(&mut HashMap<String, String>) + Send + Sync>;
+
+type CommitFn = Box<dyn FnOnce(&mut HashMap<String, String>) + Send + Sync>;
+
+pub struct SessionMiddlewareBuilder {
+    middleware: SessionMiddleware,
+    host_prefix: bool,
 }
 
 pub struct Session {
-    data: HashMap<String, String>,
+    // Shared with the `SessionView` snapshot, and copied on the first write
+    data: Arc<HashMap<String, String>>,
+    id: String,
+    stale_id: Option<String>,
+    created: OffsetDateTime,
+    expires: Option<OffsetDateTime>,
+    issued: Option<OffsetDateTime>,
+    binding: Option<String>,
+    tenant: Option<String>,
+    counter: Option<u64>,
+    value_expiry: HashMap<String, OffsetDateTime>,
+    cleared: bool,
+    fingerprint: u64,
     dirty: bool,
+    suppressed: bool,
+    info: SessionInfo,
+    state: SessionState,
+    volatile: HashMap<String, String>,
+    clock: Arc<dyn Clock>,
+    // Shared with the views of the session, which also set it when read
+    accessed: Arc<AtomicBool>,
+    commits: Vec<CommitFn>,
+    // The id of the middleware that loaded the session, and whether it has
+    // yet to write it
+    owner: usize,
+    pending: bool,
 }
 
-impl SessionMiddleware {
-    pub fn new(cookie: &str, key: Key, secure: bool) -> SessionMiddleware {
-        SessionMiddleware {
-            cookie_name: cookie.to_string(),
-            key,
-            secure,
+/// The tenant a request belongs to, inserted into the request extensions
+/// by an earlier middleware in apps serving many tenants.
+///
+/// `SessionMiddleware` embeds the tenant in the signed session, and
+/// discards sessions issued for another tenant, or without one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionTenant(pub String);
+
+/// What `SessionMiddleware` found in the request, e.g. for logging the rate
+/// of tampered or corrupt session cookies.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SessionInfo {
+    /// Whether the request carried the session cookie.
+    pub cookie_present: bool,
+    /// Whether the cookie failed signature verification or decryption with
+    /// all configured keys.
+    pub invalid_signature: bool,
+    /// Length of the cookie value in bytes.
+    pub payload_size: usize,
+    /// Why the verified cookie value couldn't be decoded, if it couldn't.
+    pub decode_error: Option<String>,
+    /// Why the session store failed to load the session, if it did.
+    pub store_error: Option<String>,
+}
+
+/// Where the session of a request came from, see
+/// `RequestSession::session_state`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionState {
+    /// There was no usable session cookie, or its session was expired or
+    /// otherwise discarded.
+    New,
+    /// The session was restored from the cookie.
+    Restored,
+    /// The cookie failed signature verification or decryption, e.g.
+    /// because it was tampered with or the keys were rotated.
+    InvalidSignature,
+    /// The cookie verified, but its payload couldn't be decoded.
+    CorruptPayload,
+    /// The session store failed to load the session, which was handled
+    /// according to the policy.
+    StoreFailed(StoreFailurePolicy),
+}
+
+impl Session {
+    fn new(data: HashMap<String, String>, clock: Arc<dyn Clock>) -> Session {
+        Session {
+            fingerprint: fingerprint(&data),
+            data: Arc::new(data),
+            id: new_session_id(),
+            stale_id: None,
+            created: clock.now(),
+            expires: None,
+            issued: None,
+            binding: None,
+            tenant: None,
+            counter: None,
+            value_expiry: HashMap::new(),
+            cleared: false,
+            dirty: false,
+            suppressed: false,
+            info: SessionInfo::default(),
+            state: SessionState::New,
+            volatile: HashMap::new(),
+            clock,
+            accessed: Arc::default(),
+            commits: Vec::new(),
+            owner: 0,
+            pending: false,
         }
     }
 
-    pub fn decode(cookie: Cookie<'_>) -> HashMap<String, String> {
-        let mut ret = HashMap::new();
-        let bytes = decode(cookie.value().as_bytes()).unwrap_or_default();
-        let mut parts = bytes.split(|&a| a == 0xff);
-        while let (Some(key), Some(value)) = (parts.next(), parts.next()) {
-            if key.is_empty() {
-                break;
-            }
-            if let (Ok(key), Ok(value)) = (str::from_utf8(key), str::from_utf8(value)) {
-                ret.insert(key.to_string(), value.to_string());
+    /// Restores a session from a decoded cookie, splitting off the metadata
+    /// entries written by `to_map`.
+    fn from_map(mut data: HashMap<String, String>, clock: Arc<dyn Clock>) -> Session {
+        let id = data.remove(ID_KEY);
+        let created = parse_timestamp(data.remove(CREATED_KEY));
+        let expires = parse_timestamp(data.remove(EXPIRES_KEY));
+        let issued = parse_timestamp(data.remove(ISSUED_KEY));
+        let binding = data.remove(BINDING_KEY);
+        let tenant = data.remove(TENANT_KEY);
+        let counter = data.remove(COUNTER_KEY).and_then(|c| c.parse().ok());
+        let expiring = data
+            .keys()
+            .filter(|key| key.starts_with(VALUE_EXPIRES_PREFIX))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut value_expiry = HashMap::new();
+        for expires_key in expiring {
+            let expires = parse_timestamp(data.remove(&expires_key));
+            let key = &expires_key[VALUE_EXPIRES_PREFIX.len()..];
+            match expires {
+                Some(expires) => {
+                    value_expiry.insert(key.to_string(), expires);
+                }
+                None => {
+                    data.remove(key);
+                }
             }
         }
-        ret
+        let mut session = Session::new(data, clock);
+        session.value_expiry = value_expiry;
+        if let Some(id) = id {
+            session.id = id;
+        }
+        if let Some(created) = created {
+            session.created = created;
+        }
+        session.expires = expires;
+        session.issued = issued;
+        session.binding = binding;
+        session.tenant = tenant;
+        session.counter = counter;
+        session
     }
 
-    pub fn encode(h: &HashMap<String, String>) -> String {
-        let mut ret = Vec::new();
-        for (i, (k, v)) in h.iter().enumerate() {
-            if i != 0 {
-                ret.push(0xff)
+    fn to_map(&self) -> HashMap<String, String> {
+        let mut data = (*self.data).clone();
+        data.insert(ID_KEY.to_string(), self.id.clone());
+        data.insert(
+            CREATED_KEY.to_string(),
+            self.created.unix_timestamp().to_string(),
+        );
+        if let Some(expires) = self.expires {
+            data.insert(
+                EXPIRES_KEY.to_string(),
+                expires.unix_timestamp().to_string(),
+            );
+        }
+        if let Some(issued) = self.issued {
+            data.insert(ISSUED_KEY.to_string(), issued.unix_timestamp().to_string());
+        }
+        if let Some(binding) = &self.binding {
+            data.insert(BINDING_KEY.to_string(), binding.clone());
+        }
+        if let Some(tenant) = &self.tenant {
+            data.insert(TENANT_KEY.to_string(), tenant.clone());
+        }
+        if let Some(counter) = self.counter {
+            data.insert(COUNTER_KEY.to_string(), counter.to_string());
+        }
+        for (key, expires) in &self.value_expiry {
+            if self.data.contains_key(key) {
+                let expires_key = format!("{VALUE_EXPIRES_PREFIX}{key}");
+                data.insert(expires_key, expires.unix_timestamp().to_string());
             }
-            ret.extend(k.bytes());
-            ret.push(0xff);
-            ret.extend(v.bytes());
         }
-        while ret.len() * 8 % 6 != 0 {
-            ret.push(0xff);
+        data
+    }
+
+    /// The session data, noting that the response depends on it.
+    fn data(&self) -> &HashMap<String, String> {
+        self.accessed.store(true, Ordering::Relaxed);
+        &self.data
+    }
+
+    fn data_mut(&mut self) -> &mut HashMap<String, String> {
+        self.accessed.store(true, Ordering::Relaxed);
+        Arc::make_mut(&mut self.data)
+    }
+
+    /// A snapshot of the session data, sharing it until either is changed.
+    fn view(&self) -> SessionView {
+        self.accessed.store(true, Ordering::Relaxed);
+        SessionView {
+            data: self.data.clone(),
+            accessed: self.accessed.clone(),
         }
-        encode(&ret[..])
     }
-}
 
-impl conduit_middleware::Middleware for SessionMiddleware {
-    fn before(&self, req: &mut dyn RequestExt) -> BeforeResult {
-        let session = {
-            let jar = req.cookies_mut().signed(&self.key);
-            jar.get(&self.cookie_name)
-                .map(Self::decode)
-                .unwrap_or_else(HashMap::new)
-        };
-        req.mut_extensions().insert(Session {
-            data: session,
-            dirty: false,
-        });
-        Ok(())
+    fn into_data(self) -> HashMap<String, String> {
+        Arc::try_unwrap(self.data).unwrap_or_else(|data| (*data).clone())
     }
 
-    fn after(&self, req: &mut dyn RequestExt, res: AfterResult) -> AfterResult {
-        let session = req.extensions().get::<Session>();
-        let session = session.expect("session must be present after request");
-        if session.dirty {
-            let encoded = Self::encode(&session.data);
-            let cookie = Cookie::build(self.cookie_name.to_string(), encoded)
-                .http_only(true)
-                .secure(self.secure)
-                .same_site(SameSite::Strict)
-                .max_age(Duration::days(MAX_AGE_DAYS))
-                .path("/")
-                .finish();
-            req.cookies_mut().signed_mut(&self.key).add(cookie);
+    fn is_expired(&self, leeway: Duration) -> bool {
+        matches!(self.expires, Some(expires) if has_expired(expires, leeway, self.clock.now()))
+    }
+
+    /// Removes the values of `session_set_with_ttl` that expired, without
+    /// counting as a change.
+    fn remove_expired_values(&mut self, leeway: Duration) {
+        let now = self.clock.now();
+        let expired = self
+            .value_expiry
+            .iter()
+            .filter(|(_, expires)| has_expired(**expires, leeway, now))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        if expired.is_empty() {
+            return;
         }
-        res
+        let data = Arc::make_mut(&mut self.data);
+        for key in expired {
+            data.remove(&key);
+            self.value_expiry.remove(&key);
+        }
+        self.fingerprint = fingerprint(&self.data);
+    }
+
+    fn regenerate(&mut self) {
+        let id = std::mem::replace(&mut self.id, new_session_id());
+        self.stale_id.get_or_insert(id);
+        self.created = self.clock.now();
+        self.dirty = true;
+    }
+
+    fn clear(&mut self) {
+        self.data = Arc::default();
+        self.value_expiry.clear();
+        self.regenerate();
+        self.cleared = true;
+    }
+
+    /// Whether the session cookie will be written with the response, either
+    /// because the data changed or a write was forced.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty || fingerprint(&self.data) != self.fingerprint
+    }
+
+    /// Skips writing the session cookie, keeping any changes made so far
+    /// only for the rest of this request.
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+        self.fingerprint = fingerprint(&self.data);
+    }
+
+    /// Writes the session cookie even if the data is unchanged, refreshing
+    /// its Max-Age.
+    pub fn force_write(&mut self) {
+        self.dirty = true;
     }
 }
 
-pub trait RequestSession {
-    fn session(&self) -> &HashMap<String, String>;
-    fn session_mut(&mut self) -> &mut HashMap<String, String>;
+/// A read-only snapshot of session data, see `RequestSession::session_view`.
+///
+/// `SessionMiddleware` also inserts a snapshot of the default session as
+/// loaded into the request extensions, so that later middleware can read it
+/// without borrowing the session or marking it dirty. Reading a view counts
+/// as reading the session for `SessionMiddlewareBuilder::vary`.
+#[derive(Clone, Debug, Default)]
+pub struct SessionView {
+    data: Arc<HashMap<String, String>>,
+    accessed: Arc<AtomicBool>,
 }
 
-impl<T: RequestExt + ?Sized> RequestSession for T {
-    fn session(&self) -> &HashMap<String, String> {
-        &self
-            .extensions()
-            .get::<Session>()
-            .expect("missing cookie session")
-            .data
+impl Deref for SessionView {
+    type Target = HashMap<String, String>;
+
+    fn deref(&self) -> &Self::Target {
+        self.accessed.store(true, Ordering::Relaxed);
+        &self.data
     }
+}
 
-    fn session_mut(&mut self) -> &mut HashMap<String, String> {
-        let session = self
-            .mut_extensions()
-            .get_mut::<Session>()
-            .expect("missing cookie session");
-        session.dirty = true;
-        &mut session.data
+impl PartialEq for SessionView {
+    fn eq(&self, other: &Self) -> bool {
+        self.data == other.data
     }
 }
 
-#[cfg(test)]
-mod test {
-    use std::collections::HashMap;
+impl Eq for SessionView {}
 
-    use conduit::{header, Body, Handler, HttpResult, Method, RequestExt, Response};
-    use conduit_middleware::MiddlewareBuilder;
-    use conduit_test::MockRequest;
-    use cookie::{Cookie, Key};
+/// Per-request settings for the session, see `RequestSession::session_options`.
+pub struct SessionOptions<'a> {
+    session: &'a mut Session,
+}
+
+impl SessionOptions<'_> {
+    /// Never writes the session cookie for this request, even if the session
+    /// was modified or a write was forced.
+    pub fn suppress_write(self) {
+        self.session.suppressed = true;
+    }
+}
 
-    use crate::{Middleware, RequestSession, SessionMiddleware};
+/// Signs `data` into a session cookie named `name`, as read by a
+/// `SessionMiddleware` with default settings, e.g. to mint sessions in
+/// background jobs or CLI tools.
+pub fn encode_session(data: &HashMap<String, String>, key: &Key, name: &str) -> Cookie<'static> {
+    let value = SessionFormat::default().encode(data, EncodeOptions::default());
+    let mut jar = cookie::CookieJar::new();
+    jar.signed_mut(key)
+        .add(Cookie::new(name.to_string(), value));
+    jar.get(name).unwrap().clone()
+}
 
-    fn test_key() -> Key {
-        let master_key: Vec<u8> = (0..32).collect();
-        Key::derive_from(&master_key)
+/// Verifies and decodes the value of a signed session cookie named `name`.
+/// Returns `None` if the session has expired.
+pub fn decode_session(
+    value: &str,
+    key: &Key,
+    name: &str,
+) -> Result<Option<HashMap<String, String>>, Error> {
+    let mut jar = cookie::CookieJar::new();
+    jar.add_original(Cookie::new(name.to_string(), value.to_string()));
+    let cookie = jar.signed(key).get(name);
+    let cookie = cookie.ok_or_else(|| Error::InvalidSession("invalid signature".to_string()))?;
+    let (_, data) = SessionFormat::decode(cookie.value())?;
+    let mut session = Session::from_map(data, Arc::new(SystemClock));
+    if session.is_expired(Duration::ZERO) {
+        return Ok(None);
     }
+    session.remove_expired_values(Duration::ZERO);
+    Ok(Some(session.into_data()))
+}
 
-    #[test]
-    fn simple() {
-        let mut req = MockRequest::new(Method::POST, "/articles");
-        let key = test_key();
+/// An order-independent hash of the session data, used to detect changes.
+fn fingerprint(data: &HashMap<String, String>) -> u64 {
+    let mut entries = data.iter().collect::<Vec<_>>();
+    entries.sort();
+    let mut hasher = DefaultHasher::new();
+    entries.hash(&mut hasher);
+    hasher.finish()
+}
 
-        // Set the session cookie
-        let mut app = MiddlewareBuilder::new(set_session);
-        app.add(Middleware::new());
-        app.add(SessionMiddleware::new("lol", key, false));
-        let response = app.call(&mut req).unwrap();
+/// All sessions of a request, keyed by cookie name. The session of the
+/// first installed `SessionMiddleware` is the default one.
+#[derive(Default)]
+struct Sessions {
+    default: Option<String>,
+    named: HashMap<String, Session>,
+}
 
-        let v = response
-            .headers()
-            .get(header::SET_COOKIE)
-            .unwrap()
-            .to_str()
-            .unwrap();
-        assert!(v.starts_with("lol"));
+/// The data of each session of the request, sorted by cookie name, without
+/// counting as a read for `SessionMiddlewareBuilder::vary`.
+#[cfg(feature = "debug")]
+pub(crate) fn sessions(req: &dyn RequestExt) -> Vec<(&str, &HashMap<String, String>)> {
+    let sessions = req.extensions().get::<Sessions>();
+    let mut sessions = sessions
+        .iter()
+        .flat_map(|sessions| &sessions.named)
+        .map(|(name, session)| (name.as_str(), &*session.data))
+        .collect::<Vec<_>>();
+    sessions.sort_by_key(|(name, _)| *name);
+    sessions
+}
 
-        // Use the session cookie
-        req.header(header::COOKIE, v);
-        let key = test_key();
-        let mut app = MiddlewareBuilder::new(use_session);
-        app.add(Middleware::new());
-        app.add(SessionMiddleware::new("lol", key, false));
-        assert!(app.call(&mut req).is_ok());
+fn try_state<'a, R: RequestExt + ?Sized>(req: &'a R, name: Option<&str>) -> Option<&'a Session> {
+    let sessions = req.extensions().get::<Sessions>()?;
+    let name = name.or(sessions.default.as_deref())?;
+    sessions.named.get(name)
+}
 
-        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
-            assert!(req
-                .session_mut()
-                .insert("foo".to_string(), "bar".to_string())
-                .is_none());
-            Response::builder().body(Body::empty())
+fn try_state_mut<'a, R: RequestExt + ?Sized>(
+    req: &'a mut R,
+    name: Option<&str>,
+) -> Option<&'a mut Session> {
+    let sessions = req.mut_extensions().get_mut::<Sessions>()?;
+    let name = name.or(sessions.default.as_deref())?.to_string();
+    sessions.named.get_mut(&name)
+}
+
+fn state<R: RequestExt + ?Sized>(req: &R) -> &Session {
+    try_state(req, None).expect("missing cookie session")
+}
+
+fn state_mut<R: RequestExt + ?Sized>(req: &mut R) -> &mut Session {
+    try_state_mut(req, None).expect("missing cookie session")
+}
+
+/// Appends `name` to the `Vary` header unless it is already listed.
+fn add_vary(headers: &mut HeaderMap, name: &HeaderValue) {
+    let listed = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|value| value == "*" || value.as_bytes().eq_ignore_ascii_case(name.as_bytes()));
+    if !listed {
+        headers.append(header::VARY, name.clone());
+    }
+}
+
+/// Whether something valid until `expires` has expired at `now`, allowing
+/// `leeway` for clock skew. Like the stores, it expires at `expires` itself.
+fn has_expired(expires: OffsetDateTime, leeway: Duration, now: OffsetDateTime) -> bool {
+    expires + leeway <= now
+}
+
+fn parse_timestamp(value: Option<String>) -> Option<OffsetDateTime> {
+    let timestamp = value?.parse().ok()?;
+    OffsetDateTime::from_unix_timestamp(timestamp).ok()
+}
+
+fn new_session_id() -> String {
+    let bytes: [u8; 32] = rand::random();
+    encode_config(bytes, URL_SAFE_NO_PAD)
+}
+
+impl SessionMiddleware {
+    pub fn new(cookie: &str, key: Key, secure: bool) -> SessionMiddleware {
+        Self::builder(cookie, key).secure(secure).build()
+    }
+
+    pub fn encrypted(cookie: &str, key: Key, secure: bool) -> SessionMiddleware {
+        Self::builder(cookie, key)
+            .secure(secure)
+            .encrypted(true)
+            .build()
+    }
+
+    /// Like `builder`, loading the key from `source`.
+    pub fn builder_from(
+        cookie: &str,
+        source: KeySource,
+    ) -> Result<SessionMiddlewareBuilder, Error> {
+        Ok(Self::builder(cookie, source.load()?))
+    }
+
+    pub fn builder(cookie: &str, key: Key) -> SessionMiddlewareBuilder {
+        let middleware = SessionMiddleware {
+            id: NEXT_MIDDLEWARE_ID.fetch_add(1, Ordering::Relaxed),
+            cookie_name: cookie.to_string(),
+            keys: Box::new(key),
+            old_keys: Vec::new(),
+            signer: None,
+            secure: false,
+            auto_secure: None,
+            http_only: true,
+            same_site: SameSite::Strict,
+            path: "/".to_string(),
+            domain: None,
+            max_age: Some(Duration::days(DEFAULT_MAX_AGE_DAYS)),
+            encrypted: false,
+            #[cfg(feature = "jwt")]
+            jwt: false,
+            store: None,
+            store_failure_policy: StoreFailurePolicy::default(),
+            max_size: None,
+            size_policy: SizePolicy::default(),
+            format: SessionFormat::default(),
+            migrate: None,
+            prune: None,
+            codec: None,
+            lifetime: None,
+            clock_skew: Duration::ZERO,
+            refresh_threshold: None,
+            idle_timeout: None,
+            bind: None,
+            bypass: None,
+            sample: None,
+            vary: None,
+            #[cfg(feature = "compression")]
+            compress_threshold: None,
+            url_safe: false,
+            error_policy: ErrorPolicy::default(),
+            metrics: None,
+            hooks: None,
+            importers: Vec::new(),
+            legacy: None,
+            counters: None,
+            partitioned: false,
+            clock: Arc::new(SystemClock),
+        };
+        SessionMiddlewareBuilder {
+            middleware,
+            host_prefix: false,
         }
-        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
-            assert_eq!(*req.session().get("foo").unwrap(), "bar");
-            Response::builder().body(Body::empty())
+    }
+
+    fn load_session(
+        &self,
+        cookie: Cookie<'_>,
+        info: &mut SessionInfo,
+    ) -> Result<Session, BoxError> {
+        #[cfg(feature = "jwt")]
+        if self.jwt {
+            return self.load_jwt(cookie.value(), info);
+        }
+        let store = match (&self.store, &self.codec) {
+            (Some(store), _) => store,
+            (None, Some(codec)) => {
+                let data = match codec.decode(cookie.value()) {
+                    Ok(data) => data,
+                    Err(error) => {
+                        self.undecodable(error, info)?;
+                        HashMap::new()
+                    }
+                };
+                return Ok(Session::from_map(data, self.clock.clone()));
+            }
+            (None, None) => {
+                let (format, mut data) = match SessionFormat::decode(cookie.value()) {
+                    Ok(decoded) => decoded,
+                    Err(error) => {
+                        self.undecodable(error, info)?;
+                        (self.format, HashMap::new())
+                    }
+                };
+                let outdated = format != self.format;
+                if let (true, Some(migrate)) = (outdated, &self.migrate) {
+                    migrate(format, &mut data);
+                }
+                let mut session = Session::from_map(data, self.clock.clone());
+                // Re-encode sessions written in another format
+                session.dirty = outdated;
+                return Ok(session);
+            }
+        };
+        match cookie.value().strip_prefix(EMBEDDED_PREFIX) {
+            Some(embedded) => self.load_embedded(embedded, info),
+            None => self.load_stored(store.as_ref(), cookie.value(), info),
         }
     }
 
-    #[test]
-    fn no_equals() {
-        let e = {
-            let mut map = HashMap::new();
-            map.insert("a".to_string(), "bc".to_string());
-            SessionMiddleware::encode(&map)
+    /// Loads a session embedded in the cookie while the store was failing,
+    /// to be moved back into the store.
+    fn load_embedded(&self, value: &str, info: &mut SessionInfo) -> Result<Session, BoxError> {
+        let data = match SessionFormat::decode(value) {
+            Ok((_, data)) => data,
+            Err(error) => {
+                self.undecodable(error, info)?;
+                HashMap::new()
+            }
         };
-        assert!(!e.ends_with('='));
+        let mut session = Session::from_map(data, self.clock.clone());
+        session.dirty = true;
+        Ok(session)
+    }
 
-        let m = SessionMiddleware::decode(Cookie::new("foo", e));
-        assert_eq!(*m.get("a").unwrap(), "bc");
+    /// A session with the data of the first foreign session cookie in `jar`
+    /// that an importer accepts.
+    fn import(&self, jar: &CookieJar) -> Option<Session> {
+        self.importers.iter().find_map(|(name, importer)| {
+            let data = importer.import(jar.get(name)?.value())?;
+            let mut session = Session::new(data, self.clock.clone());
+            session.dirty = true;
+            Some(session)
+        })
     }
 
-    #[test]
-    fn dirty_tracking() {
-        let mut req = MockRequest::new(Method::GET, "/");
+    /// Decodes a session cookie that failed verification with the decoder
+    /// of `SessionMiddlewareBuilder::legacy_until`, while it is accepted.
+    fn decode_legacy(&self, value: &str) -> Option<HashMap<String, String>> {
+        match &self.legacy {
+            Some((decoder, until)) if self.clock.now() < *until => decoder.import(value),
+            _ => None,
+        }
+    }
 
-        let mut app = MiddlewareBuilder::new(read_session);
-        app.add(Middleware::new());
-        app.add(SessionMiddleware::new("dirty", test_key(), false));
-        let response = app.call(&mut req).unwrap();
+    /// Makes `session` available to the request, and as the default
+    /// session if it is the first.
+    fn insert_session(&self, req: &mut dyn RequestExt, mut session: Session) {
+        session.owner = self.id;
+        session.pending = true;
+        let extensions = req.mut_extensions();
+        if extensions.get::<Sessions>().is_none() {
+            extensions.insert(Sessions::default());
+        }
+        let sessions = extensions.get_mut::<Sessions>().unwrap();
+        let default = sessions
+            .default
+            .get_or_insert_with(|| self.cookie_name.clone());
+        // Not `Session::view`, which would mark the session as read
+        let view = (*default == self.cookie_name).then(|| SessionView {
+            data: session.data.clone(),
+            accessed: session.accessed.clone(),
+        });
+        sessions.named.insert(self.cookie_name.clone(), session);
+        if let Some(view) = view {
+            extensions.insert(view);
+        }
+    }
 
-        assert!(response.headers().get(header::SET_COOKIE).is_none());
+    fn load_stored(
+        &self,
+        store: &dyn SessionStore,
+        id: &str,
+        info: &mut SessionInfo,
+    ) -> Result<Session, BoxError> {
+        // Unknown ids are never adopted, so a new id is issued instead
+        Ok(match store.load(id) {
+            Ok(Some(data)) => {
+                let mut session = Session::from_map(data, self.clock.clone());
+                session.id = id.to_string();
+                session
+            }
+            Ok(None) => Session::new(HashMap::new(), self.clock.clone()),
+            Err(error) => {
+                info.store_error = Some(error.to_string());
+                self.store_failed(error)?;
+                Session::new(HashMap::new(), self.clock.clone())
+            }
+        })
+    }
 
-        let mut app = MiddlewareBuilder::new(modify_session);
-        app.add(Middleware::new());
-        app.add(SessionMiddleware::new("dirty", test_key(), false));
-        let response = app.call(&mut req).unwrap();
+    /// Applies the store failure policy to `error`, failing unless it lets
+    /// the request continue.
+    fn store_failed(&self, error: BoxError) -> Result<(), BoxError> {
+        event!(WARN, cookie = %self.cookie_name, %error, "session store failed");
+        match self.store_failure_policy {
+            StoreFailurePolicy::Fail => Err(error),
+            StoreFailurePolicy::Unavailable => {
+                Err(Box::new(Error::StoreUnavailable(error.to_string())))
+            }
+            StoreFailurePolicy::Anonymous | StoreFailurePolicy::Cookie => Ok(()),
+        }
+    }
 
-        assert!(response.headers().get(header::SET_COOKIE).is_some());
+    /// Loads the session from verified JWT claims, which only hold the
+    /// session id if a store is configured.
+    #[cfg(feature = "jwt")]
+    fn load_jwt(&self, claims: &str, info: &mut SessionInfo) -> Result<Session, BoxError> {
+        let mut data = match jwt::decode_claims(claims) {
+            Ok(data) => data,
+            Err(error) => {
+                self.undecodable(error, info)?;
+                HashMap::new()
+            }
+        };
+        match (&self.store, data.remove(ID_KEY)) {
+            (Some(store), Some(id)) => self.load_stored(store.as_ref(), &id, info),
+            // Embedded while the store was failing
+            (Some(_), None) if !data.is_empty() => {
+                let mut session = Session::from_map(data, self.clock.clone());
+                session.dirty = true;
+                Ok(session)
+            }
+            (Some(_), None) => Ok(Session::new(HashMap::new(), self.clock.clone())),
+            (None, id) => {
+                data.extend(id.map(|id| (ID_KEY.to_string(), id)));
+                Ok(Session::from_map(data, self.clock.clone()))
+            }
+        }
+    }
 
-        fn read_session(req: &mut dyn RequestExt) -> HttpResult {
-            req.session();
-            Response::builder().body(Body::empty())
+    fn undecodable(&self, error: Error, info: &mut SessionInfo) -> Result<(), BoxError> {
+        event!(WARN, cookie = %self.cookie_name, %error, "undecodable session cookie");
+        info.decode_error = Some(error.to_string());
+        self.error_policy.handle(error)
+    }
+
+    /// The session data carried by the cookies in `jar`, without metadata.
+    #[cfg(feature = "test-helpers")]
+    pub(crate) fn session_data(&self, jar: &CookieJar) -> Option<HashMap<String, String>> {
+        let mut info = SessionInfo::default();
+        let (cookie, _) = self.read_cookie(jar, &mut info)?;
+        let session = self.load_session(cookie, &mut info).ok()?;
+        Some(session.into_data())
+    }
+
+    fn encode_options(&self) -> EncodeOptions {
+        EncodeOptions {
+            #[cfg(feature = "compression")]
+            compress_threshold: self.compress_threshold,
+            #[cfg(not(feature = "compression"))]
+            compress_threshold: None,
+            url_safe: self.url_safe,
         }
-        fn modify_session(req: &mut dyn RequestExt) -> HttpResult {
-            req.session_mut();
+    }
+
+    fn is_secure(&self, req: &dyn RequestExt) -> bool {
+        let trust_forwarded_proto = match self.auto_secure {
+            Some(trust_forwarded_proto) => trust_forwarded_proto,
+            None => return self.secure,
+        };
+        let forwarded = match scheme::resolved_scheme(req) {
+            Some(scheme) => Some(scheme),
+            None if trust_forwarded_proto => scheme::forwarded_scheme(req),
+            None => None,
+        };
+        let scheme = forwarded.unwrap_or_else(|| req.scheme());
+        self.secure || matches!(scheme, Scheme::Https)
+    }
+
+    /// Whether the session wasn't used for longer than the idle timeout.
+    fn is_idle(&self, session: &Session) -> bool {
+        let now = self.clock.now();
+        matches!(
+            (self.idle_timeout, session.issued),
+            (Some(timeout), Some(issued)) if has_expired(issued + timeout, self.clock_skew, now)
+        )
+    }
+
+    fn needs_refresh(&self, session: &Session) -> bool {
+        if self.idle_timeout.is_some() {
+            return true;
+        }
+        let threshold = match self.refresh_threshold {
+            Some(threshold) => threshold,
+            None => return false,
+        };
+        match (session.issued, self.max_age) {
+            (Some(issued), Some(max_age)) => issued + max_age - self.clock.now() < threshold,
+            (None, Some(_)) => true,
+            (_, None) => false,
+        }
+    }
+
+    /// Encodes the session for the cookie, saving its data to the store if
+    /// one is configured. Returns `None` if the cookie shouldn't be written
+    /// because the store failed.
+    fn save_session(&self, session: &Session) -> Result<Option<String>, BoxError> {
+        let store = match &self.store {
+            Some(store) => store,
+            None => {
+                let data = session.to_map();
+                #[cfg(feature = "jwt")]
+                if self.jwt {
+                    return Ok(Some(jwt::encode_claims(&data)));
+                }
+                return Ok(Some(match &self.codec {
+                    Some(codec) => codec.encode(&data),
+                    None => self.format.encode(&data, self.encode_options()),
+                }));
+            }
+        };
+        let saved = match &session.stale_id {
+            Some(stale_id) => store.remove(stale_id),
+            None => Ok(()),
+        };
+        let saved =
+            saved.and_then(|_| store.save(&session.id, &session.to_map(), self.store_ttl()));
+        if let Err(error) = saved {
+            self.store_failed(error)?;
+            if self.store_failure_policy != StoreFailurePolicy::Cookie {
+                return Ok(None);
+            }
+            let data = session.to_map();
+            #[cfg(feature = "jwt")]
+            if self.jwt {
+                return Ok(Some(jwt::encode_claims(&data)));
+            }
+            let encoded = self.format.encode(&data, self.encode_options());
+            return Ok(Some(format!("{EMBEDDED_PREFIX}{encoded}")));
+        }
+        #[cfg(feature = "jwt")]
+        if self.jwt {
+            let claims = std::iter::once((ID_KEY.to_string(), session.id.clone()));
+            return Ok(Some(jwt::encode_claims(&claims.collect())));
+        }
+        Ok(Some(session.id.clone()))
+    }
+
+    /// How long stores keep data. Session-only cookies still need their
+    /// data expired eventually.
+    fn store_ttl(&self) -> std::time::Duration {
+        let ttl = self
+            .max_age
+            .or(self.lifetime)
+            .unwrap_or_else(|| Duration::days(DEFAULT_MAX_AGE_DAYS));
+        ttl.unsigned_abs()
+    }
+
+    /// Whether the session carries another counter than the one written
+    /// last, e.g. because an old cookie was replayed. Sessions whose counter
+    /// can't be checked because the store failed are treated as replayed.
+    fn is_replayed(&self, session: &Session, info: &mut SessionInfo) -> Result<bool, BoxError> {
+        let counters = match &self.counters {
+            Some(counters) => counters,
+            None => return Ok(false),
+        };
+        let latest = match counters.load(&session.id) {
+            Ok(latest) => latest,
+            Err(error) => {
+                info.store_error = Some(error.to_string());
+                self.store_failed(error)?;
+                return Ok(true);
+            }
+        };
+        let latest = latest.and_then(|data| data.get(COUNTER_KEY)?.parse().ok());
+        Ok(session.counter.is_none() || latest != session.counter)
+    }
+
+    /// Increments the session's counter and records it as the latest one.
+    /// Returns whether it was recorded, since a session whose counter the
+    /// store failed to record would be rejected as replayed.
+    fn advance_counter(&self, session: &mut Session) -> Result<bool, BoxError> {
+        let counters = match &self.counters {
+            Some(counters) => counters,
+            None => return Ok(true),
+        };
+        if let Some(stale_id) = &session.stale_id {
+            if let Err(error) = counters.remove(stale_id) {
+                self.store_failed(error)?;
+            }
+        }
+        let counter = session.counter.map_or(1, |counter| counter + 1);
+        session.counter = Some(counter);
+        let mut data = HashMap::new();
+        data.insert(COUNTER_KEY.to_string(), counter.to_string());
+        match counters.save(&session.id, &data, self.store_ttl()) {
+            Ok(()) => Ok(true),
+            Err(error) => self.store_failed(error).map(|_| false),
+        }
+    }
+
+    /// Reads the session cookie, trying the primary key first and then each
+    /// of the old keys. The returned flag is set when an old key was used.
+    fn read_cookie(
+        &self,
+        jar: &CookieJar,
+        info: &mut SessionInfo,
+    ) -> Option<(Cookie<'static>, bool)> {
+        let mut joined = CookieJar::new();
+        let jar = match jar.get(&self.cookie_name) {
+            Some(_) => jar,
+            None => {
+                joined.add_original(self.join_chunks(jar)?);
+                &joined
+            }
+        };
+        let raw = jar.get(&self.cookie_name)?;
+        info.cookie_present = true;
+        info.payload_size = raw.value().len();
+        let verified = match self.signer() {
+            Some(signer) => signer
+                .verify(&self.cookie_name, raw.value())
+                .map(|(value, outdated)| (Cookie::new(self.cookie_name.clone(), value), outdated)),
+            None => {
+                let previous = self.keys.previous();
+                std::iter::once(&self.keys.current())
+                    .chain(&previous)
+                    .chain(&self.old_keys)
+                    .enumerate()
+                    .find_map(|(i, key)| self.verify(jar, key).map(|cookie| (cookie, i != 0)))
+            }
+        };
+        // Legacy cookies are only decoded again by `before`, but aren't
+        // reported as invalid
+        info.invalid_signature = verified.is_none() && self.decode_legacy(raw.value()).is_none();
+        if info.invalid_signature {
+            event!(WARN, cookie = %self.cookie_name, "session cookie failed verification");
+            if let Some(metrics) = &self.metrics {
+                metrics.invalid_signature();
+            }
+        }
+        verified
+    }
+
+    /// Whether `name` is the session cookie or one of its chunks.
+    fn is_own_cookie(&self, name: &str) -> bool {
+        let chunk = name
+            .strip_prefix(self.cookie_name.as_str())
+            .and_then(|suffix| suffix.strip_prefix('.'));
+        name == self.cookie_name || matches!(chunk, Some(i) if i.parse::<usize>().is_ok())
+    }
+
+    fn chunk_name(&self, i: usize) -> String {
+        format!("{}.{}", self.cookie_name, i)
+    }
+
+    /// Reassembles a session cookie that was split by `write_cookie`.
+    fn join_chunks(&self, jar: &CookieJar) -> Option<Cookie<'static>> {
+        let mut value = String::new();
+        let mut i = 0;
+        while let Some(chunk) = jar.get(&self.chunk_name(i)) {
+            value.push_str(chunk.value());
+            i += 1;
+        }
+        if i == 0 {
+            return None;
+        }
+        Some(Cookie::new(self.cookie_name.clone(), value))
+    }
+
+    /// The session cookie in `jar` with its value verified and, if
+    /// encrypted, decrypted with `key`.
+    fn verify(&self, jar: &CookieJar, key: &Key) -> Option<Cookie<'static>> {
+        #[cfg(feature = "jwt")]
+        if self.jwt {
+            let token = jar.get(&self.cookie_name)?;
+            let claims = jwt::verify(token.value(), key)?;
+            return Some(Cookie::new(self.cookie_name.clone(), claims.to_string()));
+        }
+        if self.encrypted {
+            jar.private(key).get(&self.cookie_name)
+        } else {
+            jar.signed(key).get(&self.cookie_name)
+        }
+    }
+
+    /// The configured signer, unless JWT mode does its own signing.
+    fn signer(&self) -> Option<&dyn Signer> {
+        #[cfg(feature = "jwt")]
+        if self.jwt {
+            return None;
+        }
+        self.signer.as_deref()
+    }
+
+    /// Signs or encrypts the session cookie.
+    fn seal(&self, cookie: Cookie<'static>) -> Result<Cookie<'static>, BoxError> {
+        if let Some(signer) = self.signer() {
+            let mut cookie = cookie;
+            cookie.set_value(signer.sign(&self.cookie_name, cookie.value())?);
+            return Ok(cookie);
+        }
+        #[cfg(feature = "jwt")]
+        if self.jwt {
+            let mut cookie = cookie;
+            cookie.set_value(jwt::sign(cookie.value(), &self.keys.current()));
+            return Ok(cookie);
+        }
+        let mut signed = CookieJar::new();
+        if self.encrypted {
+            signed.private_mut(&self.keys.current()).add(cookie);
+        } else {
+            signed.signed_mut(&self.keys.current()).add(cookie);
+        }
+        Ok(signed.get(&self.cookie_name).unwrap().clone())
+    }
+
+    fn write_cookie(&self, jar: &mut CookieJar, cookie: Cookie<'static>) {
+        let len = cookie.value().len();
+        let mut chunks = 0;
+        if len <= CHUNK_SIZE {
+            jar.add(cookie);
+        } else {
+            let mut value = cookie.value();
+            while !value.is_empty() {
+                // Custom codecs and signers may produce non-ASCII values
+                let mut end = value.len().min(CHUNK_SIZE);
+                while !value.is_char_boundary(end) {
+                    end -= 1;
+                }
+                let (chunk, rest) = value.split_at(end);
+                let mut chunk_cookie = cookie.clone();
+                chunk_cookie.set_name(self.chunk_name(chunks));
+                chunk_cookie.set_value(chunk.to_string());
+                jar.add(chunk_cookie);
+                chunks += 1;
+                value = rest;
+            }
+            self.remove_cookie(jar, self.cookie_name.clone());
+        }
+        // Drop any chunks left over from a previously larger session
+        while jar.get(&self.chunk_name(chunks)).is_some() {
+            self.remove_cookie(jar, self.chunk_name(chunks));
+            chunks += 1;
+        }
+    }
+
+    fn delete_session(&self, session: &Session) -> Result<(), BoxError> {
+        if let Some(stale_id) = &session.stale_id {
+            if let Some(store) = &self.store {
+                if let Err(error) = store.remove(stale_id) {
+                    self.store_failed(error)?;
+                }
+            }
+            if let Some(counters) = &self.counters {
+                if let Err(error) = counters.remove(stale_id) {
+                    self.store_failed(error)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the session cookie, including any chunks, from the client.
+    fn delete_cookie(&self, jar: &mut CookieJar) {
+        self.remove_cookie(jar, self.cookie_name.clone());
+        let mut chunks = 0;
+        while jar.get(&self.chunk_name(chunks)).is_some() {
+            self.remove_cookie(jar, self.chunk_name(chunks));
+            chunks += 1;
+        }
+    }
+
+    fn remove_cookie(&self, jar: &mut CookieJar, name: String) {
+        let mut cookie = Cookie::build(name, "").path(self.path.to_string()).finish();
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.to_string());
+        }
+        jar.remove(cookie);
+    }
+
+    /// Decodes an unsigned session value in any format, returning an empty
+    /// session if it is invalid.
+    pub fn decode(cookie: Cookie<'_>) -> HashMap<String, String> {
+        let decoded = SessionFormat::decode(cookie.value());
+        decoded.map(|(_, data)| data).unwrap_or_default()
+    }
+
+    pub(crate) fn try_decode_value(value: &str) -> Result<HashMap<String, String>, Error> {
+        let bytes = decode(value.as_bytes())
+            .or_else(|_| decode_config(value, URL_SAFE_NO_PAD))
+            .map_err(|e| Error::InvalidSession(e.to_string()))?;
+        Self::decode_bytes(&bytes)
+    }
+
+    pub(crate) fn decode_bytes(bytes: &[u8]) -> Result<HashMap<String, String>, Error> {
+        let mut ret = HashMap::new();
+        let mut parts = bytes.split(|&a| a == 0xff);
+        while let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+            if key.is_empty() {
+                break;
+            }
+            let utf8 =
+                |bytes| str::from_utf8(bytes).map_err(|e| Error::InvalidSession(e.to_string()));
+            ret.insert(utf8(key)?.to_string(), utf8(value)?.to_string());
+        }
+        Ok(ret)
+    }
+
+    pub fn encode(h: &HashMap<String, String>) -> String {
+        let mut ret = Self::encode_bytes(h);
+        while !(ret.len() * 8).is_multiple_of(6) {
+            ret.push(0xff);
+        }
+        encode(&ret[..])
+    }
+
+    /// Encodes the entries sorted by key, so that equal sessions always
+    /// produce the same bytes.
+    pub(crate) fn encode_bytes(h: &HashMap<String, String>) -> Vec<u8> {
+        let mut entries = h.iter().collect::<Vec<_>>();
+        entries.sort();
+        let mut ret = Vec::new();
+        for (i, (k, v)) in entries.into_iter().enumerate() {
+            if i != 0 {
+                ret.push(0xff)
+            }
+            ret.extend(k.bytes());
+            ret.push(0xff);
+            ret.extend(v.bytes());
+        }
+        ret
+    }
+}
+
+impl SessionMiddlewareBuilder {
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.middleware.secure = secure;
+        self
+    }
+
+    /// Sets the Secure attribute only for requests made over HTTPS, so the
+    /// same configuration works in local HTTP development. The scheme is
+    /// taken from `SchemeMiddleware` if it is installed. Otherwise, with
+    /// `trust_forwarded_proto` the forwarding headers of any proxy are taken
+    /// into account as well.
+    pub fn auto_secure(mut self, trust_forwarded_proto: bool) -> Self {
+        self.middleware.auto_secure = Some(trust_forwarded_proto);
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.middleware.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.middleware.same_site = same_site;
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.middleware.path = path.to_string();
+        self
+    }
+
+    /// Shares the session with subdomains of `domain`, e.g. between `www.`
+    /// and `api.` hosts.
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.middleware.domain = Some(domain.to_string());
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.middleware.max_age = Some(max_age);
+        self
+    }
+
+    /// Omits Max-Age from the session cookie, so that it is discarded when
+    /// the browser is closed.
+    pub fn session_only(mut self) -> Self {
+        self.middleware.max_age = None;
+        self
+    }
+
+    /// Adds a previously used key that is still accepted when reading
+    /// sessions. Sessions read with an old key are re-issued with the
+    /// primary key.
+    pub fn old_key(mut self, key: Key) -> Self {
+        self.middleware.old_keys.push(key);
+        self
+    }
+
+    /// Looks up the keys on every request instead of using the key passed to
+    /// `builder`.
+    pub fn key_provider<P: KeyProvider>(mut self, keys: P) -> Self {
+        self.middleware.keys = Box::new(keys);
+        self
+    }
+
+    /// Signs and verifies session cookies with `signer` instead of the
+    /// keys. Cookies are then neither signed with the keys nor encrypted.
+    pub fn signer<S: Signer>(mut self, signer: S) -> Self {
+        self.middleware.signer = Some(Box::new(signer));
+        self
+    }
+
+    pub fn encrypted(mut self, encrypted: bool) -> Self {
+        self.middleware.encrypted = encrypted;
+        self
+    }
+
+    /// Writes the session as a JWT signed with HS256, so that other services
+    /// sharing the key's `signing()` half can verify and read it.
+    ///
+    /// The session id, expiry and issue time are stored in the `jti`, `exp`
+    /// and `iat` claims, other session metadata in a `conduit_cookie`
+    /// object, all of which are therefore reserved, and all other values
+    /// as string claims. Only the id is stored if a store is configured.
+    /// This replaces the format, codec and encryption settings.
+    #[cfg(feature = "jwt")]
+    pub fn jwt(mut self) -> Self {
+        self.middleware.jwt = true;
+        self
+    }
+
+    /// Keeps session data in `store`, with only the session id stored in
+    /// the cookie.
+    pub fn store<S: SessionStore>(mut self, store: S) -> Self {
+        self.middleware.store = Some(Box::new(store));
+        self
+    }
+
+    /// What to do when the store, or the counter store of
+    /// `replay_protection`, fails. `StoreFailurePolicy::Fail` by default.
+    pub fn store_failure_policy(mut self, policy: StoreFailurePolicy) -> Self {
+        self.middleware.store_failure_policy = policy;
+        self
+    }
+
+    /// Fails the request instead of emitting a session cookie whose value
+    /// is longer than `max_size` bytes across all chunks, unless a
+    /// `size_policy` manages to shrink it.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.middleware.max_size = Some(max_size);
+        self
+    }
+
+    pub fn size_policy(mut self, size_policy: SizePolicy) -> Self {
+        self.middleware.size_policy = size_policy;
+        self
+    }
+
+    pub fn format(mut self, format: SessionFormat) -> Self {
+        self.middleware.format = format;
+        self
+    }
+
+    /// Registers a hook called with the original format and data of
+    /// sessions that weren't written in the configured format. Such sessions
+    /// are always re-encoded in the configured format.
+    pub fn migrate<F>(mut self, migrate: F) -> Self
+    where
+        F: Fn(SessionFormat, &mut HashMap<String, String>) + Send + Sync + 'static,
+    {
+        self.middleware.migrate = Some(Box::new(migrate));
+        self
+    }
+
+    /// Registers a hook called with the session data before it is written,
+    /// which can drop stale entries such as old feature flags so that
+    /// long-lived sessions don't keep growing.
+    pub fn prune<F>(mut self, prune: F) -> Self
+    where
+        F: Fn(&mut HashMap<String, String>) + Send + Sync + 'static,
+    {
+        self.middleware.prune = Some(Box::new(prune));
+        self
+    }
+
+    /// Serializes sessions with `codec` instead of the configured format.
+    /// Neither compression nor format migration apply to such sessions.
+    pub fn codec<C: SessionCodec>(mut self, codec: C) -> Self {
+        self.middleware.codec = Some(Box::new(codec));
+        self
+    }
+
+    /// Embeds an expiry time of `lifetime` after creation in the signed
+    /// session, and discards sessions past that time. Unlike the cookie's
+    /// Max-Age this can't be extended by the client.
+    pub fn lifetime(mut self, lifetime: Duration) -> Self {
+        self.middleware.lifetime = Some(lifetime);
+        self
+    }
+
+    /// Discards sessions that weren't used for `idle_timeout`, independent
+    /// of their `lifetime`. The session cookie is re-issued on every request
+    /// to record the activity.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.middleware.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Binds sessions to the request attributes returned by `bind`, e.g.
+    /// `client_ip_prefix` or `user_agent`. A hash of them is embedded in the
+    /// session, which is discarded if they change.
+    pub fn bind<F>(mut self, bind: F) -> Self
+    where
+        F: Fn(&dyn RequestExt) -> String + Send + Sync + 'static,
+    {
+        self.middleware.bind = Some(Box::new(bind));
+        self
+    }
+
+    /// Skips reading and writing the session cookie for requests matching
+    /// `bypass`, e.g. for static assets or health checks. Such requests get
+    /// an empty session that is never written.
+    pub fn bypass<F>(mut self, bypass: F) -> Self
+    where
+        F: Fn(&dyn RequestExt) -> bool + Send + Sync + 'static,
+    {
+        self.middleware.bypass = Some(Box::new(bypass));
+        self
+    }
+
+    /// Only handles the session for the share `fraction` of requests, picked
+    /// at random, and leaves the others alone. See `sample_if`.
+    ///
+    /// A client can be sampled on one request and not on the next, so the
+    /// fallback middleware must accept cookies written by this one, e.g. by
+    /// listing the new key with `old_key`. Otherwise use `sample_if` to keep
+    /// each client in one group.
+    pub fn sample(self, fraction: f64) -> Self {
+        self.sample_if(move |_| rand::random::<f64>() < fraction)
+    }
+
+    /// Only handles the session for requests matching `sample`, e.g. those
+    /// carrying a beta cookie, to roll out a new key, format or encryption
+    /// while watching the rate of invalid cookies.
+    ///
+    /// A `SessionMiddleware` for the same cookie added after this one
+    /// handles the other requests. It can keep the previous settings, and
+    /// skips requests this one handled. Without a fallback, the other
+    /// requests get an empty session that is never written.
+    pub fn sample_if<F>(mut self, sample: F) -> Self
+    where
+        F: Fn(&dyn RequestExt) -> bool + Send + Sync + 'static,
+    {
+        self.middleware.sample = Some(Box::new(sample));
+        self
+    }
+
+    /// Adds `header` to the response's `Vary` header if the handler read
+    /// the session, so that caches don't serve personalized responses to
+    /// other users. This is usually `Cookie`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `header` isn't a valid header value.
+    pub fn vary(mut self, header: &str) -> Self {
+        let value = HeaderValue::from_str(header);
+        let value = value.unwrap_or_else(|_| panic!("invalid Vary header `{}`", header));
+        self.middleware.vary = Some(value);
+        self
+    }
+
+    /// How long past their embedded expiry time sessions, and values set
+    /// with `session_set_with_ttl`, are still accepted, to allow for clock
+    /// differences between servers.
+    pub fn clock_skew(mut self, clock_skew: Duration) -> Self {
+        self.middleware.clock_skew = clock_skew;
+        self
+    }
+
+    /// Re-issues the session cookie, extending its Max-Age, once less than
+    /// `threshold` of it remains, even if the session wasn't modified.
+    pub fn sliding_expiration(mut self, threshold: Duration) -> Self {
+        self.middleware.refresh_threshold = Some(threshold);
+        self
+    }
+
+    /// Deflates session payloads larger than `threshold` bytes.
+    #[cfg(feature = "compression")]
+    pub fn compress(mut self, threshold: usize) -> Self {
+        self.middleware.compress_threshold = Some(threshold);
+        self
+    }
+
+    /// Encodes `Delimited` sessions as URL-safe base64 without padding,
+    /// instead of appending `0xff` bytes until no padding is needed. Cookies
+    /// in either encoding are read regardless of this setting, but older
+    /// versions of this crate can't read URL-safe ones.
+    pub fn url_safe_encoding(mut self, url_safe: bool) -> Self {
+        self.middleware.url_safe = url_safe;
+        self
+    }
+
+    /// How to handle session cookies that verify but can't be decoded. They
+    /// are replaced with an empty session by default.
+    pub fn error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.middleware.error_policy = error_policy;
+        self
+    }
+
+    /// Reports session reads and writes, invalid signatures and oversized
+    /// session cookies to `metrics`.
+    pub fn metrics<M: CookieMetrics>(mut self, metrics: M) -> Self {
+        self.middleware.metrics = Some(Box::new(metrics));
+        self
+    }
+
+    /// Embeds a counter in the session that is incremented on every write,
+    /// and keeps the latest counter of each session in `counters`. Cookies
+    /// with any other counter are rejected, so captured cookies can't be
+    /// replayed after the session changed or was cleared on logout.
+    pub fn replay_protection<S: SessionStore>(mut self, counters: S) -> Self {
+        self.middleware.counters = Some(Box::new(counters));
+        self
+    }
+
+    pub fn hooks<H: SessionHooks>(mut self, hooks: H) -> Self {
+        self.middleware.hooks = Some(Box::new(hooks));
+        self
+    }
+
+    /// Starts sessions with the data of the foreign session cookie
+    /// `cookie_name` decoded by `importer`, if the request has no session
+    /// cookie of its own. The session is then written in the configured
+    /// format, while the foreign cookie is left alone for the app that
+    /// owns it.
+    pub fn import<I: SessionImporter>(mut self, cookie_name: &str, importer: I) -> Self {
+        let importer = Box::new(importer);
+        self.middleware
+            .importers
+            .push((cookie_name.to_string(), importer));
+        self
+    }
+
+    /// Accepts session cookies that fail verification but are read by
+    /// `decoder` until `until`, e.g. the unsigned cookies of a previous
+    /// stack. Such sessions are treated like imported ones, and re-issued
+    /// in signed form.
+    pub fn legacy_until<I: SessionImporter>(mut self, until: OffsetDateTime, decoder: I) -> Self {
+        self.middleware.legacy = Some((Box::new(decoder), until));
+        self
+    }
+
+    /// Reads the current time for expiry, idle timeouts and refreshes from
+    /// `clock` instead of the system clock.
+    pub fn clock<C: Clock>(mut self, clock: C) -> Self {
+        self.middleware.clock = Arc::new(clock);
+        self
+    }
+
+    /// Sets the `Partitioned` attribute, for sessions of embedded
+    /// third-party content (CHIPS). Browsers require such cookies to be
+    /// Secure.
+    pub fn partitioned(mut self, partitioned: bool) -> Self {
+        self.middleware.partitioned = partitioned;
+        self
+    }
+
+    /// Adds the `__Host-` prefix to the cookie name.
+    pub fn host_prefix(mut self, host_prefix: bool) -> Self {
+        self.host_prefix = host_prefix;
+        self
+    }
+
+    /// Builds the middleware. Cookie names with a `__Host-` or `__Secure-`
+    /// prefix always get the attributes the prefix requires, since browsers
+    /// reject such cookies otherwise.
+    ///
+    /// # Panics
+    ///
+    /// Panics if SameSite is `None` or the cookie is partitioned, but it
+    /// isn't Secure.
+    pub fn build(mut self) -> SessionMiddleware {
+        let middleware = &mut self.middleware;
+        if self.host_prefix && !middleware.cookie_name.starts_with(HOST_PREFIX) {
+            middleware.cookie_name.insert_str(0, HOST_PREFIX);
+        }
+        if middleware.cookie_name.starts_with(HOST_PREFIX) {
+            middleware.secure = true;
+            middleware.domain = None;
+            middleware.path = "/".to_string();
+        } else if middleware.cookie_name.starts_with(SECURE_PREFIX) {
+            middleware.secure = true;
+        }
+        assert!(
+            middleware.same_site != SameSite::None || middleware.secure,
+            "SameSite=None session cookies must be Secure"
+        );
+        assert!(
+            !middleware.partitioned || middleware.secure,
+            "partitioned session cookies must be Secure"
+        );
+        self.middleware
+    }
+}
+
+impl SessionMiddleware {
+    fn load_request(&self, req: &mut dyn RequestExt) -> BeforeResult {
+        let session = try_state(req, Some(&self.cookie_name));
+        if matches!(session, Some(session) if session.pending && session.owner != self.id) {
+            return Ok(());
+        }
+        if matches!(&self.sample, Some(sample) if !sample(req)) {
+            let mut session = Session::new(HashMap::new(), self.clock.clone());
+            session.suppressed = true;
+            self.insert_session(req, session);
+            // Not pending, so that a fallback middleware can replace it
+            if let Some(session) = try_state_mut(req, Some(&self.cookie_name)) {
+                session.pending = false;
+            }
+            return Ok(());
+        }
+        if matches!(&self.bypass, Some(bypass) if bypass(req)) {
+            let mut session = Session::new(HashMap::new(), self.clock.clone());
+            session.suppressed = true;
+            self.insert_session(req, session);
+            return Ok(());
+        }
+        let mut info = SessionInfo::default();
+        let mut imported = false;
+        let mut session = match self.read_cookie(req.cookies(), &mut info) {
+            Some((cookie, rotated)) => {
+                let mut session = self.load_session(cookie, &mut info)?;
+                if let Some(metrics) = &self.metrics {
+                    metrics.session_read();
+                }
+                session.dirty |= rotated || self.needs_refresh(&session);
+                session
+            }
+            None if !info.cookie_present => match self.import(req.cookies()) {
+                Some(session) => {
+                    imported = true;
+                    session
+                }
+                None => Session::new(HashMap::new(), self.clock.clone()),
+            },
+            None => {
+                let legacy = req.cookies().get(&self.cookie_name);
+                match legacy.and_then(|cookie| self.decode_legacy(cookie.value())) {
+                    Some(data) => {
+                        imported = true;
+                        let mut session = Session::new(data, self.clock.clone());
+                        session.dirty = true;
+                        session
+                    }
+                    None => Session::new(HashMap::new(), self.clock.clone()),
+                }
+            }
+        };
+        session.remove_expired_values(self.clock_skew);
+        let binding = self.bind.as_ref().map(|bind| binding::hash(&bind(req)));
+        let rebound = matches!((&session.binding, &binding), (Some(old), Some(new)) if old != new);
+        let loaded = info.cookie_present
+            && !info.invalid_signature
+            && !imported
+            && info.store_error.is_none();
+        let restored = loaded || imported;
+        let tenant = req.extensions().get::<SessionTenant>();
+        let tenant = tenant.map(|tenant| tenant.0.clone());
+        let discarded = session.is_expired(self.clock_skew)
+            || self.is_idle(&session)
+            || rebound
+            || (loaded && session.tenant != tenant)
+            || (loaded && self.is_replayed(&session, &mut info)?);
+        let mut session = if discarded {
+            Session::new(HashMap::new(), self.clock.clone())
+        } else {
+            session
+        };
+        if info.store_error.is_some() {
+            session.suppressed = self.store_failure_policy == StoreFailurePolicy::Anonymous;
+        }
+        if session.binding != binding {
+            // Re-issue existing sessions that were written before binding
+            session.dirty |= !session.data.is_empty();
+            session.binding = binding;
+        }
+        session.tenant = tenant;
+        if let Some(hooks) = &self.hooks {
+            if info.invalid_signature || info.decode_error.is_some() {
+                hooks.on_invalid(req, &info);
+            }
+            if restored && !discarded && info.decode_error.is_none() {
+                hooks.on_load(req, &session.data);
+            } else {
+                hooks.on_create(req, Arc::make_mut(&mut session.data));
+            }
+        }
+        session.state = if info.invalid_signature {
+            SessionState::InvalidSignature
+        } else if info.decode_error.is_some() {
+            SessionState::CorruptPayload
+        } else if info.store_error.is_some() {
+            SessionState::StoreFailed(self.store_failure_policy)
+        } else if restored && !discarded {
+            SessionState::Restored
+        } else {
+            SessionState::New
+        };
+        session.info = info;
+        self.insert_session(req, session);
+        Ok(())
+    }
+
+    fn write_request(&self, req: &mut dyn RequestExt, res: AfterResult) -> AfterResult {
+        let secure = self.is_secure(req);
+        let session = match try_state_mut(req, Some(&self.cookie_name)) {
+            Some(session) if session.owner == self.id => session,
+            // Handled by an earlier middleware for the same cookie
+            Some(_) => return res,
+            None => panic!("session must be present after request"),
+        };
+        session.pending = false;
+        let res = match &self.vary {
+            Some(vary) if session.accessed.load(Ordering::Relaxed) => res.map(|mut res| {
+                add_vary(res.headers_mut(), vary);
+                res
+            }),
+            _ => res,
+        };
+        if !session.commits.is_empty() {
+            let data = Arc::make_mut(&mut session.data);
+            session.commits.drain(..).for_each(|commit| commit(data));
+        }
+        if session.suppressed || !session.is_dirty() {
+            return res;
+        }
+        if let Some(prune) = &self.prune {
+            prune(Arc::make_mut(&mut session.data));
+        }
+        if session.cleared && session.data.is_empty() {
+            self.delete_session(session)?;
+            event!(DEBUG, cookie = %self.cookie_name, "removing session cookie");
+            self.delete_cookie(req.cookies_mut());
+        } else {
+            session.expires = self.lifetime.map(|lifetime| session.created + lifetime);
+            session.issued = Some(self.clock.now());
+            let recorded = self.advance_counter(session)?;
+            let mut attempt = 0;
+            let cookie = loop {
+                if !recorded {
+                    break None;
+                }
+                let encoded = match self.save_session(session)? {
+                    Some(encoded) => encoded,
+                    None => break None,
+                };
+                let mut cookie = Cookie::build(self.cookie_name.to_string(), encoded)
+                    .http_only(self.http_only)
+                    .secure(secure)
+                    .same_site(self.same_site)
+                    .path(self.path.to_string())
+                    .finish();
+                if let Some(max_age) = self.max_age {
+                    cookie.set_max_age(max_age);
+                }
+                if let Some(domain) = &self.domain {
+                    cookie.set_domain(domain.to_string());
+                }
+                let cookie = self.seal(cookie)?;
+                let len = cookie.value().len();
+                let max_size = match self.max_size {
+                    Some(max_size) if len > max_size => max_size,
+                    _ => break Some(cookie),
+                };
+                if let Some(metrics) = &self.metrics {
+                    metrics.oversized_cookie();
+                }
+                let shrunk =
+                    self.size_policy
+                        .shrink(Arc::make_mut(&mut session.data), max_size, attempt);
+                if !shrunk {
+                    return Err(Box::new(Error::SessionTooLarge { len }));
+                }
+                event!(WARN, cookie = %self.cookie_name, len, "shrinking oversized session");
+                attempt += 1;
+            };
+            if let Some(cookie) = cookie {
+                event!(DEBUG, cookie = %self.cookie_name, "writing session cookie");
+                self.write_cookie(req.cookies_mut(), cookie);
+                if let Some(metrics) = &self.metrics {
+                    metrics.session_written();
+                }
+                if let Some(hooks) = &self.hooks {
+                    let session = try_state(req, Some(&self.cookie_name)).unwrap();
+                    hooks.on_write(req, &session.data);
+                }
+            }
+        }
+        if self.partitioned {
+            let names = req
+                .cookies()
+                .delta()
+                .map(|cookie| cookie.name().to_string())
+                .filter(|name| self.is_own_cookie(name))
+                .collect::<Vec<_>>();
+            for name in names {
+                req.partition_cookie(&name);
+            }
+        }
+        res
+    }
+}
+
+fn is_unavailable(error: &BoxError) -> bool {
+    matches!(error.downcast_ref(), Some(Error::StoreUnavailable(_)))
+}
+
+fn service_unavailable() -> AfterResult {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .body(Body::empty())
+        .map_err(box_error)
+}
+
+impl conduit_middleware::Middleware for SessionMiddleware {
+    fn before(&self, req: &mut dyn RequestExt) -> BeforeResult {
+        match self.load_request(req) {
+            // The handler still runs, but its response is replaced by `after`
+            Err(error) if is_unavailable(&error) => {
+                let mut session = Session::new(HashMap::new(), self.clock.clone());
+                session.suppressed = true;
+                session.state = SessionState::StoreFailed(StoreFailurePolicy::Unavailable);
+                session.info.store_error = Some(error.to_string());
+                self.insert_session(req, session);
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
+    fn after(&self, req: &mut dyn RequestExt, res: AfterResult) -> AfterResult {
+        let unavailable = matches!(
+            try_state(req, Some(&self.cookie_name)),
+            Some(session) if session.owner == self.id
+                && session.pending
+                && session.state == SessionState::StoreFailed(StoreFailurePolicy::Unavailable)
+        );
+        match self.write_request(req, res) {
+            _ if unavailable => service_unavailable(),
+            Err(error) if is_unavailable(&error) => service_unavailable(),
+            res => res,
+        }
+    }
+}
+
+pub trait RequestSession {
+    fn session(&self) -> &HashMap<String, String>;
+    fn session_mut(&mut self) -> &mut HashMap<String, String>;
+
+    /// Like `session`, but returns `None` if `SessionMiddleware` isn't
+    /// installed.
+    fn try_session(&self) -> Option<&HashMap<String, String>>;
+    fn try_session_mut(&mut self) -> Option<&mut HashMap<String, String>>;
+
+    /// Values that live only for the current request and are never written
+    /// to the session cookie, e.g. data derived from the session.
+    fn session_volatile(&self) -> &HashMap<String, String>;
+    fn session_volatile_mut(&mut self) -> &mut HashMap<String, String>;
+
+    /// The session stored in the cookie `name`, for apps installing several
+    /// `SessionMiddleware`s. `session` returns the one installed first.
+    fn session_named(&self, name: &str) -> &HashMap<String, String>;
+    fn session_named_mut(&mut self, name: &str) -> &mut HashMap<String, String>;
+
+    /// Re-issues the session cookie under a new session id, e.g. after
+    /// login to defend against session fixation.
+    fn session_regenerate(&mut self);
+
+    /// Empties the session. Unless new data is added afterwards, the session
+    /// cookie is removed from the client.
+    fn clear_session(&mut self);
+
+    fn session_is_dirty(&self) -> bool;
+    fn session_mark_clean(&mut self);
+    fn session_force_write(&mut self);
+
+    /// Per-request session settings, e.g. to suppress the session cookie on
+    /// health checks or cacheable pages.
+    fn session_options(&mut self) -> SessionOptions<'_>;
+
+    /// Details about the session cookie of the request.
+    fn session_info(&self) -> &SessionInfo;
+
+    /// Whether the session was restored from the request, or why not.
+    fn session_state(&self) -> SessionState;
+
+    /// A snapshot of the current session data. It shares the data until
+    /// either is changed, so read-only requests don't copy it.
+    fn session_view(&self) -> SessionView;
+
+    fn session_iter(&self) -> hash_map::Iter<'_, String, String> {
+        self.session().iter()
+    }
+
+    fn session_remove(&mut self, key: &str) -> Option<String> {
+        self.session_mut().remove(key)
+    }
+
+    fn session_extend<I: IntoIterator<Item = (String, String)>>(&mut self, values: I) {
+        self.session_mut().extend(values)
+    }
+
+    /// Queues a change to the session, applied after the handler and the
+    /// middleware added after `SessionMiddleware` ran, right before the
+    /// session is written. Changes queued by several middleware are merged
+    /// into a single write of the cookie or store.
+    fn session_on_commit<F>(&mut self, commit: F)
+    where
+        F: FnOnce(&mut HashMap<String, String>) + Send + Sync + 'static;
+
+    /// Stores `value` in the session until `ttl` has passed, e.g. for
+    /// one-time codes. Expired values are removed when the session is
+    /// loaded. Overwriting the value with `session_mut` keeps its expiry.
+    fn session_set_with_ttl(&mut self, key: &str, value: &str, ttl: Duration);
+
+    /// Keeps only the session values for which `keep` returns true. The
+    /// session is only written if anything was removed.
+    fn session_retain<F: FnMut(&str, &str) -> bool>(&mut self, mut keep: F) {
+        self.session_mut().retain(|key, value| keep(key, value))
+    }
+
+    /// Deserializes a session value that was stored with `session_set`.
+    #[cfg(feature = "serde")]
+    fn session_get<V: DeserializeOwned>(&self, key: &str) -> serde_json::Result<Option<V>> {
+        self.session()
+            .get(key)
+            .map(|value| serde_json::from_str(value))
+            .transpose()
+    }
+
+    /// Serializes `value` as JSON and stores it in the session.
+    #[cfg(feature = "serde")]
+    fn session_set<V: Serialize>(&mut self, key: &str, value: &V) -> serde_json::Result<()> {
+        let value = serde_json::to_string(value)?;
+        self.session_mut().insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+impl<T: RequestExt + ?Sized> RequestSession for T {
+    fn session(&self) -> &HashMap<String, String> {
+        self.try_session().expect("missing cookie session")
+    }
+
+    fn session_mut(&mut self) -> &mut HashMap<String, String> {
+        self.try_session_mut().expect("missing cookie session")
+    }
+
+    fn try_session(&self) -> Option<&HashMap<String, String>> {
+        try_state(self, None).map(Session::data)
+    }
+
+    fn try_session_mut(&mut self) -> Option<&mut HashMap<String, String>> {
+        try_state_mut(self, None).map(Session::data_mut)
+    }
+
+    fn session_view(&self) -> SessionView {
+        state(self).view()
+    }
+
+    fn session_on_commit<F>(&mut self, commit: F)
+    where
+        F: FnOnce(&mut HashMap<String, String>) + Send + Sync + 'static,
+    {
+        state_mut(self).commits.push(Box::new(commit));
+    }
+
+    fn session_set_with_ttl(&mut self, key: &str, value: &str, ttl: Duration) {
+        let session = state_mut(self);
+        let expires = session.clock.now() + ttl;
+        session.value_expiry.insert(key.to_string(), expires);
+        session
+            .data_mut()
+            .insert(key.to_string(), value.to_string());
+        session.dirty = true;
+    }
+
+    fn session_volatile(&self) -> &HashMap<String, String> {
+        &state(self).volatile
+    }
+
+    fn session_volatile_mut(&mut self) -> &mut HashMap<String, String> {
+        &mut state_mut(self).volatile
+    }
+
+    fn session_named(&self, name: &str) -> &HashMap<String, String> {
+        let session = try_state(self, Some(name));
+        session.expect("missing cookie session").data()
+    }
+
+    fn session_named_mut(&mut self, name: &str) -> &mut HashMap<String, String> {
+        let session = try_state_mut(self, Some(name));
+        session.expect("missing cookie session").data_mut()
+    }
+
+    fn session_regenerate(&mut self) {
+        state_mut(self).regenerate();
+    }
+
+    fn clear_session(&mut self) {
+        state_mut(self).clear();
+    }
+
+    fn session_is_dirty(&self) -> bool {
+        state(self).is_dirty()
+    }
+
+    fn session_mark_clean(&mut self) {
+        state_mut(self).mark_clean();
+    }
+
+    fn session_force_write(&mut self) {
+        state_mut(self).force_write();
+    }
+
+    fn session_options(&mut self) -> SessionOptions<'_> {
+        SessionOptions {
+            session: state_mut(self),
+        }
+    }
+
+    fn session_info(&self) -> &SessionInfo {
+        &state(self).info
+    }
+
+    fn session_state(&self) -> SessionState {
+        state(self).state
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use conduit::{
+        header, Body, BoxError, Handler, HttpResult, Method, RequestExt, Response, StatusCode,
+    };
+    use conduit_middleware::MiddlewareBuilder;
+    use conduit_test::MockRequest;
+    use cookie::time::{Duration, OffsetDateTime};
+    use cookie::{Cookie, Key, SameSite};
+
+    use crate::{
+        client_ip_prefix, decode_session, encode_session, user_agent, Clock, CookieMetrics,
+        ErrorPolicy, KeyProvider, MemoryStore, Middleware, RequestCookies, RequestSession,
+        SessionImporter, SessionInfo, SessionMiddleware, SessionState, SessionStore, SessionTenant,
+        Signer, StoreFailurePolicy,
+    };
+
+    // Fails every call while `down` is set
+    #[derive(Default)]
+    struct FlakyStore {
+        store: MemoryStore,
+        down: AtomicBool,
+    }
+
+    impl FlakyStore {
+        fn check(&self) -> Result<(), BoxError> {
+            if self.down.load(Ordering::SeqCst) {
+                let error = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+                return Err(Box::new(error));
+            }
+            Ok(())
+        }
+    }
+
+    impl SessionStore for FlakyStore {
+        fn load(&self, id: &str) -> Result<Option<HashMap<String, String>>, BoxError> {
+            self.check()?;
+            self.store.load(id)
+        }
+
+        fn save(
+            &self,
+            id: &str,
+            data: &HashMap<String, String>,
+            ttl: std::time::Duration,
+        ) -> Result<(), BoxError> {
+            self.check()?;
+            self.store.save(id, data, ttl)
+        }
+
+        fn remove(&self, id: &str) -> Result<(), BoxError> {
+            self.check()?;
+            self.store.remove(id)
+        }
+    }
+
+    fn test_key() -> Key {
+        let master_key: Vec<u8> = (0..32).collect();
+        Key::derive_from(&master_key)
+    }
+
+    #[test]
+    fn simple() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        let key = test_key();
+
+        // Set the session cookie
+        let mut app = MiddlewareBuilder::new(set_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("lol", key, false));
+        let response = app.call(&mut req).unwrap();
+
+        let v = response
+            .headers()
+            .get(header::SET_COOKIE)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(v.starts_with("lol"));
+
+        // Use the session cookie
+        req.header(header::COOKIE, v);
+        let key = test_key();
+        let mut app = MiddlewareBuilder::new(use_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("lol", key, false));
+        assert!(app.call(&mut req).is_ok());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req
+                .session_mut()
+                .insert("foo".to_string(), "bar".to_string())
+                .is_none());
+            Response::builder().body(Body::empty())
+        }
+        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(*req.session().get("foo").unwrap(), "bar");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn encrypted() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+
+        let mut app = MiddlewareBuilder::new(set_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::encrypted("secret", test_key(), false));
+        let response = app.call(&mut req).unwrap();
+
+        let v = response
+            .headers()
+            .get(header::SET_COOKIE)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let cookie = Cookie::parse(v).unwrap();
+        let plain = SessionMiddleware::encode(&{
+            let mut map = HashMap::new();
+            map.insert("foo".to_string(), "bar".to_string());
+            map
+        });
+        assert!(!cookie.value().contains(&plain));
+
+        // A signed-only middleware must not accept the encrypted cookie
+        req.header(header::COOKIE, v);
+        let mut app = MiddlewareBuilder::new(empty_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("secret", test_key(), false));
+        assert!(app.call(&mut req).is_ok());
+
+        let mut app = MiddlewareBuilder::new(use_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::encrypted("secret", test_key(), false));
+        assert!(app.call(&mut req).is_ok());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn empty_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.session().is_empty());
+            Response::builder().body(Body::empty())
+        }
+        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(*req.session().get("foo").unwrap(), "bar");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn key_provider() {
+        struct Rotating(Mutex<Vec<Key>>);
+
+        impl KeyProvider for Rotating {
+            fn current(&self) -> Key {
+                self.0.lock().unwrap()[0].clone()
+            }
+            fn previous(&self) -> Vec<Key> {
+                self.0.lock().unwrap()[1..].to_vec()
+            }
+        }
+
+        let keys = Arc::new(Rotating(Mutex::new(vec![Key::derive_from(&[1; 32])])));
+        let mut app = MiddlewareBuilder::new(use_session);
+        app.add(Middleware::new());
+        app.add(
+            SessionMiddleware::builder("provided", test_key())
+                .key_provider(keys.clone())
+                .build(),
+        );
+
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        let response = app.call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        // Rotating the key re-signs the session with the new key
+        keys.0.lock().unwrap().insert(0, test_key());
+        let response = app.call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap().to_string()).unwrap();
+        let mut jar = cookie::CookieJar::new();
+        jar.add_original(cookie);
+        assert!(jar.signed(&test_key()).get("provided").is_some());
+
+        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn key_rotation() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        let old_key = Key::derive_from(&[1; 32]);
+
+        let mut app = MiddlewareBuilder::new(set_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("rotate", old_key.clone(), false));
+        let response = app.call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        // Without the old key the session is discarded
+        let mut app = MiddlewareBuilder::new(empty_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("rotate", test_key(), false));
+        assert!(app.call(&mut req).is_ok());
+
+        // With the old key the session is accepted and re-signed
+        let mut app = MiddlewareBuilder::new(use_session);
+        app.add(Middleware::new());
+        app.add(
+            SessionMiddleware::builder("rotate", test_key())
+                .old_key(old_key.clone())
+                .build(),
+        );
+        let response = app.call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        let mut app = MiddlewareBuilder::new(use_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("rotate", test_key(), false));
+        let response = app.call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn empty_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.session().is_empty());
+            Response::builder().body(Body::empty())
+        }
+        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(*req.session().get("foo").unwrap(), "bar");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn typed_values() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+
+        let mut app = MiddlewareBuilder::new(set_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("typed", test_key(), false));
+        let response = app.call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        let mut app = MiddlewareBuilder::new(use_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("typed", test_key(), false));
+        assert!(app.call(&mut req).is_ok());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_set("user", &(42_u32, "alex".to_string()))
+                .unwrap();
+            req.session_mut()
+                .insert("raw".to_string(), "not json".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
+            let user = req.session_get::<(u32, String)>("user").unwrap();
+            assert_eq!(user, Some((42, "alex".to_string())));
+            assert_eq!(req.session_get::<u32>("missing").unwrap(), None);
+            assert!(req.session_get::<u32>("raw").is_err());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn regenerate() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+
+        let mut app = MiddlewareBuilder::new(set_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("regen", test_key(), false));
+        let response = app.call(&mut req).unwrap();
+        let first = response.headers().get(header::SET_COOKIE).unwrap();
+        let first = first.to_str().unwrap().to_string();
+        req.header(header::COOKIE, &first);
+
+        let mut app = MiddlewareBuilder::new(regenerate_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("regen", test_key(), false));
+        let response = app.call(&mut req).unwrap();
+        let second = response.headers().get(header::SET_COOKIE).unwrap();
+        let second = second.to_str().unwrap().to_string();
+        assert_ne!(first, second);
+        req.header(header::COOKIE, &second);
+
+        let mut app = MiddlewareBuilder::new(use_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("regen", test_key(), false));
+        assert!(app.call(&mut req).is_ok());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn regenerate_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_regenerate();
+            Response::builder().body(Body::empty())
+        }
+        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session().len(), 1);
+            assert_eq!(*req.session().get("foo").unwrap(), "bar");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn batched_commits() {
+        struct CountingStore {
+            store: MemoryStore,
+            saves: AtomicUsize,
+        }
+
+        impl SessionStore for CountingStore {
+            fn load(&self, id: &str) -> Result<Option<HashMap<String, String>>, BoxError> {
+                self.store.load(id)
+            }
+
+            fn save(
+                &self,
+                id: &str,
+                data: &HashMap<String, String>,
+                ttl: std::time::Duration,
+            ) -> Result<(), BoxError> {
+                self.saves.fetch_add(1, Ordering::SeqCst);
+                self.store.save(id, data, ttl)
+            }
+
+            fn remove(&self, id: &str) -> Result<(), BoxError> {
+                self.store.remove(id)
+            }
+        }
+
+        struct Visits;
+
+        impl conduit_middleware::Middleware for Visits {
+            fn before(&self, req: &mut dyn RequestExt) -> conduit_middleware::BeforeResult {
+                req.session_on_commit(|data| {
+                    let visits = data.get("visits").map_or(0, |v| v.parse().unwrap());
+                    data.insert("visits".to_string(), (visits + 1).to_string());
+                });
+                Ok(())
+            }
+
+            fn after(
+                &self,
+                req: &mut dyn RequestExt,
+                res: conduit_middleware::AfterResult,
+            ) -> conduit_middleware::AfterResult {
+                let path = req.path().to_string();
+                req.session_on_commit(|data| {
+                    data.insert("last_path".to_string(), path);
+                });
+                res
+            }
+        }
+
+        let store = Arc::new(CountingStore {
+            store: MemoryStore::new(),
+            saves: AtomicUsize::new(0),
+        });
+        let mut app = MiddlewareBuilder::new(set_session);
+        app.add(Middleware::new());
+        app.add(
+            SessionMiddleware::builder("batched", test_key())
+                .store(store.clone())
+                .build(),
+        );
+        app.add(Visits);
+
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        let response = app.call(&mut req).unwrap();
+        assert_eq!(
+            response
+                .headers()
+                .get_all(header::SET_COOKIE)
+                .iter()
+                .count(),
+            1
+        );
+        assert_eq!(store.saves.load(Ordering::SeqCst), 1);
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+        let data = store.load(&cookie.value()[44..]).unwrap().unwrap();
+        assert_eq!(data["user"], "alice");
+        assert_eq!(data["visits"], "1");
+        assert_eq!(data["last_path"], "/articles");
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            // Queued changes aren't applied yet
+            assert!(req.session().get("visits").is_none());
+            req.session_mut()
+                .insert("user".to_string(), "alice".to_string());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn server_side_store() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        let store = Arc::new(MemoryStore::new());
+
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("stored", test_key())
+                    .store(store.clone())
+                    .build(),
+            );
+            app
+        };
+
+        let response = app(set_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+        let id = cookie.value()[44..].to_string();
+        assert!(!cookie.value().contains(&SessionMiddleware::encode(&{
+            let mut map = HashMap::new();
+            map.insert("foo".to_string(), "bar".to_string());
+            map
+        })));
+        let data = store.load(&id).unwrap().unwrap();
+        assert_eq!(*data.get("foo").unwrap(), "bar");
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        let response = app(regenerate_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        assert!(store.load(&id).unwrap().is_none());
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        assert!(app(use_session).call(&mut req).is_ok());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn regenerate_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_regenerate();
+            Response::builder().body(Body::empty())
+        }
+        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(*req.session().get("foo").unwrap(), "bar");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn store_failure_policy() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        let store = Arc::new(FlakyStore::default());
+        let app = |policy, handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("flaky", test_key())
+                    .store(store.clone())
+                    .store_failure_policy(policy)
+                    .build(),
+            );
+            app
+        };
+
+        let response = app(StoreFailurePolicy::Fail, set_session)
+            .call(&mut req)
+            .unwrap();
+        let stored = response.headers().get(header::SET_COOKIE).unwrap();
+        let stored = stored.to_str().unwrap().to_string();
+        req.header(header::COOKIE, &stored);
+
+        store.down.store(true, Ordering::SeqCst);
+        assert!(app(StoreFailurePolicy::Fail, set_session)
+            .call(&mut req)
+            .is_err());
+        let response = app(StoreFailurePolicy::Unavailable, set_session)
+            .call(&mut req)
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        // Also when only saving the new session fails
+        let mut fresh = MockRequest::new(Method::POST, "/articles");
+        let response = app(StoreFailurePolicy::Unavailable, set_session)
+            .call(&mut fresh)
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // The stored session is left alone
+        let response = app(StoreFailurePolicy::Anonymous, anonymous)
+            .call(&mut req)
+            .unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        let response = app(StoreFailurePolicy::Cookie, fallback)
+            .call(&mut req)
+            .unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+        assert!(cookie.value()[44..].starts_with('~'));
+        req.header(header::COOKIE, v.to_str().unwrap());
+        let response = app(StoreFailurePolicy::Cookie, use_fallback)
+            .call(&mut req)
+            .unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_some());
+
+        // Once the store recovers, the embedded session moves back into it
+        store.down.store(false, Ordering::SeqCst);
+        let response = app(StoreFailurePolicy::Cookie, use_fallback)
+            .call(&mut req)
+            .unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+        let data = store.load(&cookie.value()[44..]).unwrap().unwrap();
+        assert_eq!(data["foo"], "baz");
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn anonymous(req: &mut dyn RequestExt) -> HttpResult {
+            let state = SessionState::StoreFailed(StoreFailurePolicy::Anonymous);
+            assert_eq!(req.session_state(), state);
+            assert!(req.session().is_empty());
+            req.session_mut()
+                .insert("foo".to_string(), "baz".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn fallback(req: &mut dyn RequestExt) -> HttpResult {
+            let state = SessionState::StoreFailed(StoreFailurePolicy::Cookie);
+            assert_eq!(req.session_state(), state);
+            req.session_mut()
+                .insert("foo".to_string(), "baz".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn use_fallback(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session_state(), SessionState::Restored);
+            assert_eq!(req.session()["foo"], "baz");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn counter_store_failure() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        let counters = Arc::new(FlakyStore::default());
+        let app = |policy, handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("counted", test_key())
+                    .replay_protection(counters.clone())
+                    .store_failure_policy(policy)
+                    .build(),
+            );
+            app
+        };
+
+        let response = app(StoreFailurePolicy::Fail, set_session)
+            .call(&mut req)
+            .unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        counters.down.store(true, Ordering::SeqCst);
+        assert!(app(StoreFailurePolicy::Fail, set_session)
+            .call(&mut req)
+            .is_err());
+        let response = app(StoreFailurePolicy::Unavailable, unavailable)
+            .call(&mut req)
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+        let response = app(StoreFailurePolicy::Anonymous, anonymous)
+            .call(&mut req)
+            .unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+        // Cookies whose counter can't be recorded would be rejected later
+        let response = app(StoreFailurePolicy::Cookie, fallback)
+            .call(&mut req)
+            .unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        counters.down.store(false, Ordering::SeqCst);
+        assert!(app(StoreFailurePolicy::Fail, use_session)
+            .call(&mut req)
+            .is_ok());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn unavailable(req: &mut dyn RequestExt) -> HttpResult {
+            let state = SessionState::StoreFailed(StoreFailurePolicy::Unavailable);
+            assert_eq!(req.session_state(), state);
+            Response::builder().body(Body::empty())
+        }
+        fn anonymous(req: &mut dyn RequestExt) -> HttpResult {
+            let state = SessionState::StoreFailed(StoreFailurePolicy::Anonymous);
+            assert_eq!(req.session_state(), state);
+            assert!(req.session().is_empty());
+            Response::builder().body(Body::empty())
+        }
+        fn fallback(req: &mut dyn RequestExt) -> HttpResult {
+            let state = SessionState::StoreFailed(StoreFailurePolicy::Cookie);
+            assert_eq!(req.session_state(), state);
+            req.session_mut()
+                .insert("foo".to_string(), "baz".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session_state(), SessionState::Restored);
+            assert_eq!(req.session()["foo"], "bar");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn chunked_on_char_boundaries() {
+        let middleware = SessionMiddleware::new("big", test_key(), false);
+        let value = format!("a{}", "\u{e9}".repeat(super::CHUNK_SIZE));
+        let mut jar = cookie::CookieJar::new();
+        middleware.write_cookie(&mut jar, Cookie::new("big", value.clone()));
+        let joined = middleware.join_chunks(&jar).unwrap();
+        assert_eq!(joined.value(), value);
+        assert!(jar.get("big.2").is_some());
+    }
+
+    #[test]
+    fn chunked() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(SessionMiddleware::new("big", test_key(), false));
+            app
+        };
+
+        let response = app(set_big_session).call(&mut req).unwrap();
+        let cookies = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|v| Cookie::parse(v.to_str().unwrap().to_string()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(cookies.len(), 3);
+        assert!(cookies.iter().all(|c| c.value().len() <= 4000));
+        let header = cookies
+            .iter()
+            .map(|c| format!("{}={}", c.name(), c.value()))
+            .collect::<Vec<_>>()
+            .join("; ");
+        req.header(header::COOKIE, &header);
+
+        let response = app(shrink_session).call(&mut req).unwrap();
+        let mut v = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|v| Cookie::parse(v.to_str().unwrap().to_string()).unwrap())
+            .map(|c| (c.name().to_string(), c.value().is_empty()))
+            .collect::<Vec<_>>();
+        v.sort();
+        assert_eq!(
+            v,
+            [
+                ("big".to_string(), false),
+                ("big.0".to_string(), true),
+                ("big.1".to_string(), true),
+                ("big.2".to_string(), true),
+            ]
+        );
+
+        fn set_big_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "x".repeat(8000));
+            Response::builder().body(Body::empty())
+        }
+        fn shrink_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session().get("foo").unwrap().len(), 8000);
+            req.session_mut().remove("foo");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn max_size() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+
+        let mut app = MiddlewareBuilder::new(set_big_session);
+        app.add(Middleware::new());
+        app.add(
+            SessionMiddleware::builder("big", test_key())
+                .max_size(4000)
+                .build(),
+        );
+        assert!(app.call(&mut req).is_err());
+
+        fn set_big_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "x".repeat(8000));
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn size_policy() {
+        use crate::SizePolicy;
+
+        let session = |size_policy| {
+            let mut req = MockRequest::new(Method::POST, "/articles");
+            let mut app = MiddlewareBuilder::new(set_big_session);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("big", test_key())
+                    .max_size(4000)
+                    .size_policy(size_policy)
+                    .build(),
+            );
+            let response = app.call(&mut req).ok()?;
+            let v = response.headers().get(header::SET_COOKIE).unwrap();
+            req.header(header::COOKIE, v.to_str().unwrap());
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(SessionMiddleware::new("big", test_key(), false));
+            let response = app.call(&mut req).unwrap();
+            let keys = response.headers().get("x-keys").unwrap();
+            Some(keys.to_str().unwrap().to_string())
+        };
+
+        assert_eq!(session(SizePolicy::Fail), None);
+        assert_eq!(session(SizePolicy::evict(&["b"])), None);
+        assert_eq!(
+            session(SizePolicy::evict(&["c", "a", "b"])),
+            Some("b".to_string())
+        );
+        let truncate = SizePolicy::truncate(|data, max_size| {
+            for value in data.values_mut() {
+                value.truncate(max_size / 4);
+            }
+        });
+        assert_eq!(session(truncate), Some("a,b".to_string()));
+        let ineffective = SizePolicy::truncate(|_, _| {});
+        assert_eq!(session(ineffective), None);
+
+        fn set_big_session(req: &mut dyn RequestExt) -> HttpResult {
+            let session = req.session_mut();
+            session.insert("a".to_string(), "x".repeat(3000));
+            session.insert("b".to_string(), "x".repeat(2000));
+            Response::builder().body(Body::empty())
+        }
+        fn handler(req: &mut dyn RequestExt) -> HttpResult {
+            let mut keys = req.session().keys().cloned().collect::<Vec<_>>();
+            keys.sort();
+            Response::builder()
+                .header("x-keys", keys.join(","))
+                .body(Body::empty())
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn format_migration() {
+        use crate::SessionFormat;
+
+        let mut req = MockRequest::new(Method::POST, "/articles");
+
+        let mut app = MiddlewareBuilder::new(set_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("migrate", test_key(), false));
+        let response = app.call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("migrate", test_key())
+                    .format(SessionFormat::Json)
+                    .migrate(|format, data| {
+                        assert_eq!(format, SessionFormat::Delimited);
+                        data.insert("migrated".to_string(), "yes".to_string());
+                    })
+                    .build(),
+            );
+            app
+        };
+
+        let response = app(migrated_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        // Already migrated, so neither the hook nor a re-write happens
+        let response = app(migrated_session).call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn migrated_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(*req.session().get("foo").unwrap(), "bar");
+            assert_eq!(*req.session().get("migrated").unwrap(), "yes");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn custom_codec() {
+        use crate::{Error, SessionCodec, SessionFormat};
+
+        struct Prefixed;
+
+        impl SessionCodec for Prefixed {
+            fn encode(&self, data: &HashMap<String, String>) -> String {
+                let options = Default::default();
+                format!("v1.{}", SessionFormat::Delimited.encode(data, options))
+            }
+
+            fn decode(&self, value: &str) -> Result<HashMap<String, String>, Error> {
+                let value = value.strip_prefix("v1.");
+                let value = value.ok_or_else(|| Error::InvalidSession("no prefix".into()))?;
+                SessionFormat::decode(value).map(|(_, data)| data)
+            }
+        }
+
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("codec", test_key())
+                    .codec(Prefixed)
+                    .build(),
+            );
+            app
+        };
+
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        let response = app(set_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        assert!(v.to_str().unwrap().contains("v1."));
+        req.header(header::COOKIE, v.to_str().unwrap());
+        app(check_session).call(&mut req).unwrap();
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn check_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(*req.session().get("foo").unwrap(), "bar");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn embedded_expiry() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+
+        let mut app = MiddlewareBuilder::new(set_session);
+        app.add(Middleware::new());
+        app.add(
+            SessionMiddleware::builder("expiring", test_key())
+                .lifetime(Duration::seconds(-10))
+                .build(),
+        );
+        let response = app.call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        let app = |clock_skew, handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("expiring", test_key())
+                    .clock_skew(clock_skew)
+                    .build(),
+            );
+            app
+        };
+        assert!(app(Duration::ZERO, expired_session).call(&mut req).is_ok());
+        assert!(app(Duration::minutes(1), use_session)
+            .call(&mut req)
+            .is_ok());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn expired_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.session().is_empty());
+            Response::builder().body(Body::empty())
+        }
+        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(*req.session().get("foo").unwrap(), "bar");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+
+        let app = |idle_timeout, handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("idle", test_key())
+                    .lifetime(Duration::hours(12))
+                    .idle_timeout(idle_timeout)
+                    .build(),
+            );
+            app
+        };
+
+        let response = app(Duration::minutes(30), set_session)
+            .call(&mut req)
+            .unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        // Active sessions are re-issued even without changes
+        let response = app(Duration::minutes(30), use_session)
+            .call(&mut req)
+            .unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_some());
+
+        let response = app(Duration::seconds(-10), expired_session)
+            .call(&mut req)
+            .unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(*req.session().get("foo").unwrap(), "bar");
+            Response::builder().body(Body::empty())
+        }
+        fn expired_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.session().is_empty());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    struct TestClock(Mutex<OffsetDateTime>);
+
+    impl TestClock {
+        fn advance(&self, duration: Duration) {
+            *self.0.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> OffsetDateTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn clock() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        let clock = Arc::new(TestClock(Mutex::new(OffsetDateTime::UNIX_EPOCH)));
+
+        let app = |clock: &Arc<TestClock>, handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("clock", test_key())
+                    .lifetime(Duration::hours(12))
+                    .idle_timeout(Duration::minutes(30))
+                    .clock(clock.clone())
+                    .build(),
+            );
+            app
+        };
+
+        let response = app(&clock, set_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        // Each use within the idle timeout extends the session
+        for _ in 0..30 {
+            clock.advance(Duration::minutes(20));
+            let response = app(&clock, use_session).call(&mut req).unwrap();
+            let v = response.headers().get(header::SET_COOKIE).unwrap();
+            req.header(header::COOKIE, v.to_str().unwrap());
+        }
+        // Expires at the timeout itself, like values and store entries
+        clock.advance(Duration::minutes(30));
+        assert!(app(&clock, expired_session).call(&mut req).is_ok());
+
+        // Store entries expire by the same clock
+        let store = MemoryStore::new().clock(clock.clone());
+        let data = HashMap::new();
+        store
+            .save("a", &data, std::time::Duration::from_secs(60))
+            .unwrap();
+        assert!(store.load("a").unwrap().is_some());
+        clock.advance(Duration::minutes(1));
+        assert!(store.load("a").unwrap().is_none());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(*req.session().get("foo").unwrap(), "bar");
+            Response::builder().body(Body::empty())
+        }
+        fn expired_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.session().is_empty());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn expiring_values() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        let clock = Arc::new(TestClock(Mutex::new(OffsetDateTime::UNIX_EPOCH)));
+        let app = |clock_skew, handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("ttl", test_key())
+                    .clock(clock.clone())
+                    .clock_skew(clock_skew)
+                    .build(),
+            );
+            app
+        };
+
+        let response = app(Duration::ZERO, set_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        clock.advance(Duration::minutes(4));
+        let response = app(Duration::ZERO, unexpired).call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+        clock.advance(Duration::minutes(1));
+        let response = app(Duration::ZERO, expired).call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+        assert!(app(Duration::minutes(2), unexpired).call(&mut req).is_ok());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_set_with_ttl("code", "123456", Duration::minutes(5));
+            req.session_mut()
+                .insert("user".to_string(), "alice".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn unexpired(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session().len(), 2);
+            assert_eq!(req.session()["code"], "123456");
+            Response::builder().body(Body::empty())
+        }
+        fn expired(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session().len(), 1);
+            assert_eq!(req.session()["user"], "alice");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn prune() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        req.header(
+            header::COOKIE,
+            &encode_session(
+                &[("flag:old", "1"), ("flag:new", "1"), ("user", "alice")]
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+                &test_key(),
+                "pruned",
+            )
+            .to_string(),
+        );
+
+        let mut app = MiddlewareBuilder::new(set_session);
+        app.add(Middleware::new());
+        app.add(
+            SessionMiddleware::builder("pruned", test_key())
+                .prune(|data| data.retain(|k, _| k != "flag:old"))
+                .build(),
+        );
+        let response = app.call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+        let data = decode_session(cookie.value(), &test_key(), "pruned")
+            .unwrap()
+            .unwrap();
+        assert_eq!(data.len(), 3);
+        assert!(!data.contains_key("flag:old"));
+        assert_eq!(data["count"], "1");
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            // Pruning happens after the handler, which still sees old keys
+            assert_eq!(req.session().len(), 3);
+            req.session_mut()
+                .insert("count".to_string(), "1".to_string());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "jwt")]
+    fn jwt() {
+        let app = |store: Option<&Arc<MemoryStore>>,
+                   handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut builder = SessionMiddleware::builder("jwt", test_key())
+                .lifetime(Duration::hours(1))
+                .jwt();
+            if let Some(store) = store {
+                builder = builder.store(store.clone());
+            }
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(builder.build());
+            app
+        };
+        let claims = |token: &str| {
+            let claims = token.split('.').nth(1).unwrap();
+            let json = base64::decode_config(claims, base64::URL_SAFE_NO_PAD).unwrap();
+            serde_json::from_slice::<serde_json::Value>(&json).unwrap()
+        };
+
+        let store = Arc::new(MemoryStore::new());
+        for store in [None, Some(&store)] {
+            let mut req = MockRequest::new(Method::POST, "/articles");
+            let response = app(store, set_session).call(&mut req).unwrap();
+            let v = response.headers().get(header::SET_COOKIE).unwrap();
+            let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+            let claims = claims(cookie.value());
+            assert!(claims["jti"].is_string());
+            assert_eq!(
+                claims["user"].as_str(),
+                store.map_or(Some("alice"), |_| None)
+            );
+            assert_eq!(claims["exp"].is_number(), store.is_none());
+
+            req.header(header::COOKIE, &cookie.stripped().to_string());
+            assert!(app(store, use_session).call(&mut req).is_ok());
+
+            // Tokens signed with another key are ignored
+            let token = cookie.value().replace('.', ".x");
+            req.header(header::COOKIE, &format!("jwt={token}"));
+            assert!(app(store, new_session).call(&mut req).is_ok());
+        }
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("user".to_string(), "alice".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session()["user"], "alice");
+            assert_eq!(req.session_state(), SessionState::Restored);
+            Response::builder().body(Body::empty())
+        }
+        fn new_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.session().is_empty());
+            assert_eq!(req.session_state(), SessionState::InvalidSignature);
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn import() {
+        struct Legacy;
+
+        impl SessionImporter for Legacy {
+            fn import(&self, value: &str) -> Option<HashMap<String, String>> {
+                let (key, value) = value.split_once(':')?;
+                Some(std::iter::once((key.to_string(), value.to_string())).collect())
+            }
+        }
+
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("native", test_key())
+                    .import("legacy", Legacy)
+                    .build(),
+            );
+            app
+        };
+
+        let mut req = MockRequest::new(Method::GET, "/");
+        req.header(header::COOKIE, "legacy=user:alice");
+        let response = app(imported_session).call(&mut req).unwrap();
+        let cookies = response.headers().get_all(header::SET_COOKIE);
+        let cookies = cookies.iter().collect::<Vec<_>>();
+        assert_eq!(cookies.len(), 1);
+        let cookie = Cookie::parse(cookies[0].to_str().unwrap()).unwrap();
+        assert_eq!(cookie.name(), "native");
+
+        // The native session takes precedence once it exists
+        let native = cookie.stripped().to_string();
+        req.header(header::COOKIE, &format!("{native}; legacy=user:bob"));
+        assert!(app(imported_session).call(&mut req).is_ok());
+
+        req.header(header::COOKIE, "legacy=invalid");
+        assert!(app(new_session).call(&mut req).is_ok());
+
+        fn imported_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session()["user"], "alice");
+            assert_eq!(req.session_state(), SessionState::Restored);
+            Response::builder().body(Body::empty())
+        }
+        fn new_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.session().is_empty());
+            assert_eq!(req.session_state(), SessionState::New);
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn legacy_cookies() {
+        struct Plain;
+
+        impl SessionImporter for Plain {
+            fn import(&self, value: &str) -> Option<HashMap<String, String>> {
+                let (key, value) = value.split_once(':')?;
+                Some(std::iter::once((key.to_string(), value.to_string())).collect())
+            }
+        }
+
+        let clock = Arc::new(TestClock(Mutex::new(OffsetDateTime::UNIX_EPOCH)));
+        let until = OffsetDateTime::UNIX_EPOCH + Duration::days(30);
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("legacy", test_key())
+                    .legacy_until(until, Plain)
+                    .clock(clock.clone())
+                    .build(),
+            );
+            app
+        };
+
+        let mut req = MockRequest::new(Method::GET, "/");
+        req.header(header::COOKIE, "legacy=user:alice");
+        let response = app(legacy_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+        let data = decode_session(cookie.value(), &test_key(), "legacy")
+            .unwrap()
+            .unwrap();
+        assert_eq!(data["user"], "alice");
+
+        req.header(header::COOKIE, "legacy=invalid");
+        assert!(app(invalid_session).call(&mut req).is_ok());
+
+        clock.advance(Duration::days(30));
+        req.header(header::COOKIE, "legacy=user:alice");
+        assert!(app(invalid_session).call(&mut req).is_ok());
+
+        fn legacy_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session()["user"], "alice");
+            assert_eq!(req.session_state(), SessionState::Restored);
+            Response::builder().body(Body::empty())
+        }
+        fn invalid_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.session().is_empty());
+            assert_eq!(req.session_state(), SessionState::InvalidSignature);
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn bypass() {
+        let mut req = MockRequest::new(Method::GET, "/health");
+        req.header(header::COOKIE, "bypassed=invalid");
+
+        let mut app = MiddlewareBuilder::new(handler);
+        app.add(Middleware::new());
+        app.add(
+            SessionMiddleware::builder("bypassed", test_key())
+                .bypass(|req| req.path() == "/health")
+                .build(),
+        );
+        let response = app.call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        fn handler(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session_state(), SessionState::New);
+            assert_eq!(req.session_info().payload_size, 0);
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn sampling() {
+        let new_key = Key::derive_from(&[1; 32]);
+        let app = || {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("sampled", new_key.clone())
+                    .old_key(test_key())
+                    .sample_if(|req| req.cookies().get("beta").is_some())
+                    .build(),
+            );
+            app.add(SessionMiddleware::new("sampled", test_key(), false));
+            app
+        };
+
+        let mut req = MockRequest::new(Method::GET, "/");
+        let response = app().call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+        assert_eq!(
+            decode_session(cookie.value(), &test_key(), "sampled")
+                .unwrap()
+                .unwrap()["visits"],
+            "1"
+        );
+
+        // Sampled requests read the old cookie, and write it with the new key
+        req.header(header::COOKIE, &format!("beta=1; {}", cookie.stripped()));
+        let response = app().call(&mut req).unwrap();
+        let v = response.headers().get_all(header::SET_COOKIE);
+        assert_eq!(v.iter().count(), 1);
+        let v = v.iter().next().unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+        let data = decode_session(cookie.value(), &new_key, "sampled")
+            .unwrap()
+            .unwrap();
+        assert_eq!(data["visits"], "2");
+
+        fn handler(req: &mut dyn RequestExt) -> HttpResult {
+            let visits = req
+                .session()
+                .get("visits")
+                .map_or(0, |v| v.parse().unwrap());
+            req.session_mut()
+                .insert("visits".to_string(), (visits + 1).to_string());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn sampling_without_fallback() {
+        let mut app = MiddlewareBuilder::new(handler);
+        app.add(Middleware::new());
+        app.add(
+            SessionMiddleware::builder("sampled", test_key())
+                .sample_if(|req| req.cookies().get("beta").is_some())
+                .build(),
+        );
+
+        let mut req = MockRequest::new(Method::GET, "/");
+        let response = app.call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        req.header(header::COOKIE, "beta=1");
+        let response = app.call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_some());
+
+        fn handler(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.session().get("user").is_none());
+            req.session_mut()
+                .insert("user".to_string(), "1".to_string());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn vary() {
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("vary", test_key())
+                    .vary("Cookie")
+                    .build(),
+            );
+            app
+        };
+        let vary = |handler| {
+            let mut req = MockRequest::new(Method::GET, "/");
+            let response = app(handler).call(&mut req).unwrap();
+            let vary = response.headers().get_all(header::VARY);
+            vary.iter()
+                .map(|v| v.to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(vary(anonymous).is_empty());
+        assert_eq!(vary(personalized), ["Cookie"]);
+        assert_eq!(vary(listed), ["Accept-Encoding, cookie"]);
+        assert_eq!(vary(from_view), ["Cookie"]);
+
+        fn anonymous(_req: &mut dyn RequestExt) -> HttpResult {
+            Response::builder().body(Body::empty())
+        }
+        fn personalized(req: &mut dyn RequestExt) -> HttpResult {
+            let user = req.session().get("user").cloned().unwrap_or_default();
+            Response::builder().body(Body::from_vec(user.into_bytes()))
+        }
+        fn listed(req: &mut dyn RequestExt) -> HttpResult {
+            req.session();
+            Response::builder()
+                .header(header::VARY, "Accept-Encoding, cookie")
+                .body(Body::empty())
+        }
+        // Like auth or rate-limit middleware reading the loaded session
+        fn from_view(req: &mut dyn RequestExt) -> HttpResult {
+            let view = req.extensions().get::<crate::SessionView>().unwrap();
+            let user = view.get("user").cloned().unwrap_or_default();
+            Response::builder().body(Body::from_vec(user.into_bytes()))
+        }
+    }
+
+    #[test]
+    fn tenants() {
+        struct Tenant;
+
+        impl conduit_middleware::Middleware for Tenant {
+            fn before(&self, req: &mut dyn RequestExt) -> conduit_middleware::BeforeResult {
+                let tenant = req.headers().get("x-tenant").map(|v| v.to_str().unwrap());
+                if let Some(tenant) = tenant.map(str::to_string) {
+                    req.mut_extensions().insert(SessionTenant(tenant));
+                }
+                Ok(())
+            }
+        }
+
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(Tenant);
+            app.add(SessionMiddleware::new("tenant", test_key(), false));
+            app
+        };
+
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        req.header("x-tenant", "acme");
+        let response = app(set_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+        assert!(app(use_session).call(&mut req).is_ok());
+
+        req.header("x-tenant", "globex");
+        assert!(app(new_session).call(&mut req).is_ok());
+
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        req.header(header::COOKIE, v.to_str().unwrap());
+        assert!(app(new_session).call(&mut req).is_ok());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("user".to_string(), "alice".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session()["user"], "alice");
+            Response::builder().body(Body::empty())
+        }
+        fn new_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.session().is_empty());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn custom_signer() {
+        // Accepts values of the retired "v1" scheme, but re-signs them
+        struct Versioned;
+
+        impl Signer for Versioned {
+            fn sign(&self, _name: &str, value: &str) -> Result<String, BoxError> {
+                Ok(format!("v2.{value}"))
+            }
+
+            fn verify(&self, _name: &str, value: &str) -> Option<(String, bool)> {
+                match value.split_once('.')? {
+                    ("v2", value) => Some((value.to_string(), false)),
+                    ("v1", value) => Some((value.to_string(), true)),
+                    _ => None,
+                }
+            }
+        }
+
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("signer", test_key())
+                    .signer(Versioned)
+                    .build(),
+            );
+            app
+        };
+
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        let response = app(set_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+        assert!(cookie.value().starts_with("v2."));
+
+        let payload = &cookie.value()[3..];
+        req.header(header::COOKIE, &format!("signer=v2.{payload}"));
+        let response = app(use_session).call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        req.header(header::COOKIE, &format!("signer=v1.{payload}"));
+        let response = app(use_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        assert!(v.to_str().unwrap().starts_with("signer=v2."));
+
+        req.header(header::COOKIE, &format!("signer=v0.{payload}"));
+        assert!(app(new_session).call(&mut req).is_ok());
+
+        // Keys sign like the default signed cookies
+        let key = test_key();
+        let signed = key.sign("name", "value").unwrap();
+        assert_eq!(
+            key.verify("name", &signed),
+            Some(("value".to_string(), false))
+        );
+        assert_eq!(key.verify("name", &signed.replace("value", "other")), None);
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session()["foo"], "bar");
+            Response::builder().body(Body::empty())
+        }
+        fn new_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.session().is_empty());
+            assert_eq!(req.session_state(), SessionState::InvalidSignature);
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn bound_sessions() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+        req.header(header::USER_AGENT, "browser/1");
+
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("bound", test_key())
+                    .bind(|req| format!("{} {}", client_ip_prefix(req), user_agent(req)))
+                    .build(),
+            );
+            app
+        };
+
+        let response = app(empty_session).call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        let response = app(set_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        assert!(!v.to_str().unwrap().contains("browser"));
+        req.header(header::COOKIE, v.to_str().unwrap());
+        assert!(app(use_session).call(&mut req).is_ok());
+
+        req.header(header::USER_AGENT, "browser/2");
+        assert!(app(empty_session).call(&mut req).is_ok());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(*req.session().get("foo").unwrap(), "bar");
+            Response::builder().body(Body::empty())
+        }
+        fn empty_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.session().is_empty());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn sliding_expiration() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+
+        let app = |threshold, handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("sliding", test_key())
+                    .max_age(Duration::days(1))
+                    .sliding_expiration(threshold)
+                    .build(),
+            );
+            app
+        };
+
+        let response = app(Duration::hours(1), set_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        let response = app(Duration::hours(1), read_session)
+            .call(&mut req)
+            .unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        let response = app(Duration::days(2), read_session).call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_some());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn read_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(*req.session().get("foo").unwrap(), "bar");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn session_only() {
+        let mut req = MockRequest::new(Method::GET, "/");
+
+        let mut app = MiddlewareBuilder::new(modify_session);
+        app.add(Middleware::new());
+        app.add(
+            SessionMiddleware::builder("transient", test_key())
+                .session_only()
+                .build(),
+        );
+        let response = app.call(&mut req).unwrap();
+
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+        assert_eq!(cookie.max_age(), None);
+        assert_eq!(cookie.expires(), None);
+
+        fn modify_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_force_write();
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn auto_secure() {
+        let app = |trust_forwarded_proto| {
+            let mut app = MiddlewareBuilder::new(set_session);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("auto", test_key())
+                    .auto_secure(trust_forwarded_proto)
+                    .build(),
+            );
+            app
+        };
+        let secure = |app: MiddlewareBuilder, req: &mut MockRequest| {
+            let response = app.call(req).unwrap();
+            let v = response.headers().get(header::SET_COOKIE).unwrap();
+            Cookie::parse(v.to_str().unwrap().to_string())
+                .unwrap()
+                .secure()
+        };
+
+        let mut req = MockRequest::new(Method::GET, "/");
+        assert_eq!(secure(app(true), &mut req), None);
+
+        req.header("x-forwarded-proto", "https");
+        assert_eq!(secure(app(false), &mut req), None);
+        assert_eq!(secure(app(true), &mut req), Some(true));
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn partitioned() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        let mut app = MiddlewareBuilder::new(set_session);
+        app.add(Middleware::new());
+        app.add(
+            SessionMiddleware::builder("embedded", test_key())
+                .secure(true)
+                .same_site(SameSite::None)
+                .partitioned(true)
+                .build(),
+        );
+
+        let response = app.call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        assert!(v.to_str().unwrap().ends_with("; Partitioned"));
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn scoped_removal() {
+        let mut req = MockRequest::new(Method::POST, "/app/logout");
+
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("scoped", test_key())
+                    .path("/app")
+                    .domain("example.com")
+                    .build(),
+            );
+            app
+        };
+
+        let response = app(set_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        let response = app(clear_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+        assert_eq!(cookie.value(), "");
+        assert_eq!(cookie.path(), Some("/app"));
+        assert_eq!(cookie.domain(), Some("example.com"));
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn clear_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.clear_session();
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn clear_session() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(SessionMiddleware::new("logout", test_key(), false));
+            app
+        };
+
+        let response = app(set_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        let response = app(clear_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+        assert_eq!(cookie.name(), "logout");
+        assert_eq!(cookie.value(), "");
+        assert_eq!(cookie.max_age(), Some(Duration::ZERO));
+        assert_eq!(cookie.path(), Some("/"));
+
+        let response = app(clear_and_set_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+        assert_ne!(cookie.value(), "");
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn clear_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.clear_session();
+            assert!(req.session().is_empty());
+            Response::builder().body(Body::empty())
+        }
+        fn clear_and_set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.clear_session();
+            req.session_mut()
+                .insert("baz".to_string(), "qux".to_string());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn cookie_prefixes() {
+        let cookie = |middleware| {
+            let mut req = MockRequest::new(Method::GET, "/");
+            let mut app = MiddlewareBuilder::new(modify_session);
+            app.add(Middleware::new());
+            app.add(middleware);
+            let response = app.call(&mut req).unwrap();
+            let v = response.headers().get(header::SET_COOKIE).unwrap();
+            Cookie::parse(v.to_str().unwrap().to_string()).unwrap()
+        };
+
+        let host = cookie(
+            SessionMiddleware::builder("__Host-sid", test_key())
+                .domain("example.com")
+                .path("/app")
+                .build(),
+        );
+        assert_eq!(host.name(), "__Host-sid");
+        assert_eq!(host.secure(), Some(true));
+        assert_eq!(host.domain(), None);
+        assert_eq!(host.path(), Some("/"));
+
+        let secure = cookie(
+            SessionMiddleware::builder("__Secure-sid", test_key())
+                .path("/app")
+                .build(),
+        );
+        assert_eq!(secure.secure(), Some(true));
+        assert_eq!(secure.path(), Some("/app"));
+
+        let prefixed = cookie(
+            SessionMiddleware::builder("sid", test_key())
+                .host_prefix(true)
+                .build(),
+        );
+        assert_eq!(prefixed.name(), "__Host-sid");
+        assert_eq!(prefixed.secure(), Some(true));
+
+        fn modify_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_force_write();
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "SameSite=None session cookies must be Secure")]
+    fn same_site_none_requires_secure() {
+        SessionMiddleware::builder("cross", test_key())
+            .same_site(SameSite::None)
+            .build();
+    }
+
+    #[test]
+    #[should_panic(expected = "partitioned session cookies must be Secure")]
+    fn partitioned_requires_secure() {
+        SessionMiddleware::builder("embedded", test_key())
+            .partitioned(true)
+            .build();
+    }
+
+    #[test]
+    fn manual_flush_control() {
+        let mut req = MockRequest::new(Method::GET, "/");
+
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(SessionMiddleware::new("flush", test_key(), false));
+            app
+        };
+
+        let response = app(unchanged).call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        let response = app(force).call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_some());
+
+        fn unchanged(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(!req.session_is_dirty());
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            assert!(req.session_is_dirty());
+            req.session_mark_clean();
+            assert!(!req.session_is_dirty());
+            Response::builder().body(Body::empty())
+        }
+        fn force(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_force_write();
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn missing_middleware() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        assert!(req.try_session().is_none());
+        assert!(req.try_session_mut().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn compressed() {
+        let mut req = MockRequest::new(Method::POST, "/articles");
+
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("deflated", test_key())
+                    .compress(100)
+                    .build(),
+            );
+            app
+        };
+
+        let response = app(set_session).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+        assert!(cookie.value().len() < 1000);
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        assert!(app(use_session).call(&mut req).is_ok());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "x".repeat(4000));
+            Response::builder().body(Body::empty())
+        }
+        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(*req.session().get("foo").unwrap(), "x".repeat(4000));
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn no_equals() {
+        let e = {
+            let mut map = HashMap::new();
+            map.insert("a".to_string(), "bc".to_string());
+            SessionMiddleware::encode(&map)
+        };
+        assert!(!e.ends_with('='));
+
+        let m = SessionMiddleware::decode(Cookie::new("foo", e));
+        assert_eq!(*m.get("a").unwrap(), "bc");
+    }
+
+    #[test]
+    fn builder_attributes() {
+        let mut req = MockRequest::new(Method::GET, "/");
+
+        let mut app = MiddlewareBuilder::new(modify_session);
+        app.add(Middleware::new());
+        app.add(
+            SessionMiddleware::builder("built", test_key())
+                .secure(true)
+                .http_only(false)
+                .same_site(SameSite::Lax)
+                .path("/app")
+                .domain("example.com")
+                .max_age(Duration::hours(1))
+                .build(),
+        );
+        let response = app.call(&mut req).unwrap();
+
+        let v = response
+            .headers()
+            .get(header::SET_COOKIE)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let cookie = Cookie::parse(v).unwrap();
+        assert_eq!(cookie.name(), "built");
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.http_only(), None);
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        assert_eq!(cookie.path(), Some("/app"));
+        assert_eq!(cookie.domain(), Some("example.com"));
+        assert_eq!(cookie.max_age(), Some(Duration::hours(1)));
+
+        fn modify_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_force_write();
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn dirty_tracking() {
+        let mut req = MockRequest::new(Method::GET, "/");
+
+        let mut app = MiddlewareBuilder::new(read_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("dirty", test_key(), false));
+        let response = app.call(&mut req).unwrap();
+
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        let mut app = MiddlewareBuilder::new(borrow_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("dirty", test_key(), false));
+        let response = app.call(&mut req).unwrap();
+
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        let mut app = MiddlewareBuilder::new(modify_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("dirty", test_key(), false));
+        let response = app.call(&mut req).unwrap();
+
+        assert!(response.headers().get(header::SET_COOKIE).is_some());
+
+        fn read_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session();
+            Response::builder().body(Body::empty())
+        }
+        fn borrow_session(req: &mut dyn RequestExt) -> HttpResult {
+            let session = req.session_mut();
+            session.insert("foo".to_string(), "bar".to_string());
+            session.remove("foo");
+            Response::builder().body(Body::empty())
+        }
+        fn modify_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn suppress_write() {
+        let mut req = MockRequest::new(Method::GET, "/health");
+
+        let mut app = MiddlewareBuilder::new(suppressed);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("quiet", test_key(), false));
+        let response = app.call(&mut req).unwrap();
+
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        fn suppressed(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            req.session_force_write();
+            req.session_options().suppress_write();
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn named_sessions() {
+        let mut req = MockRequest::new(Method::GET, "/");
+
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(SessionMiddleware::new("auth", test_key(), false));
+            app.add(SessionMiddleware::new("prefs", test_key(), false));
+            app
+        };
+
+        let response = app(set_sessions).call(&mut req).unwrap();
+        let cookies = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|v| {
+                Cookie::parse(v.to_str().unwrap())
+                    .unwrap()
+                    .stripped()
+                    .to_string()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(cookies.len(), 2);
+        req.header(header::COOKIE, &cookies.join("; "));
+
+        assert!(app(use_sessions).call(&mut req).is_ok());
+
+        fn set_sessions(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("user".to_string(), "1".to_string());
+            req.session_named_mut("prefs")
+                .insert("theme".to_string(), "dark".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn use_sessions(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session_named("auth").get("user").unwrap(), "1");
+            assert_eq!(req.session().get("theme"), None);
+            assert_eq!(req.session_named("prefs").get("theme").unwrap(), "dark");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn session_view() {
+        use crate::SessionView;
+
+        let mut req = MockRequest::new(Method::GET, "/");
+        let mut app = MiddlewareBuilder::new(set_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("view", test_key(), false));
+        let response = app.call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        let mut app = MiddlewareBuilder::new(handler);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("view", test_key(), false));
+        let response = app.call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("user".to_string(), "alice".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn handler(req: &mut dyn RequestExt) -> HttpResult {
+            let loaded = req.extensions().get::<SessionView>().unwrap().clone();
+            req.session_volatile_mut()
+                .insert("seen".to_string(), "yes".to_string());
+            let view = req.session_view();
+            assert_eq!(view, loaded);
+            assert_eq!(view.get("user").unwrap(), "alice");
+            // Reads share the data, writes copy it
+            assert!(Arc::ptr_eq(&view.data, &loaded.data));
+            req.session_mut()
+                .insert("user".to_string(), "bob".to_string());
+            assert_eq!(loaded.get("user").unwrap(), "alice");
+            assert!(!Arc::ptr_eq(&req.session_view().data, &loaded.data));
+            req.session_mut()
+                .insert("user".to_string(), "alice".to_string());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn session_view_read_only() {
+        use crate::SessionView;
+
+        let mut req = MockRequest::new(Method::GET, "/");
+        let mut app = MiddlewareBuilder::new(set_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("view", test_key(), false));
+        let response = app.call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        let mut app = MiddlewareBuilder::new(handler);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("view", test_key(), false));
+        app.call(&mut req).unwrap();
+
+        // Nothing copied the data while handling a read-only request
+        let loaded = req.extensions().get::<SessionView>().unwrap();
+        assert!(Arc::ptr_eq(&req.session_view().data, &loaded.data));
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("user".to_string(), "alice".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn handler(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session().get("user").unwrap(), "alice");
+            let loaded = req.extensions().get::<SessionView>().unwrap();
+            assert!(Arc::ptr_eq(&req.session_view().data, &loaded.data));
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn bulk_operations() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        let mut app = MiddlewareBuilder::new(set_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("bulk", test_key(), false));
+        let response = app.call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(SessionMiddleware::new("bulk", test_key(), false));
+            app
+        };
+        let response = app(no_op).call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+        let response = app(prune).call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_some());
+
+        fn set_session(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_extend(vec![
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "2".to_string()),
+            ]);
+            Response::builder().body(Body::empty())
+        }
+        fn no_op(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session_iter().count(), 2);
+            assert_eq!(req.session_remove("missing"), None);
+            req.session_retain(|_, value| !value.is_empty());
+            Response::builder().body(Body::empty())
+        }
+        fn prune(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_retain(|key, _| key != "a");
+            assert_eq!(req.session_remove("b").as_deref(), Some("2"));
+            assert!(req.session().is_empty());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn session_info() {
+        let mut req = MockRequest::new(Method::GET, "/");
+
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(SessionMiddleware::new("info", test_key(), false));
+            app
+        };
+
+        let response = app(no_cookie).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let v = v.to_str().unwrap().to_string();
+        req.header(header::COOKIE, &v);
+        assert!(app(valid).call(&mut req).is_ok());
+
+        req.header(header::COOKIE, "info=tampered");
+        assert!(app(tampered).call(&mut req).is_ok());
+
+        let mut jar = cookie::CookieJar::new();
+        jar.signed_mut(&test_key())
+            .add(Cookie::new("info", "not base64!"));
+        let cookie = jar.get("info").unwrap().to_string();
+        req.header(header::COOKIE, &cookie);
+        assert!(app(corrupt).call(&mut req).is_ok());
+
+        fn no_cookie(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(*req.session_info(), SessionInfo::default());
+            assert_eq!(req.session_state(), SessionState::New);
+            req.session_mut()
+                .insert("foo".to_string(), "bar".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn valid(req: &mut dyn RequestExt) -> HttpResult {
+            let info = req.session_info();
+            assert!(info.cookie_present);
+            assert!(!info.invalid_signature);
+            assert!(info.payload_size > 0);
+            assert_eq!(info.decode_error, None);
+            assert_eq!(req.session_state(), SessionState::Restored);
+            Response::builder().body(Body::empty())
+        }
+        fn tampered(req: &mut dyn RequestExt) -> HttpResult {
+            let info = req.session_info();
+            assert!(info.cookie_present);
+            assert!(info.invalid_signature);
+            assert_eq!(info.payload_size, "tampered".len());
+            assert_eq!(req.session_state(), SessionState::InvalidSignature);
+            Response::builder().body(Body::empty())
+        }
+        fn corrupt(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session_state(), SessionState::CorruptPayload);
+            assert!(req.session().is_empty());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn error_policy() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        let mut jar = cookie::CookieJar::new();
+        jar.signed_mut(&test_key())
+            .add(Cookie::new("broken", "not base64!"));
+        let cookie = jar.get("broken").unwrap().to_string();
+        req.header(header::COOKIE, &cookie);
+
+        let app = |policy: ErrorPolicy| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("broken", test_key())
+                    .error_policy(policy)
+                    .build(),
+            );
+            app
+        };
+
+        assert!(app(ErrorPolicy::Ignore).call(&mut req).is_ok());
+
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let log = logged.clone();
+        let policy = ErrorPolicy::log(move |e| log.lock().unwrap().push(e.to_string()));
+        assert!(app(policy).call(&mut req).is_ok());
+        assert_eq!(logged.lock().unwrap().len(), 1);
+
+        assert!(app(ErrorPolicy::Fail).call(&mut req).is_err());
+
+        fn handler(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.session().is_empty());
+            assert!(req.session_info().decode_error.is_some());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn metrics() {
+        #[derive(Default)]
+        struct Counts {
+            read: AtomicUsize,
+            written: AtomicUsize,
+            invalid: AtomicUsize,
+        }
+
+        impl CookieMetrics for Counts {
+            fn session_read(&self) {
+                self.read.fetch_add(1, Ordering::SeqCst);
+            }
+            fn session_written(&self) {
+                self.written.fetch_add(1, Ordering::SeqCst);
+            }
+            fn invalid_signature(&self) {
+                self.invalid.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let counts = Arc::new(Counts::default());
+        let mut req = MockRequest::new(Method::GET, "/");
+        let app = |counts: &Arc<Counts>| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("metrics", test_key())
+                    .metrics(counts.clone())
+                    .build(),
+            );
+            app
+        };
+
+        let response = app(&counts).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+        app(&counts).call(&mut req).unwrap();
+        req.header(header::COOKIE, "metrics=tampered");
+        app(&counts).call(&mut req).unwrap();
+
+        assert_eq!(counts.read.load(Ordering::SeqCst), 1);
+        assert_eq!(counts.written.load(Ordering::SeqCst), 2);
+        assert_eq!(counts.invalid.load(Ordering::SeqCst), 1);
+
+        fn handler(req: &mut dyn RequestExt) -> HttpResult {
+            if req.session().is_empty() {
+                req.session_mut()
+                    .insert("foo".to_string(), "bar".to_string());
+            }
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn hooks() {
+        use crate::SessionHooks;
+
+        #[derive(Default)]
+        struct Events(Mutex<Vec<&'static str>>);
+
+        impl SessionHooks for Events {
+            fn on_create(&self, _: &dyn RequestExt, data: &mut HashMap<String, String>) {
+                data.insert("visitor".to_string(), "new".to_string());
+                self.0.lock().unwrap().push("create");
+            }
+            fn on_load(&self, _: &dyn RequestExt, data: &HashMap<String, String>) {
+                assert_eq!(data["visitor"], "new");
+                self.0.lock().unwrap().push("load");
+            }
+            fn on_invalid(&self, _: &dyn RequestExt, info: &SessionInfo) {
+                assert!(info.invalid_signature);
+                self.0.lock().unwrap().push("invalid");
+            }
+            fn on_write(&self, _: &dyn RequestExt, _: &HashMap<String, String>) {
+                self.0.lock().unwrap().push("write");
+            }
+        }
+
+        let events = Arc::new(Events::default());
+        let mut req = MockRequest::new(Method::GET, "/");
+        let app = |events: &Arc<Events>| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("hooks", test_key())
+                    .hooks(events.clone())
+                    .build(),
+            );
+            app
+        };
+
+        let response = app(&events).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+        app(&events).call(&mut req).unwrap();
+        req.header(header::COOKIE, "hooks=tampered");
+        app(&events).call(&mut req).unwrap();
+
+        assert_eq!(
+            *events.0.lock().unwrap(),
+            ["create", "write", "load", "invalid", "create", "write"]
+        );
+
+        fn handler(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session()["visitor"], "new");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn replay_protection() {
+        let counters = Arc::new(MemoryStore::new());
+        let app = |counters: &Arc<MemoryStore>, handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(
+                SessionMiddleware::builder("replay", test_key())
+                    .replay_protection(counters.clone())
+                    .build(),
+            );
+            app
+        };
+        let call = |handler, cookie: Option<&str>| {
+            let mut req = MockRequest::new(Method::GET, "/");
+            if let Some(cookie) = cookie {
+                req.header(header::COOKIE, cookie);
+            }
+            let response = app(&counters, handler).call(&mut req).unwrap();
+            let v = response.headers().get(header::SET_COOKIE)?;
+            let cookie = Cookie::parse(v.to_str().unwrap().to_string()).unwrap();
+            Some(cookie.stripped().to_string())
+        };
+
+        let first = call(increment, None).unwrap();
+        let second = call(increment, Some(&first)).unwrap();
+        // The first cookie was replaced, so it starts a new session
+        call(expect_empty, Some(&first));
+        call(expect_count, Some(&second));
+        call(clear, Some(&second));
+        call(expect_empty, Some(&second));
+
+        fn increment(req: &mut dyn RequestExt) -> HttpResult {
+            let count = req.session().get("count").map_or(0, |c| c.parse().unwrap());
+            req.session_mut()
+                .insert("count".to_string(), (count + 1).to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn expect_empty(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.session().is_empty());
+            Response::builder().body(Body::empty())
+        }
+        fn expect_count(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session()["count"], "2");
+            Response::builder().body(Body::empty())
+        }
+        fn clear(req: &mut dyn RequestExt) -> HttpResult {
+            req.clear_session();
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn volatile_values() {
+        let mut req = MockRequest::new(Method::GET, "/");
+
+        let app = |handler: fn(&mut dyn RequestExt) -> HttpResult| {
+            let mut app = MiddlewareBuilder::new(handler);
+            app.add(Middleware::new());
+            app.add(SessionMiddleware::new("volatile", test_key(), false));
+            app
+        };
+
+        let response = app(set_volatile).call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        let response = app(set_both).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+        assert!(app(check).call(&mut req).is_ok());
+
+        fn set_volatile(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_volatile_mut()
+                .insert("user".to_string(), "cached".to_string());
+            assert!(req.session().is_empty());
+            Response::builder().body(Body::empty())
+        }
+        fn set_both(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_volatile_mut()
+                .insert("user".to_string(), "cached".to_string());
+            req.session_mut()
+                .insert("user_id".to_string(), "1".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn check(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.session_volatile().is_empty());
+            assert_eq!(req.session().get("user_id").unwrap(), "1");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn out_of_band() {
+        let mut data = HashMap::new();
+        data.insert("user_id".to_string(), "1".to_string());
+
+        let cookie = encode_session(&data, &test_key(), "app");
+        assert_eq!(cookie.name(), "app");
+        let decoded = decode_session(cookie.value(), &test_key(), "app");
+        assert_eq!(decoded.unwrap(), Some(data));
+        let other_key = Key::derive_from(&[1; 32]);
+        assert!(decode_session(cookie.value(), &other_key, "app").is_err());
+
+        let mut req = MockRequest::new(Method::GET, "/");
+        req.header(header::COOKIE, &cookie.to_string());
+        let mut app = MiddlewareBuilder::new(use_session);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("app", test_key(), false));
+        let response = app.call(&mut req).unwrap();
+
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap().to_string()).unwrap();
+        let decoded = decode_session(cookie.value(), &test_key(), "app").unwrap();
+        let decoded = decoded.unwrap();
+        assert_eq!(decoded.get("visits").unwrap(), "1");
+        assert_eq!(decoded.len(), 2);
+
+        // Expired sessions aren't returned
+        let mut app = MiddlewareBuilder::new(use_session);
+        app.add(Middleware::new());
+        app.add(
+            SessionMiddleware::builder("app", test_key())
+                .lifetime(Duration::seconds(-10))
+                .build(),
+        );
+        let response = app.call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        let cookie = Cookie::parse(v.to_str().unwrap().to_string()).unwrap();
+        let decoded = decode_session(cookie.value(), &test_key(), "app");
+        assert_eq!(decoded.unwrap(), None);
+
+        fn use_session(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session().get("user_id").unwrap(), "1");
+            req.session_mut()
+                .insert("visits".to_string(), "1".to_string());
             Response::builder().body(Body::empty())
         }
     }
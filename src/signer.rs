@@ -0,0 +1,44 @@
+use std::sync::Arc;
+
+use conduit::BoxError;
+use cookie::{Cookie, CookieJar, Key};
+
+/// Signs and verifies session cookie values, for deployments that need
+/// another algorithm than the HMAC-SHA256 of the cookie crate, e.g. Ed25519
+/// keys held by an external crypto provider.
+pub trait Signer: Send + Sync + 'static {
+    /// Signs the value of the cookie `name`, returning the value to send.
+    fn sign(&self, name: &str, value: &str) -> Result<String, BoxError>;
+
+    /// Verifies a value returned by `sign`, returning the original value and
+    /// whether it should be signed again, e.g. because it was signed with a
+    /// retired key.
+    fn verify(&self, name: &str, value: &str) -> Option<(String, bool)>;
+}
+
+/// Signs values like the cookie crate's `SignedJar`.
+impl Signer for Key {
+    fn sign(&self, name: &str, value: &str) -> Result<String, BoxError> {
+        let mut jar = CookieJar::new();
+        jar.signed_mut(self)
+            .add(Cookie::new(name.to_string(), value.to_string()));
+        Ok(jar.get(name).unwrap().value().to_string())
+    }
+
+    fn verify(&self, name: &str, value: &str) -> Option<(String, bool)> {
+        let mut jar = CookieJar::new();
+        jar.add_original(Cookie::new(name.to_string(), value.to_string()));
+        let cookie = jar.signed(self).get(name)?;
+        Some((cookie.value().to_string(), false))
+    }
+}
+
+impl<S: Signer + ?Sized> Signer for Arc<S> {
+    fn sign(&self, name: &str, value: &str) -> Result<String, BoxError> {
+        (**self).sign(name, value)
+    }
+
+    fn verify(&self, name: &str, value: &str) -> Option<(String, bool)> {
+        (**self).verify(name, value)
+    }
+}
@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// What to do when the session cookie exceeds
+/// `SessionMiddlewareBuilder::max_size`. Browsers drop oversized cookies
+/// entirely, losing the whole session.
+#[derive(Clone, Default)]
+pub enum SizePolicy {
+    /// Fail the request with `Error::SessionTooLarge`.
+    #[default]
+    Fail,
+    /// Pass the session data and the maximum size to a callback that
+    /// shrinks it. The request fails if it is still too large afterwards.
+    Truncate(Arc<TruncateFn>),
+    /// Remove the listed keys one by one, in order, until the session fits.
+    /// The request fails if it is still too large without all of them.
+    Evict(Vec<String>),
+}
+
+type TruncateFn = dyn Fn(&mut HashMap<String, String>, usize) + Send + Sync;
+
+impl SizePolicy {
+    pub fn truncate<F>(truncate: F) -> Self
+    where
+        F: Fn(&mut HashMap<String, String>, usize) + Send + Sync + 'static,
+    {
+        SizePolicy::Truncate(Arc::new(truncate))
+    }
+
+    /// Evicts the low-priority `keys`, starting with the first.
+    pub fn evict(keys: &[&str]) -> Self {
+        SizePolicy::Evict(keys.iter().map(|key| key.to_string()).collect())
+    }
+
+    /// Shrinks `data` once, returning whether anything was attempted.
+    pub(crate) fn shrink(
+        &self,
+        data: &mut HashMap<String, String>,
+        max_size: usize,
+        attempt: usize,
+    ) -> bool {
+        match self {
+            SizePolicy::Fail => false,
+            SizePolicy::Truncate(truncate) if attempt == 0 => {
+                truncate(data, max_size);
+                true
+            }
+            SizePolicy::Truncate(_) => false,
+            SizePolicy::Evict(keys) => keys.iter().any(|key| data.remove(key).is_some()),
+        }
+    }
+}
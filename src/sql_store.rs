@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use conduit::{box_error, BoxError};
+
+use crate::{Clock, SessionMiddleware, SessionStore, SystemClock};
+
+/// A statement parameter passed to `SqlConnection`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SqlParam<'a> {
+    Text(&'a str),
+    Int(i64),
+}
+
+/// The few statements `SqlStore` needs from a database driver or pool.
+///
+/// Implementations run the SQL as given, with placeholders in the syntax of
+/// the configured `SqlDialect`. For example with the `postgres` crate:
+///
+/// ```ignore
+/// struct Postgres(Mutex<postgres::Client>);
+///
+/// fn params(params: &[SqlParam<'_>]) -> Vec<Box<dyn ToSql + Sync>> {
+///     let param = |param: &SqlParam<'_>| -> Box<dyn ToSql + Sync> {
+///         match *param {
+///             SqlParam::Text(text) => Box::new(text.to_string()),
+///             SqlParam::Int(int) => Box::new(int),
+///         }
+///     };
+///     params.iter().map(param).collect()
+/// }
+///
+/// impl SqlConnection for Postgres {
+///     fn execute(&self, sql: &str, params: &[SqlParam<'_>]) -> Result<u64, BoxError> {
+///         let params = params(params);
+///         let params = params.iter().map(|p| &**p).collect::<Vec<_>>();
+///         self.0.lock().unwrap().execute(sql, &params).map_err(box_error)
+///     }
+///
+///     fn query_text(&self, sql: &str, params: &[SqlParam<'_>]) -> Result<Option<String>, BoxError> {
+///         let params = params(params);
+///         let params = params.iter().map(|p| &**p).collect::<Vec<_>>();
+///         let row = self.0.lock().unwrap().query_opt(sql, &params).map_err(box_error)?;
+///         Ok(row.map(|row| row.get(0)))
+///     }
+/// }
+///
+/// let store = SqlStore::new(Postgres(Mutex::new(client)));
+/// store.create_table()?;
+/// ```
+pub trait SqlConnection: Send + Sync + 'static {
+    /// Runs a statement, returning the number of affected rows.
+    fn execute(&self, sql: &str, params: &[SqlParam<'_>]) -> Result<u64, BoxError>;
+
+    /// Runs a query selecting a single text column, returning its value in
+    /// the first row, if any.
+    fn query_text(&self, sql: &str, params: &[SqlParam<'_>]) -> Result<Option<String>, BoxError>;
+}
+
+impl<C: SqlConnection + ?Sized> SqlConnection for Arc<C> {
+    fn execute(&self, sql: &str, params: &[SqlParam<'_>]) -> Result<u64, BoxError> {
+        (**self).execute(sql, params)
+    }
+
+    fn query_text(&self, sql: &str, params: &[SqlParam<'_>]) -> Result<Option<String>, BoxError> {
+        (**self).query_text(sql, params)
+    }
+}
+
+/// The SQL syntax `SqlStore` generates, mostly for placeholders and upserts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SqlDialect {
+    /// `$1` placeholders, also for CockroachDB.
+    #[default]
+    Postgres,
+    MySql,
+    Sqlite,
+}
+
+/// A `SessionStore` keeping sessions in a SQL table, for deployments without
+/// Redis.
+///
+/// Rows hold the encoded session data and the expiry as Unix seconds.
+/// Expired rows are ignored when loading, but only deleted by
+/// `delete_expired`, which should run periodically, e.g. from a cron job.
+pub struct SqlStore<C> {
+    connection: C,
+    dialect: SqlDialect,
+    table: String,
+    clock: Box<dyn Clock>,
+}
+
+impl<C: SqlConnection> SqlStore<C> {
+    pub fn new(connection: C) -> Self {
+        SqlStore {
+            connection,
+            dialect: SqlDialect::default(),
+            table: "sessions".to_string(),
+            clock: Box::new(SystemClock),
+        }
+    }
+
+    /// Sets the SQL dialect, `SqlDialect::Postgres` by default.
+    pub fn dialect(mut self, dialect: SqlDialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Sets the table name, `sessions` by default. Since it is part of the
+    /// generated SQL, it may only contain ASCII letters, digits, `_` and `.`.
+    pub fn table(mut self, table: &str) -> Self {
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+        assert!(
+            !table.is_empty() && table.chars().all(valid),
+            "invalid table name `{}`",
+            table
+        );
+        self.table = table.to_string();
+        self
+    }
+
+    /// Expires sessions according to `clock` instead of the system clock.
+    pub fn clock<Cl: Clock>(mut self, clock: Cl) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// The statements creating the session table and its expiry index, for
+    /// migration tools. They do nothing if the table exists.
+    pub fn schema(&self) -> Vec<String> {
+        let table = &self.table;
+        let index = format!("{}_expires", table.replace('.', "_"));
+        match self.dialect {
+            SqlDialect::Postgres | SqlDialect::Sqlite => vec![
+                format!(
+                    "CREATE TABLE IF NOT EXISTS {} (id TEXT PRIMARY KEY, data TEXT NOT NULL, \
+                     expires BIGINT NOT NULL)",
+                    table
+                ),
+                format!(
+                    "CREATE INDEX IF NOT EXISTS {} ON {} (expires)",
+                    index, table
+                ),
+            ],
+            // MySQL has no `IF NOT EXISTS` for indexes
+            SqlDialect::MySql => vec![format!(
+                "CREATE TABLE IF NOT EXISTS {} (id VARCHAR(255) PRIMARY KEY, data TEXT NOT NULL, \
+                 expires BIGINT NOT NULL, INDEX {} (expires))",
+                table, index
+            )],
+        }
+    }
+
+    /// Runs the statements of `schema`.
+    pub fn create_table(&self) -> Result<(), BoxError> {
+        for sql in self.schema() {
+            self.connection.execute(&sql, &[])?;
+        }
+        Ok(())
+    }
+
+    /// Deletes expired sessions, returning how many were deleted.
+    pub fn delete_expired(&self) -> Result<u64, BoxError> {
+        let sql = format!(
+            "DELETE FROM {} WHERE expires <= {}",
+            self.table,
+            self.placeholder(1)
+        );
+        self.connection.execute(&sql, &[SqlParam::Int(self.now())])
+    }
+
+    fn placeholder(&self, n: usize) -> String {
+        match self.dialect {
+            SqlDialect::Postgres => format!("${n}"),
+            SqlDialect::MySql | SqlDialect::Sqlite => "?".to_string(),
+        }
+    }
+
+    fn now(&self) -> i64 {
+        self.clock.now().unix_timestamp()
+    }
+}
+
+impl<C: SqlConnection> SessionStore for SqlStore<C> {
+    fn load(&self, id: &str) -> Result<Option<HashMap<String, String>>, BoxError> {
+        let sql = format!(
+            "SELECT data FROM {} WHERE id = {} AND expires > {}",
+            self.table,
+            self.placeholder(1),
+            self.placeholder(2)
+        );
+        let params = [SqlParam::Text(id), SqlParam::Int(self.now())];
+        self.connection
+            .query_text(&sql, &params)?
+            .map(|value| SessionMiddleware::try_decode_value(&value).map_err(box_error))
+            .transpose()
+    }
+
+    fn save(
+        &self,
+        id: &str,
+        data: &HashMap<String, String>,
+        ttl: Duration,
+    ) -> Result<(), BoxError> {
+        let upsert = match self.dialect {
+            SqlDialect::Postgres | SqlDialect::Sqlite => {
+                "ON CONFLICT (id) DO UPDATE SET data = excluded.data, expires = excluded.expires"
+            }
+            SqlDialect::MySql => {
+                "ON DUPLICATE KEY UPDATE data = VALUES(data), expires = VALUES(expires)"
+            }
+        };
+        let sql = format!(
+            "INSERT INTO {} (id, data, expires) VALUES ({}, {}, {}) {}",
+            self.table,
+            self.placeholder(1),
+            self.placeholder(2),
+            self.placeholder(3),
+            upsert
+        );
+        let value = SessionMiddleware::encode(data);
+        let expires = self.now().saturating_add(ttl.as_secs() as i64);
+        let params = [
+            SqlParam::Text(id),
+            SqlParam::Text(&value),
+            SqlParam::Int(expires),
+        ];
+        self.connection.execute(&sql, &params).map(drop)
+    }
+
+    fn remove(&self, id: &str) -> Result<(), BoxError> {
+        let sql = format!(
+            "DELETE FROM {} WHERE id = {}",
+            self.table,
+            self.placeholder(1)
+        );
+        self.connection
+            .execute(&sql, &[SqlParam::Text(id)])
+            .map(drop)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use conduit::BoxError;
+
+    use super::{SqlConnection, SqlDialect, SqlParam, SqlStore};
+    use crate::SessionStore;
+
+    // Runs the statements of `SqlStore` against a map of id to data and
+    // expiry, logging them
+    #[derive(Default)]
+    struct Table {
+        rows: Mutex<HashMap<String, (String, i64)>>,
+        log: Mutex<Vec<String>>,
+    }
+
+    fn text(param: &SqlParam<'_>) -> String {
+        match param {
+            SqlParam::Text(text) => text.to_string(),
+            SqlParam::Int(_) => panic!("expected text"),
+        }
+    }
+
+    fn int(param: &SqlParam<'_>) -> i64 {
+        match param {
+            SqlParam::Int(int) => *int,
+            SqlParam::Text(_) => panic!("expected an integer"),
+        }
+    }
+
+    impl SqlConnection for Table {
+        fn execute(&self, sql: &str, params: &[SqlParam<'_>]) -> Result<u64, BoxError> {
+            self.log.lock().unwrap().push(sql.to_string());
+            let mut rows = self.rows.lock().unwrap();
+            if sql.starts_with("INSERT") {
+                let row = (text(&params[1]), int(&params[2]));
+                rows.insert(text(&params[0]), row);
+                Ok(1)
+            } else if sql.contains("WHERE id") {
+                Ok(rows.remove(&text(&params[0])).map_or(0, |_| 1))
+            } else if sql.contains("WHERE expires") {
+                let before = rows.len();
+                rows.retain(|_, (_, expires)| *expires > int(&params[0]));
+                Ok((before - rows.len()) as u64)
+            } else {
+                Ok(0)
+            }
+        }
+
+        fn query_text(
+            &self,
+            sql: &str,
+            params: &[SqlParam<'_>],
+        ) -> Result<Option<String>, BoxError> {
+            self.log.lock().unwrap().push(sql.to_string());
+            let rows = self.rows.lock().unwrap();
+            let row = rows.get(&text(&params[0]));
+            let row = row.filter(|(_, expires)| *expires > int(&params[1]));
+            Ok(row.map(|(data, _)| data.clone()))
+        }
+    }
+
+    #[test]
+    fn sql_store() {
+        let table = Arc::new(Table::default());
+        let store = SqlStore::new(table.clone());
+        store.create_table().unwrap();
+        let mut data = HashMap::new();
+        data.insert("foo".to_string(), "bar".to_string());
+
+        store.save("a", &data, Duration::from_secs(60)).unwrap();
+        store.save("b", &data, Duration::from_secs(0)).unwrap();
+        assert_eq!(store.load("a").unwrap(), Some(data));
+        assert_eq!(store.load("b").unwrap(), None);
+        assert_eq!(store.delete_expired().unwrap(), 1);
+
+        store.remove("a").unwrap();
+        assert_eq!(store.load("a").unwrap(), None);
+
+        let log = table.log.lock().unwrap();
+        assert_eq!(
+            log[2],
+            "INSERT INTO sessions (id, data, expires) VALUES ($1, $2, $3) \
+             ON CONFLICT (id) DO UPDATE SET data = excluded.data, expires = excluded.expires"
+        );
+        assert_eq!(
+            log[4],
+            "SELECT data FROM sessions WHERE id = $1 AND expires > $2"
+        );
+    }
+
+    #[test]
+    fn mysql() {
+        let table = Arc::new(Table::default());
+        let store = SqlStore::new(table.clone())
+            .dialect(SqlDialect::MySql)
+            .table("app.sessions");
+        assert_eq!(
+            store.schema(),
+            [
+                "CREATE TABLE IF NOT EXISTS app.sessions (id VARCHAR(255) PRIMARY KEY, \
+              data TEXT NOT NULL, expires BIGINT NOT NULL, INDEX app_sessions_expires (expires))"
+            ]
+        );
+        store.remove("a").unwrap();
+        let log = table.log.lock().unwrap();
+        assert_eq!(log[0], "DELETE FROM app.sessions WHERE id = ?");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid table name")]
+    fn invalid_table() {
+        SqlStore::new(Arc::new(Table::default())).table("sessions; DROP TABLE users");
+    }
+}
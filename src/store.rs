@@ -0,0 +1,493 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use conduit::BoxError;
+use cookie::time::OffsetDateTime;
+use cookie::{Cookie, CookieJar, Key};
+
+use crate::{Clock, SessionMiddleware, SystemClock};
+
+// The entry holding the encrypted data of `EncryptedStore`.
+const SEALED_KEY: &str = "\0sealed";
+
+/// Server-side storage for session data.
+///
+/// When a store is configured on `SessionMiddleware` the session cookie only
+/// carries the (signed) session id, and the data is loaded from and saved to
+/// the store.
+pub trait SessionStore: Send + Sync + 'static {
+    fn load(&self, id: &str) -> Result<Option<HashMap<String, String>>, BoxError>;
+    fn save(&self, id: &str, data: &HashMap<String, String>, ttl: Duration)
+        -> Result<(), BoxError>;
+    fn remove(&self, id: &str) -> Result<(), BoxError>;
+}
+
+impl<S: SessionStore + ?Sized> SessionStore for Arc<S> {
+    fn load(&self, id: &str) -> Result<Option<HashMap<String, String>>, BoxError> {
+        (**self).load(id)
+    }
+
+    fn save(
+        &self,
+        id: &str,
+        data: &HashMap<String, String>,
+        ttl: Duration,
+    ) -> Result<(), BoxError> {
+        (**self).save(id, data, ttl)
+    }
+
+    fn remove(&self, id: &str) -> Result<(), BoxError> {
+        (**self).remove(id)
+    }
+}
+
+/// A `SessionStore` keeping sessions in process memory.
+///
+/// Expired sessions are only dropped when they are next looked up, so this
+/// is mostly useful for tests and development.
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, Entry>>,
+    clock: Box<dyn Clock>,
+}
+
+struct Entry {
+    expires: OffsetDateTime,
+    data: HashMap<String, String>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore {
+            sessions: Mutex::default(),
+            clock: Box::new(SystemClock),
+        }
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Expires sessions according to `clock` instead of the system clock.
+    pub fn clock<C: Clock>(mut self, clock: C) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> Result<Option<HashMap<String, String>>, BoxError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(id) {
+            Some(entry) if entry.expires <= self.clock.now() => {
+                sessions.remove(id);
+                Ok(None)
+            }
+            Some(entry) => Ok(Some(entry.data.clone())),
+            None => Ok(None),
+        }
+    }
+
+    fn save(
+        &self,
+        id: &str,
+        data: &HashMap<String, String>,
+        ttl: Duration,
+    ) -> Result<(), BoxError> {
+        let expires = self.clock.now() + ttl;
+        let mut sessions = self.sessions.lock().unwrap();
+        let data = data.clone();
+        sessions.insert(id.to_string(), Entry { expires, data });
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> Result<(), BoxError> {
+        self.sessions.lock().unwrap().remove(id);
+        Ok(())
+    }
+}
+
+/// A bounded `SessionStore` keeping sessions in process memory, for
+/// single-node deployments.
+///
+/// Expired sessions are dropped when looked up or by `purge_expired`, and
+/// once `max_sessions` is reached the least recently used session is
+/// evicted to make room. Sessions don't survive a restart, and aren't
+/// shared between processes.
+pub struct LruStore {
+    state: Mutex<LruState>,
+    max_sessions: usize,
+    clock: Box<dyn Clock>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
+}
+
+#[derive(Default)]
+struct LruState {
+    sessions: HashMap<String, LruEntry>,
+    // Session ids by the tick of their last use, oldest first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+struct LruEntry {
+    entry: Entry,
+    used: u64,
+}
+
+impl LruState {
+    fn touch(&mut self, id: &str) {
+        self.tick += 1;
+        let entry = self.sessions.get_mut(id).unwrap();
+        self.recency.remove(&entry.used);
+        entry.used = self.tick;
+        self.recency.insert(self.tick, id.to_string());
+    }
+
+    fn remove(&mut self, id: &str) -> Option<Entry> {
+        let entry = self.sessions.remove(id)?;
+        self.recency.remove(&entry.used);
+        Some(entry.entry)
+    }
+}
+
+/// Counters of an `LruStore`, e.g. to export as metrics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Loads that found a live session.
+    pub hits: u64,
+    /// Loads of unknown or expired sessions.
+    pub misses: u64,
+    /// Live sessions dropped to stay within `max_sessions`.
+    pub evictions: u64,
+    /// Sessions dropped because their TTL passed.
+    pub expirations: u64,
+    /// Sessions currently held, including expired ones not yet dropped.
+    pub sessions: usize,
+}
+
+impl StoreStats {
+    /// The share of loads that found a live session, or 0 before any load.
+    pub fn hit_rate(&self) -> f64 {
+        let loads = self.hits + self.misses;
+        if loads == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / loads as f64
+    }
+}
+
+impl LruStore {
+    /// Holds at most `max_sessions` sessions, which must be positive.
+    pub fn new(max_sessions: usize) -> Self {
+        assert!(max_sessions > 0, "`max_sessions` must be positive");
+        LruStore {
+            state: Mutex::default(),
+            max_sessions,
+            clock: Box::new(SystemClock),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            expirations: AtomicU64::new(0),
+        }
+    }
+
+    /// Expires sessions according to `clock` instead of the system clock.
+    pub fn clock<C: Clock>(mut self, clock: C) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Drops all expired sessions, e.g. from a periodic background task, so
+    /// that they don't hold memory until they are evicted.
+    pub fn purge_expired(&self) {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        let expired = state
+            .sessions
+            .iter()
+            .filter(|(_, lru)| lru.entry.expires <= now)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        for id in &expired {
+            state.remove(id);
+        }
+        let expired = expired.len() as u64;
+        self.expirations.fetch_add(expired, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        StoreStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            expirations: self.expirations.load(Ordering::Relaxed),
+            sessions: self.state.lock().unwrap().sessions.len(),
+        }
+    }
+}
+
+impl SessionStore for LruStore {
+    fn load(&self, id: &str) -> Result<Option<HashMap<String, String>>, BoxError> {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        let expires = state.sessions.get(id).map(|lru| lru.entry.expires);
+        match expires {
+            Some(expires) if expires <= now => {
+                state.remove(id);
+                self.expirations.fetch_add(1, Ordering::Relaxed);
+            }
+            Some(_) => {
+                state.touch(id);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(state.sessions[id].entry.data.clone()));
+            }
+            None => {}
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        Ok(None)
+    }
+
+    fn save(
+        &self,
+        id: &str,
+        data: &HashMap<String, String>,
+        ttl: Duration,
+    ) -> Result<(), BoxError> {
+        let now = self.clock.now();
+        let entry = Entry {
+            expires: now + ttl,
+            data: data.clone(),
+        };
+        let mut state = self.state.lock().unwrap();
+        state.remove(id);
+        while state.sessions.len() >= self.max_sessions {
+            let oldest = state.recency.values().next().unwrap().clone();
+            let evicted = state.remove(&oldest).unwrap();
+            if evicted.expires <= now {
+                self.expirations.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        state
+            .sessions
+            .insert(id.to_string(), LruEntry { entry, used: 0 });
+        state.touch(id);
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> Result<(), BoxError> {
+        self.state.lock().unwrap().remove(id);
+        Ok(())
+    }
+}
+
+/// Encrypts session data before passing it to another `SessionStore`, so
+/// that a leaked database or Redis dump doesn't expose it.
+///
+/// The key can be the one of `SessionMiddleware` or a dedicated one. The
+/// data is bound to the session id, so it can't be moved to another session.
+/// Entries that fail decryption or decoding are treated as missing, so that
+/// their sessions are replaced.
+pub struct EncryptedStore<S> {
+    store: S,
+    key: Key,
+    old_keys: Vec<Key>,
+    accept_plaintext: bool,
+}
+
+impl<S: SessionStore> EncryptedStore<S> {
+    pub fn new(store: S, key: Key) -> Self {
+        EncryptedStore {
+            store,
+            key,
+            old_keys: Vec::new(),
+            accept_plaintext: false,
+        }
+    }
+
+    /// Also decrypts data encrypted with `key`, e.g. after rotating keys.
+    /// Such data is encrypted with the current key when next saved.
+    pub fn old_key(mut self, key: Key) -> Self {
+        self.old_keys.push(key);
+        self
+    }
+
+    /// Also reads unencrypted entries, e.g. written before encryption was
+    /// enabled, until they are encrypted when next saved. Only meant for the
+    /// migration, since such entries aren't bound to their session id.
+    pub fn accept_plaintext(mut self, accept_plaintext: bool) -> Self {
+        self.accept_plaintext = accept_plaintext;
+        self
+    }
+}
+
+impl<S: SessionStore> SessionStore for EncryptedStore<S> {
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn load(&self, id: &str) -> Result<Option<HashMap<String, String>>, BoxError> {
+        let mut data = match self.store.load(id)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let sealed = match data.remove(SEALED_KEY) {
+            Some(sealed) => sealed,
+            None if self.accept_plaintext => return Ok(Some(data)),
+            None => {
+                event!(WARN, "ignoring unencrypted stored session");
+                return Ok(None);
+            }
+        };
+        let mut jar = CookieJar::new();
+        jar.add_original(Cookie::new(id.to_string(), sealed));
+        let value = std::iter::once(&self.key)
+            .chain(&self.old_keys)
+            .find_map(|key| jar.private(key).get(id));
+        // Not an outage, so the session is replaced rather than failing the
+        // request on every visit
+        let value = match value {
+            Some(value) => value,
+            None => {
+                event!(WARN, "stored session failed decryption");
+                return Ok(None);
+            }
+        };
+        match SessionMiddleware::try_decode_value(value.value()) {
+            Ok(data) => Ok(Some(data)),
+            Err(error) => {
+                event!(WARN, %error, "undecodable stored session");
+                Ok(None)
+            }
+        }
+    }
+
+    fn save(
+        &self,
+        id: &str,
+        data: &HashMap<String, String>,
+        ttl: Duration,
+    ) -> Result<(), BoxError> {
+        let mut jar = CookieJar::new();
+        let value = SessionMiddleware::encode(data);
+        jar.private_mut(&self.key)
+            .add(Cookie::new(id.to_string(), value));
+        let sealed = jar.get(id).unwrap().value().to_string();
+        let sealed = std::iter::once((SEALED_KEY.to_string(), sealed)).collect();
+        self.store.save(id, &sealed, ttl)
+    }
+
+    fn remove(&self, id: &str) -> Result<(), BoxError> {
+        self.store.remove(id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use cookie::time::OffsetDateTime;
+    use cookie::{Cookie, CookieJar, Key};
+
+    use super::{EncryptedStore, LruStore, MemoryStore, SessionStore, StoreStats, SEALED_KEY};
+    use crate::Clock;
+
+    struct TestClock(Mutex<OffsetDateTime>);
+
+    impl Clock for TestClock {
+        fn now(&self) -> OffsetDateTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn memory_store() {
+        let store = MemoryStore::new();
+        let mut data = HashMap::new();
+        data.insert("foo".to_string(), "bar".to_string());
+
+        store.save("a", &data, Duration::from_secs(60)).unwrap();
+        store.save("b", &data, Duration::from_secs(0)).unwrap();
+        assert_eq!(store.load("a").unwrap(), Some(data));
+        assert_eq!(store.load("b").unwrap(), None);
+
+        store.remove("a").unwrap();
+        assert_eq!(store.load("a").unwrap(), None);
+    }
+
+    #[test]
+    fn lru_store() {
+        let clock = Arc::new(TestClock(Mutex::new(OffsetDateTime::now_utc())));
+        let store = LruStore::new(2).clock(clock.clone());
+        let mut data = HashMap::new();
+        data.insert("foo".to_string(), "bar".to_string());
+        let ttl = Duration::from_secs(60);
+
+        store.save("a", &data, ttl).unwrap();
+        store.save("b", &data, ttl).unwrap();
+        // `a` was used more recently than `b`, which is evicted
+        assert_eq!(store.load("a").unwrap(), Some(data.clone()));
+        store.save("c", &data, ttl).unwrap();
+        assert_eq!(store.load("b").unwrap(), None);
+        assert_eq!(store.load("c").unwrap(), Some(data.clone()));
+
+        *clock.0.lock().unwrap() += Duration::from_secs(61);
+        assert_eq!(store.load("a").unwrap(), None);
+        store.purge_expired();
+
+        let stats = store.stats();
+        let expected = StoreStats {
+            hits: 2,
+            misses: 2,
+            evictions: 1,
+            expirations: 2,
+            sessions: 0,
+        };
+        assert_eq!(stats, expected);
+        assert_eq!(stats.hit_rate(), 0.5);
+    }
+
+    #[test]
+    fn encrypted_store() {
+        let inner = Arc::new(MemoryStore::new());
+        let key = Key::generate();
+        let store = EncryptedStore::new(inner.clone(), key.clone());
+        let mut data = HashMap::new();
+        data.insert("user".to_string(), "alice".to_string());
+        let ttl = Duration::from_secs(60);
+
+        store.save("a", &data, ttl).unwrap();
+        assert_eq!(store.load("a").unwrap(), Some(data.clone()));
+        let sealed = inner.load("a").unwrap().unwrap();
+        assert!(!sealed.values().any(|value| value.contains("alice")));
+
+        // Data can't be moved to another session
+        inner.save("b", &sealed, ttl).unwrap();
+        assert_eq!(store.load("b").unwrap(), None);
+
+        let rotated = EncryptedStore::new(inner.clone(), Key::generate());
+        assert_eq!(rotated.load("a").unwrap(), None);
+        let rotated = rotated.old_key(key.clone());
+        assert_eq!(rotated.load("a").unwrap(), Some(data.clone()));
+
+        // Entries written before encryption was enabled
+        inner.save("c", &data, ttl).unwrap();
+        assert_eq!(store.load("c").unwrap(), None);
+        let migrating = EncryptedStore::new(inner.clone(), key.clone()).accept_plaintext(true);
+        assert_eq!(migrating.load("c").unwrap(), Some(data));
+
+        // A corrupt entry isn't a store outage
+        let mut jar = CookieJar::new();
+        jar.private_mut(&key).add(Cookie::new("d", "not base64!"));
+        let sealed = jar.get("d").unwrap().value().to_string();
+        let sealed = std::iter::once((SEALED_KEY.to_string(), sealed)).collect();
+        inner.save("d", &sealed, ttl).unwrap();
+        assert_eq!(store.load("d").unwrap(), None);
+    }
+}
@@ -0,0 +1,102 @@
+//! Helpers for testing apps that use this crate.
+
+use std::collections::HashMap;
+
+use conduit::{header, Body, Response};
+use cookie::time::Duration;
+use cookie::{Cookie, CookieJar, Key};
+
+use crate::{encode_session, SessionMiddleware};
+
+/// Collects the cookies set by `response` into a jar, so that they can be
+/// read back, e.g. with `jar.signed(&key)`. Cookies removed by the response
+/// are left out.
+pub fn response_cookies(response: &Response<Body>) -> CookieJar {
+    let mut jar = CookieJar::new();
+    for value in response.headers().get_all(header::SET_COOKIE) {
+        let cookie = value.to_str().ok().map(|v| Cookie::parse(v.to_string()));
+        if let Some(Ok(cookie)) = cookie {
+            if cookie.max_age() != Some(Duration::ZERO) {
+                jar.add_original(cookie);
+            }
+        }
+    }
+    jar
+}
+
+/// Decodes the session written by `response`, as configured on `middleware`.
+/// Returns `None` if the response didn't set a valid session cookie.
+pub fn response_session(
+    middleware: &SessionMiddleware,
+    response: &Response<Body>,
+) -> Option<HashMap<String, String>> {
+    middleware.session_data(&response_cookies(response))
+}
+
+/// Encodes and signs `data` as the session cookie `cookie_name`, returning a
+/// `Cookie` header value for requests that should start with that session,
+/// e.g. already logged in.
+pub fn session_cookie(cookie_name: &str, key: &Key, data: &HashMap<String, String>) -> String {
+    encode_session(data, key, cookie_name).to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use conduit::{header, Body, Handler, HttpResult, Method, RequestExt, Response};
+    use conduit_middleware::MiddlewareBuilder;
+    use conduit_test::MockRequest;
+    use cookie::Key;
+
+    use super::{response_cookies, response_session, session_cookie};
+    use crate::{Middleware, RequestCookies, RequestSession, SessionMiddleware};
+
+    fn test_key() -> Key {
+        let master_key: Vec<u8> = (0..32).collect();
+        Key::derive_from(&master_key)
+    }
+
+    #[test]
+    fn inspect_response() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        let mut app = MiddlewareBuilder::new(handler);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::encrypted("app", test_key(), false));
+        let response = app.call(&mut req).unwrap();
+
+        let jar = response_cookies(&response);
+        assert_eq!(jar.signed(&test_key()).get("signed").unwrap().value(), "1");
+
+        let middleware = SessionMiddleware::encrypted("app", test_key(), false);
+        let session = response_session(&middleware, &response).unwrap();
+        assert_eq!(session.get("user_id").unwrap(), "1");
+        assert_eq!(session.len(), 1);
+
+        fn handler(req: &mut dyn RequestExt) -> HttpResult {
+            let cookie = cookie::Cookie::new("signed", "1");
+            req.cookies_mut().signed_mut(&test_key()).add(cookie);
+            req.session_mut()
+                .insert("user_id".to_string(), "1".to_string());
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn seeded_session() {
+        let mut data = HashMap::new();
+        data.insert("user_id".to_string(), "1".to_string());
+
+        let mut req = MockRequest::new(Method::GET, "/");
+        req.header(header::COOKIE, &session_cookie("app", &test_key(), &data));
+        let mut app = MiddlewareBuilder::new(handler);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new("app", test_key(), false));
+        assert!(app.call(&mut req).is_ok());
+
+        fn handler(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.session().get("user_id").unwrap(), "1");
+            Response::builder().body(Body::empty())
+        }
+    }
+}
@@ -0,0 +1,22 @@
+use subtle::ConstantTimeEq;
+
+/// Compares a secret token, e.g. from a cookie, against the expected value
+/// in time independent of where they differ.
+///
+/// Only the length of the inputs may leak.
+pub fn constant_time_eq(a: impl AsRef<[u8]>, b: impl AsRef<[u8]>) -> bool {
+    a.as_ref().ct_eq(b.as_ref()).into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::constant_time_eq;
+
+    #[test]
+    fn compares() {
+        assert!(constant_time_eq("token", "token"));
+        assert!(!constant_time_eq("token", "tokex"));
+        assert!(!constant_time_eq("token", "tok"));
+        assert!(constant_time_eq(b"", ""));
+    }
+}
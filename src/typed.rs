@@ -0,0 +1,211 @@
+use std::marker::PhantomData;
+
+use conduit::RequestExt;
+use conduit_middleware::{AfterResult, BeforeResult};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+
+use crate::RequestSession;
+
+/// Keeps the session in a single struct `T`, available via
+/// `req.typed_session::<T>()`, so that handlers don't have to agree on
+/// string keys.
+///
+/// Each field of `T` is stored under its name as JSON, like `session_set`
+/// does, next to the values of other middleware like flash messages. If the
+/// session doesn't deserialize into `T`, e.g. after a field changed its
+/// type, handlers get `T::default()`, and the session is left alone unless
+/// they change it.
+///
+/// Must be added after `SessionMiddleware`.
+pub struct TypedSession<T> {
+    _marker: PhantomData<fn() -> T>,
+}
+
+struct TypedState<T> {
+    original: Map<String, Value>,
+    current: T,
+}
+
+impl<T> Default for TypedSession<T> {
+    fn default() -> Self {
+        TypedSession {
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> TypedSession<T> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+fn to_object<T: Serialize>(value: &T) -> Map<String, Value> {
+    match serde_json::to_value(value) {
+        Ok(Value::Object(object)) => object,
+        _ => panic!("typed sessions must serialize to a map"),
+    }
+}
+
+impl<T> conduit_middleware::Middleware for TypedSession<T>
+where
+    T: Serialize + DeserializeOwned + Default + Send + Sync + 'static,
+{
+    fn before(&self, req: &mut dyn RequestExt) -> BeforeResult {
+        // Values that aren't JSON were stored by other means, and are only
+        // matched by string fields
+        let object = req
+            .session()
+            .iter()
+            .map(|(key, value)| {
+                let json = serde_json::from_str(value);
+                (
+                    key.clone(),
+                    json.unwrap_or_else(|_| Value::String(value.clone())),
+                )
+            })
+            .collect();
+        let current = serde_json::from_value::<T>(Value::Object(object)).unwrap_or_default();
+        let original = to_object(&current);
+        req.mut_extensions()
+            .insert(TypedState { original, current });
+        Ok(())
+    }
+
+    fn after(&self, req: &mut dyn RequestExt, res: AfterResult) -> AfterResult {
+        let state = req.mut_extensions().remove::<TypedState<T>>();
+        let state = state.expect("typed session must be present after request");
+        let current = to_object(&state.current);
+        if current == state.original {
+            return res;
+        }
+        let session = req.session_mut();
+        for key in state.original.keys() {
+            if !current.contains_key(key) {
+                session.remove(key);
+            }
+        }
+        for (key, value) in current {
+            session.insert(key, value.to_string());
+        }
+        res
+    }
+}
+
+pub trait RequestTypedSession {
+    /// The session as read by `TypedSession<T>`.
+    fn typed_session<T: Send + Sync + 'static>(&self) -> &T;
+    /// The session, which is written back if changed.
+    fn typed_session_mut<T: Send + Sync + 'static>(&mut self) -> &mut T;
+}
+
+impl<R: RequestExt + ?Sized> RequestTypedSession for R {
+    fn typed_session<T: Send + Sync + 'static>(&self) -> &T {
+        let state = self.extensions().get::<TypedState<T>>();
+        &state.expect("missing typed session").current
+    }
+
+    fn typed_session_mut<T: Send + Sync + 'static>(&mut self) -> &mut T {
+        let state = self.mut_extensions().get_mut::<TypedState<T>>();
+        &mut state.expect("missing typed session").current
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use conduit::{header, Body, Handler, HttpResult, Method, RequestExt, Response};
+    use conduit_middleware::MiddlewareBuilder;
+    use conduit_test::MockRequest;
+    use cookie::Key;
+    use serde::{Deserialize, Serialize};
+
+    use super::{RequestTypedSession, TypedSession};
+    use crate::{Middleware, RequestSession, SessionMiddleware};
+
+    #[derive(Default, Serialize, Deserialize)]
+    struct AppSession {
+        user_id: Option<u64>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        cart: Vec<String>,
+    }
+
+    fn app(handler: fn(&mut dyn RequestExt) -> HttpResult) -> MiddlewareBuilder {
+        let master_key: Vec<u8> = (0..32).collect();
+        let mut app = MiddlewareBuilder::new(handler);
+        app.add(Middleware::new());
+        app.add(SessionMiddleware::new(
+            "typed",
+            Key::derive_from(&master_key),
+            false,
+        ));
+        app.add(TypedSession::<AppSession>::new());
+        app
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        let response = app(login).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        // Unchanged sessions aren't written again
+        let response = app(check).call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        let response = app(checkout).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+        app(after_checkout).call(&mut req).unwrap();
+
+        fn login(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.typed_session::<AppSession>().user_id, None);
+            let session = req.typed_session_mut::<AppSession>();
+            session.user_id = Some(7);
+            session.cart.push("book".to_string());
+            req.session_mut()
+                .insert("theme".to_string(), "dark".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn check(req: &mut dyn RequestExt) -> HttpResult {
+            let session = req.typed_session::<AppSession>();
+            assert_eq!(session.user_id, Some(7));
+            assert_eq!(session.cart, ["book"]);
+            assert_eq!(req.session()["user_id"], "7");
+            Response::builder().body(Body::empty())
+        }
+        fn checkout(req: &mut dyn RequestExt) -> HttpResult {
+            req.typed_session_mut::<AppSession>().cart.clear();
+            Response::builder().body(Body::empty())
+        }
+        fn after_checkout(req: &mut dyn RequestExt) -> HttpResult {
+            assert!(req.typed_session::<AppSession>().cart.is_empty());
+            assert!(!req.session().contains_key("cart"));
+            assert_eq!(req.session()["theme"], "dark");
+            Response::builder().body(Body::empty())
+        }
+    }
+
+    #[test]
+    fn undecodable() {
+        let mut req = MockRequest::new(Method::GET, "/");
+        let response = app(set).call(&mut req).unwrap();
+        let v = response.headers().get(header::SET_COOKIE).unwrap();
+        req.header(header::COOKIE, v.to_str().unwrap());
+
+        let response = app(check).call(&mut req).unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+
+        fn set(req: &mut dyn RequestExt) -> HttpResult {
+            req.session_mut()
+                .insert("user_id".to_string(), "admin".to_string());
+            Response::builder().body(Body::empty())
+        }
+        fn check(req: &mut dyn RequestExt) -> HttpResult {
+            assert_eq!(req.typed_session::<AppSession>().user_id, None);
+            assert_eq!(req.session()["user_id"], "admin");
+            Response::builder().body(Body::empty())
+        }
+    }
+}